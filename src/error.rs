@@ -22,7 +22,159 @@ pub enum EscrowError {
     /// Invalid Amount
     #[error("Invalid Amount")]
     InvalidAmount,
-
+    /// Instruction data ended before all fields were read
+    #[error("Instruction Data Too Short")]
+    InstructionTooShort,
+    /// More legs than an escrow can hold
+    #[error("Too Many Legs")]
+    TooManyLegs,
+    /// Bytes left over after the instruction was read
+    #[error("Trailing Instruction Data")]
+    TrailingInstructionData,
+    /// Temp token account is not owned by this escrow's authority
+    #[error("Invalid Vault Authority")]
+    InvalidVaultAuthority,
+    /// Escrow account is not owned by this program
+    #[error("Invalid Escrow Owner")]
+    InvalidEscrowOwner,
+    /// Token program account is not the SPL Token program
+    #[error("Invalid Token Program")]
+    InvalidTokenProgram,
+    /// System program account is not the system program
+    #[error("Invalid System Program")]
+    InvalidSystemProgram,
+    /// Account the instruction writes to is not writable
+    #[error("Account Not Writable")]
+    AccountNotWritable,
+    /// Token account does not hold the mint of its leg
+    #[error("Invalid Token Mint")]
+    InvalidTokenMint,
+    /// Token account is not owned by the party of its leg
+    #[error("Invalid Token Owner")]
+    InvalidTokenOwner,
+    /// Expiry given at init is not in the future
+    #[error("Invalid Expiry")]
+    InvalidExpiry,
+    /// Escrow is past its expiry and can only be reclaimed
+    #[error("Escrow Expired")]
+    EscrowExpired,
+    /// Escrow has no expiry or has not reached it yet
+    #[error("Escrow Not Expired")]
+    EscrowNotExpired,
+    /// Fill is out of range or would use up a leg the taker pays before the escrow is exhausted
+    #[error("Invalid Fill")]
+    InvalidFill,
+    /// Escrow terms were amended after the version the taker agreed to
+    #[error("Stale Escrow Version")]
+    StaleEscrowVersion,
+    /// Signer is not the admin of the config or the upgrade authority of the program
+    #[error("Invalid Admin")]
+    InvalidAdmin,
+    /// Fee is above `MAX_FEE_BPS`
+    #[error("Fee Too High")]
+    FeeTooHigh,
+    /// Fee exempt list is longer than `MAX_FEE_EXEMPT`
+    #[error("Too Many Fee Exempt Accounts")]
+    TooManyFeeExempt,
+    /// Escrow is disputed, only its arbiter can settle it
+    #[error("Escrow Disputed")]
+    EscrowDisputed,
+    /// Escrow is not disputed
+    #[error("Escrow Not Disputed")]
+    EscrowNotDisputed,
+    /// Escrow has no arbiter or the signer is not its arbiter
+    #[error("Invalid Arbiter")]
+    InvalidArbiter,
+    /// Milestone shares are invalid, or the milestone is not the next one to release
+    #[error("Invalid Milestones")]
+    InvalidMilestones,
+    /// Escrow releases its legs by milestone and cannot be exchanged
+    #[error("Milestone Escrow")]
+    MilestoneEscrow,
+    /// Hashlock is set on an escrow that cannot be claimed with a secret, or the escrow is not hashlocked
+    #[error("Invalid Hashlock")]
+    InvalidHashlock,
+    /// Secret does not hash to the hashlock
+    #[error("Invalid Secret")]
+    InvalidSecret,
+    /// No Ed25519 instruction verifies the maker's signature over the order
+    #[error("Invalid Order Signature")]
+    InvalidOrderSignature,
+    /// Order nonce was already filled or cancelled
+    #[error("Order Nonce Used")]
+    OrderNonceUsed,
+    /// Token account no longer delegates or holds the amount to settle
+    #[error("Insufficient Delegation")]
+    InsufficientDelegation,
+    /// Escrow has an arbiter, the initializer cannot cancel it or take back what it holds alone
+    #[error("Arbiter Approval Required")]
+    ArbiterApprovalRequired,
+    /// Associated token account program account is not that program
+    #[error("Invalid Associated Token Program")]
+    InvalidAssociatedTokenProgram,
+    /// Token account is not owned by SPL Token or Token-2022
+    #[error("Invalid Token Account Owner")]
+    InvalidTokenAccountOwner,
+    /// Program data account is not the one of this program
+    #[error("Invalid Program Data")]
+    InvalidProgramData,
+    /// Config account is not the one of this program
+    #[error("Invalid Config Account")]
+    InvalidConfigAccount,
+    /// Treasury is not the one stored in the config
+    #[error("Invalid Treasury")]
+    InvalidTreasury,
+    /// Escrow account is not at the address of its initializer and seed
+    #[error("Invalid Escrow Address")]
+    InvalidEscrowAddress,
+    /// Vault token account is not at the address of its leg
+    #[error("Invalid Vault Address")]
+    InvalidVaultAddress,
+    /// PDA account is not the authority of this escrow or order
+    #[error("Invalid Authority")]
+    InvalidAuthority,
+    /// Rent destination is not the one stored in the escrow
+    #[error("Invalid Rent Destination")]
+    InvalidRentDestination,
+    /// Initializer is not the one of the escrow
+    #[error("Invalid Initializer")]
+    InvalidInitializer,
+    /// Taker is not the one of the escrow or order
+    #[error("Invalid Taker")]
+    InvalidTaker,
+    /// Maker is not the one of the order
+    #[error("Invalid Maker")]
+    InvalidMaker,
+    /// Signer is neither the initializer nor the taker of the escrow
+    #[error("Invalid Party")]
+    InvalidParty,
+    /// Signer is neither the initializer nor the arbiter of the escrow
+    #[error("Invalid Approver")]
+    InvalidApprover,
+    /// Token account already backs another leg
+    #[error("Duplicate Token Account")]
+    DuplicateTokenAccount,
+    /// Temp token account is not the one stored for its leg
+    #[error("Invalid Temp Token Account")]
+    InvalidTempTokenAccount,
+    /// Metadata account is not the Metaplex metadata of its mint
+    #[error("Invalid Metadata Account")]
+    InvalidMetadataAccount,
+    /// Creator account is not the creator listed in the metadata
+    #[error("Invalid Creator")]
+    InvalidCreator,
+    /// Nonce account is not the one tracking the order nonce
+    #[error("Invalid Nonce Account")]
+    InvalidNonceAccount,
+    /// Token account is not the associated token account of its wallet
+    #[error("Invalid Associated Token Account")]
+    InvalidAssociatedTokenAccount,
+    /// Token-2022 mint has an extension the escrow cannot settle
+    #[error("Unsupported Mint Extension")]
+    UnsupportedMintExtension,
+    /// Token account is already delegated to someone other than the escrow authority
+    #[error("Token Account Delegated")]
+    TokenAccountDelegated,
 }
 
 impl From<EscrowError> for ProgramError {
@@ -1,146 +1,1846 @@
-// use core::num;
+use std::mem::size_of;
 
-use solana_program::program_error::ProgramError;
+use solana_program::{
+    bpf_loader_upgradeable, ed25519_program,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::{Pubkey, PUBKEY_BYTES},
+    system_program, sysvar,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
 
-use crate::error::EscrowError::InvalidInstruction;
+use crate::{
+    error::EscrowError::{
+        InstructionTooShort, InvalidInstruction, InvalidMilestones, TooManyFeeExempt, TooManyLegs,
+        TrailingInstructionData,
+    },
+    find_config_address, find_escrow_address, find_escrow_authority, find_order_authority, find_order_nonces_address,
+    find_vault_address,
+    metadata::find_metadata_address,
+    order::{NonceBitmap, Order},
+    state::{XLeg, YLeg, MAX_FEE_EXEMPT, MAX_LEGS, MAX_MILESTONES},
+};
+
+/// Denominator of the share of the remaining terms settled by `ExchangePartial`
+pub const FILL_DENOMINATOR: u64 = 1_000_000_000;
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum EscrowInstruction {
-    /// Starts the trade by creating and populating an escrow account and transferring ownership of the given temp token account to the PDA
+    /// Starts the trade by creating the escrow account and moving every X leg into a vault token account the program creates, owned by the PDA.
+    /// Sending it again with the same terms succeeds without doing anything. Token-2022 mints with an extension the
+    /// escrow cannot settle, such as a transfer hook or a permanent delegate, fail with `UnsupportedMintExtension`.
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person initializing the escrow
-    /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
-    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar
-    /// 5. `[]` The token program
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, pays for the escrow account and the vaults
+    /// 1. `[]` The account of the only person allowed to take the trade, `Pubkey::default()` opens the trade to any taker
+    /// 2. `[writable]` The escrow account to create, at `find_escrow_address(program_id, initializer, seed)`. It will hold all necessary info about the trade.
+    /// 3. `[]` The rent sysvar
+    /// 4. `[]` The system program
+    /// 5. For each X leg:
+    ///    * `[writable]` The initializer's token account the tokens come from, backing the leg in a non-custodial
+    ///      escrow
+    ///    * `[writable]` The vault token account to create, at `find_vault_address(program_id, escrow, leg_index)`,
+    ///      omitted in a non-custodial escrow
+    ///    * `[]` The mint of the tokens
+    ///    * `[]` The token program owning the mint, SPL Token or Token-2022
+    /// 6. For each Y leg:
+    ///    * `[]` The mint of the tokens
+    ///    * `[]` The token program owning the mint, SPL Token or Token-2022
+    /// 7. `[]` The PDA account, only in a non-custodial escrow
+    ///
+    /// The initializer pays the Token-2022 transfer fee of the deposits, each vault receives the full X amount.
+    /// The legs are received in token accounts of the taker and of the initializer, see `Exchange`.
+    /// With an arbiter, either party can `Dispute` the trade and only the arbiter can then settle it with `Resolve`.
+    /// The initializer then needs the arbiter or the taker to co-sign a cancel, and cannot lower the SOL leg held by
+    /// the escrow or bring its expiry forward.
+    /// With milestones, the X legs and the SOL leg are paid to the taker tranche by tranche with `ReleaseMilestone`
+    /// instead of being exchanged, the escrow then has no Y leg and the initializer sends the SOL leg.
+    /// With a hashlock, the taker claims the X legs and the SOL leg with `ClaimWithSecret` until the expiry, which is
+    /// required, and only then can the initializer get them back. The escrow then has no Y leg and no milestones.
+    /// A non-custodial escrow creates no vault, the initializer approves the PDA as delegate of each X amount on its
+    /// own token account instead, and the trade fails with `InsufficientDelegation` if the tokens were moved or the
+    /// approval revoked. It cannot have milestones, a hashlock or an arbiter, and a token account already delegated to
+    /// someone else fails with `TokenAccountDelegated`. Closing the escrow revokes the approvals, through the
+    /// initializer when it signs, otherwise through the PDA on Token-2022 legs.
     InitEscrow {
-        /// The amount party A expects to receive of token Y
+        /// Lamports of the SOL leg, none when 0
         lamports: u64,
-        sol_dir : u8,
-        amount_x : u8,
+        /// Direction of the SOL leg: 1 initializer -> taker, 2 taker -> initializer
+        sol_dir: u8,
+        /// Number of X legs, sent by the initializer
+        amount_x: u8,
+        /// Number of Y legs, sent by the taker
         amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9]
+        /// Amount of each X leg
+        lamports_x: [u64; MAX_LEGS],
+        /// Amount of each Y leg
+        lamports_y: [u64; MAX_LEGS],
+        /// Picked by the initializer so they can open several escrows, see `find_escrow_address`
+        seed: u64,
+        /// Receives the rent of the vaults and of the escrow account, the initializer when `None`
+        rent_destination: Option<Pubkey>,
+        /// Unix timestamp after which the escrow can no longer be taken, never when `None`
+        expires_at: Option<i64>,
+        /// Pay the Metaplex royalties of the NFT legs out of the SOL leg
+        enforce_royalties: bool,
+        /// Settles the trade if a party disputes it, no arbiter when `None`
+        arbiter: Option<Pubkey>,
+        /// Share of the legs each milestone releases in basis points, summing to `MILESTONE_DENOMINATOR`, at most
+        /// `MAX_MILESTONES`. The escrow settles by exchange when empty.
+        milestones: Vec<u16>,
+        /// SHA-256 hash of the secret the taker reveals to claim the trade, settled by exchange when `None`
+        hashlock: Option<[u8; 32]>,
+        /// Keep the X legs in the initializer's token accounts, delegated to the PDA, instead of in vaults
+        non_custodial: bool,
     },
-    /// Starts the trade by creating and populating an escrow account and transferring ownership of the given temp token account to the PDA
+    /// Cancels a trade, sending every X leg back to the initializer and closing the escrow account
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person initializing the escrow
-    /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
-    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar
-    /// 5. `[]` The token program
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow, gets the SOL leg back
+    /// 1. `[]` The account of the taker
+    /// 2. `[writable]` The escrow account holding the escrow info
+    /// 3. `[]` The rent sysvar
+    /// 4. `[]` The PDA account
+    /// 5. `[writable]` The rent destination stored in the escrow, receives the rent of the vaults and the escrow account
+    /// 6. For each X leg:
+    ///    * `[writable]` A token account of the initializer for the mint, gets the tokens back, omitted in a
+    ///      non-custodial escrow
+    ///    * `[writable]` The vault token account to get tokens from and eventually close, the initializer's token
+    ///      account whose approval is revoked in a non-custodial escrow
+    ///    * `[writable]` The mint of the tokens, receives the transfer fees withheld in the vault
+    ///    * `[]` The token program stored for the leg
+    /// 7. `[signer]` The arbiter of the escrow, or its taker when it names one, only in an escrow with an arbiter
+    ///
+    /// A hashlocked escrow can only be cancelled once it has expired.
     CancelEscrow {
-        /// The amount party A expects to receive of token Y
+        /// The stored terms, checked before anything is sent back, see `InitEscrow`
         lamports: u64,
-        sol_dir : u8,
-        amount_x : u8,
+        sol_dir: u8,
+        amount_x: u8,
         amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9]
+        lamports_x: [u64; MAX_LEGS],
+        lamports_y: [u64; MAX_LEGS],
     },
     /// Accepts a trade
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person taking the trade
-    /// 1. `[writable]` The taker's token account for the token they send
-    /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The PDA's temp token account to get tokens from and eventually close
-    /// 4. `[writable]` The initializer's main account to send their rent fees to
-    /// 5. `[writable]` The initializer's token account that will receive tokens
-    /// 6. `[writable]` The escrow account holding the escrow info
-    /// 7. `[]` The token program
-    /// 8. `[]` The PDA account
+    /// 0. `[writable]` The initializer's main account, receives the SOL leg sent by the taker
+    /// 1. `[signer, writable]` The account of the person taking the trade, anyone in an open trade
+    /// 2. `[writable]` The escrow account holding the escrow info
+    /// 3. `[]` The rent sysvar
+    /// 4. `[]` The PDA account
+    /// 5. `[writable]` The rent destination stored in the escrow, receives the rent of the vaults and the escrow account
+    /// 6. `[]` The config account, at `find_config_address(program_id)`
+    /// 7. `[writable]` The treasury stored in the config, ignored when the config does not exist
+    /// 8. `[]` The system program
+    /// 9. `[]` The associated token account program
+    /// 10. For each leg charged a fee, X legs first: `[writable]` The treasury's token account for the mint of the leg
+    /// 11. For each X leg:
+    ///    * `[writable]` The taker's token account for the mint, their associated token account is created when missing
+    ///    * `[writable]` The vault token account to get tokens from and eventually close
+    ///    * `[writable]` The mint of the tokens, receives the transfer fees withheld in the vault
+    ///    * `[]` The token program stored for the leg
+    /// 12. For each Y leg:
+    ///    * `[writable]` The initializer's token account for the mint, their associated token account is created when
+    ///      missing
+    ///    * `[writable]` The taker's token account the tokens come from, any of the taker's in an open trade
+    ///    * `[]` The mint of the tokens
+    ///    * `[]` The token program stored for the leg
+    /// 13. When the escrow enforces royalties, for each NFT leg sold for the SOL leg, a mint with a supply of 1 and
+    ///     no decimals:
+    ///    * `[]` The Metaplex metadata account of the mint
+    ///    * `[writable]` Each verified creator with a share, in the order of the metadata
+    ///
+    /// The taker pays the rent of the associated token accounts it creates.
+    /// The fee is taken from what each party receives, unless the initializer or the taker is fee exempt.
+    /// The royalties are taken from the SOL the seller of the NFT legs receives.
+    /// For Token-2022 mints with a transfer fee, the taker pays it on the Y legs so the initializer receives the
+    /// full amounts, while the fee is withheld from the X legs the taker receives out of the vaults.
     Exchange {
-        /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
+        /// The terms the taker agreed to, the trade fails unless they match the stored ones, see `InitEscrow`
+        lamports: u64,
+        sol_dir: u8,
+        amount_x: u8,
+        amount_y: u8,
+        lamports_x: [u64; MAX_LEGS],
+        lamports_y: [u64; MAX_LEGS],
+        /// Bump seed of each metadata account passed for the royalties, see `find_metadata_address`
+        metadata_bump_seeds: Vec<u8>,
+    },
+    /// Accepts a trade on the terms stored in the escrow account, nothing needs to be resent
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `Exchange`
+    ExchangeStored {
+        /// Version of the escrow the taker agreed to, the trade fails if the terms changed since
+        expected_version: u64,
+        /// Bump seed of each metadata account passed for the royalties, see `find_metadata_address`
+        metadata_bump_seeds: Vec<u8>,
+    },
+    /// Cancels a trade on the terms stored in the escrow account, nothing needs to be resent
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `CancelEscrow`
+    CancelStored,
+    /// Returns every X leg to the initializer once the escrow has expired and closes the escrow account, anyone can send it
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `CancelEscrow`, except that no arbiter or taker co-signs and the initializer only signs a non-custodial
+    /// escrow, whose approvals are revoked.
+    ReclaimExpired,
+    /// Accepts `fill_numerator / FILL_DENOMINATOR` of the remaining terms, the vaults and the escrow account are
+    /// closed once nothing is left. A partial fill fails with `InvalidFill` when a leg is an NFT or when it would
+    /// give the taker nothing of an X leg.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// Same as `Exchange`
+    ExchangePartial {
+        /// Share of every remaining leg to settle, `FILL_DENOMINATOR` settles everything
+        fill_numerator: u64,
+        /// Version of the escrow the taker agreed to, the trade fails if the terms changed since
+        expected_version: u64,
+        /// Bump seed of each metadata account passed for the royalties, see `find_metadata_address`
+        metadata_bump_seeds: Vec<u8>,
+    },
+    /// Amends the terms of an open escrow and bumps its version, the Y amounts and the SOL amount are replaced,
+    /// the expiry is replaced when given, while X legs can be topped up or added. With an arbiter, the SOL leg
+    /// held by the escrow can only be raised and the expiry only pushed back.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow, pays for the new vaults and the extra space
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[]` The rent sysvar
+    /// 3. `[]` The system program
+    /// 4. For each X leg, the existing ones first:
+    ///    * `[writable]` The initializer's token account the tokens come from, the one backing the leg in a
+    ///      non-custodial escrow, where each account backs a single leg
+    ///    * `[writable]` The vault token account, created for a new leg at `find_vault_address(program_id, escrow, leg_index)`,
+    ///      the initializer's token account again in a non-custodial escrow
+    ///    * `[]` The mint of the tokens
+    ///    * `[]` The token program owning the mint, the stored one for an existing leg
+    /// 5. `[]` The PDA account, only in a non-custodial escrow where every X leg is approved again for its new amount
+    UpdateEscrow {
+        /// The new amount of the SOL leg
         lamports: u64,
-        sol_dir : u8,
-        amount_x : u8,
+        /// Number of X legs after the update, at least the current number
+        amount_x: u8,
+        /// Tokens added to each X leg, the whole amount for a new leg
+        top_up_x: [u64; MAX_LEGS],
+        /// Number of Y legs, must be the current number
         amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9]
+        /// The new amounts of the Y legs
+        lamports_y: [u64; MAX_LEGS],
+        /// Unix timestamp after which the escrow can no longer be taken, `Some(0)` removes the expiry and `None`
+        /// keeps the stored one
+        expires_at: Option<i64>,
+    },
+    /// Creates the config account holding the protocol fee settings, the signer becomes its admin
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The upgrade authority of the program, pays for the config account
+    /// 1. `[writable]` The config account to create, at `find_config_address(program_id)`
+    /// 2. `[]` The program data account of this program
+    /// 3. `[]` The rent sysvar
+    /// 4. `[]` The system program
+    InitConfig {
+        /// Fee in basis points, at most `MAX_FEE_BPS`
+        fee_bps: u16,
+        /// Receives the fees
+        treasury: Pubkey,
+    },
+    /// Replaces the protocol fee settings
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The admin of the config
+    /// 1. `[writable]` The config account
+    UpdateConfig {
+        /// The admin from now on
+        admin: Pubkey,
+        /// Fee in basis points, at most `MAX_FEE_BPS`
+        fee_bps: u16,
+        /// Receives the fees
+        treasury: Pubkey,
+        /// Accounts whose trades pay no fee, at most `MAX_FEE_EXEMPT`
+        fee_exempt: Vec<Pubkey>,
+    },
+    /// Freezes an escrow that names an arbiter, it can then only be settled by `Resolve`.
+    /// Raising it again replaces the evidence hash.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The initializer or the taker of the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    Dispute {
+        /// Hash of the evidence backing the dispute, kept off chain
+        evidence_hash: [u8; 32],
+    },
+    /// Settles a disputed escrow, sending each X leg to one of the parties, splitting the SOL leg held by the escrow
+    /// and closing the escrow account
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The arbiter stored in the escrow, pays for the associated token accounts it creates
+    /// 1. `[writable]` The account of the initializer, gets its share of the SOL leg
+    /// 2. `[writable]` The account of the taker, gets its share of the SOL leg
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The PDA account
+    /// 5. `[writable]` The rent destination stored in the escrow, receives the rent of the vaults and the escrow account
+    /// 6. `[]` The system program
+    /// 7. `[]` The associated token account program
+    /// 8. For each X leg:
+    ///    * `[writable]` The token account receiving the leg, a token account of the initializer for the mint when it
+    ///      goes back to them, otherwise the taker's, whose associated token account is created when missing
+    ///    * `[writable]` The vault token account to get tokens from and close
+    ///    * `[writable]` The mint of the tokens, receives the transfer fees withheld in the vault
+    ///    * `[]` The token program stored for the leg
+    ///
+    /// The Y legs were never deposited, they stay with the taker.
+    Resolve {
+        /// Lamports of the SOL leg held by the escrow going to the taker, the rest goes back to the initializer
+        taker_lamports: u64,
+        /// Number of X legs, must be the stored number
+        amount_x: u8,
+        /// Whether each X leg goes to the taker, otherwise it goes back to the initializer
+        x_to_taker: [bool; MAX_LEGS],
+    },
+    /// Releases the next milestone of a milestone escrow, sending its tranche of every X leg and of the SOL leg to the
+    /// taker. The last one releases whatever is left and closes the vaults and the escrow account.
+    /// What is not released yet goes back to the initializer on cancel.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The initializer or the arbiter of the escrow, pays for the associated token accounts it creates
+    /// 1. `[]` The account of the initializer
+    /// 2. `[writable]` The account of the taker, gets the SOL tranche
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The PDA account
+    /// 5. `[writable]` The rent destination stored in the escrow, receives the rent of the vaults and the escrow account
+    /// 6. `[]` The system program
+    /// 7. `[]` The associated token account program
+    /// 8. For each X leg:
+    ///    * `[writable]` The taker's token account for the mint, their associated token account is created when missing
+    ///    * `[writable]` The vault token account to get tokens from and eventually close
+    ///    * `[writable]` The mint of the tokens, receives the transfer fees withheld in the vault
+    ///    * `[]` The token program stored for the leg
+    ReleaseMilestone {
+        /// Index of the milestone to release, must be the next one so a resent instruction releases nothing twice
+        milestone: u8,
+    },
+    /// Claims a hashlocked escrow before its expiry by revealing the secret, sending every X leg and the SOL leg to the
+    /// taker and closing the escrow account. The secret is logged as program data for the other chain of the swap.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The taker of the escrow, pays for the associated token accounts it creates
+    /// 1. `[]` The account of the initializer
+    /// 2. `[writable]` The escrow account holding the escrow info
+    /// 3. `[]` The PDA account
+    /// 4. `[writable]` The rent destination stored in the escrow, receives the rent of the vaults and the escrow account
+    /// 5. `[]` The system program
+    /// 6. `[]` The associated token account program
+    /// 7. For each X leg:
+    ///    * `[writable]` The taker's token account for the mint, their associated token account is created when missing
+    ///    * `[writable]` The vault token account to get tokens from and close
+    ///    * `[writable]` The mint of the tokens, receives the transfer fees withheld in the vault
+    ///    * `[]` The token program stored for the leg
+    ClaimWithSecret {
+        /// The preimage of the hashlock
+        secret: [u8; 32],
+    },
+    /// Fills an order signed off chain by its maker, no escrow account is involved. The maker's signature over
+    /// `Order::message` for this program must be verified by an Ed25519 program instruction right before this one, see
+    /// `verify_order_signature`. The maker's X tokens are sent as delegate of the order authority, the taker's Y
+    /// tokens and lamports go to the maker, and the nonce is marked as used.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The taker, anyone for an open order, pays for the accounts it creates
+    /// 1. `[writable]` The maker's main account, receives the lamports
+    /// 2. `[]` The order authority of the maker, at `find_order_authority(program_id, maker)`
+    /// 3. `[writable]` The nonce account tracking the order's nonce, at `find_order_nonces_address`, created when missing
+    /// 4. `[]` The instructions sysvar
+    /// 5. `[]` The system program
+    /// 6. `[]` The associated token account program
+    /// 7. `[writable]` The maker's token account for the X mint, delegating at least the X amount to the order authority
+    /// 8. `[writable]` The taker's token account for the X mint, their associated token account is created when missing
+    /// 9. `[]` The X mint
+    /// 10. `[]` The token program owning the X mint, SPL Token or Token-2022
+    /// 11. When the order has a Y amount:
+    ///    * `[writable]` The taker's token account the Y tokens come from
+    ///    * `[writable]` The maker's token account for the Y mint, their associated token account is created when
+    ///      missing
+    ///    * `[]` The Y mint
+    ///    * `[]` The token program owning the Y mint, SPL Token or Token-2022
+    FillSignedOrder {
+        /// The order the maker signed
+        order: Order,
+        /// Bump seed of the order authority, see `find_order_authority`
+        authority_bump_seed: u8,
+    },
+    /// Cancels a signed order by marking its nonce as used
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The maker, pays for the nonce account when it is created
+    /// 1. `[writable]` The nonce account tracking the nonce, at `find_order_nonces_address`, created when missing
+    /// 2. `[]` The system program
+    CancelOrder {
+        /// The nonce of the order to cancel
+        nonce: u64,
     },
 }
 
 impl EscrowInstruction {
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (&tag, rest) = input.split_first().ok_or(InstructionTooShort)?;
 
-        // let (tag, rest1) = input.split_first().ok_or(InvalidInstruction)?;
-        // let (num_x, rest2) = rest1.split_first().ok_or(InvalidInstruction)?;
-        // let (num_y, rest3) = rest2.split_first().ok_or(InvalidInstruction)?;
-        // let (sol_dir, rest4) = rest3.split_first().ok_or(InvalidInstruction)?;
-        // let lamports = Self::unpack_amount(rest4)?;
+        let (instruction, rest) = match tag {
+            0..=2 => {
+                let (&sol_dir, rest) = rest.split_first().ok_or(InstructionTooShort)?;
+                let (lamports, rest) = Self::unpack_amount(rest)?;
+                let (amount_x, lamports_x, rest) = Self::unpack_legs(rest)?;
+                let (amount_y, lamports_y, rest) = Self::unpack_legs(rest)?;
 
-        let tag = &input[0];
+                match tag {
+                    0 => {
+                        let (seed, rest) = Self::unpack_amount(rest)?;
+                        let (rent_destination, rest) = Self::unpack_optional_pubkey(rest)?;
+                        let (expires_at, rest) = Self::unpack_optional_timestamp(rest)?;
+                        let (enforce_royalties, rest) = Self::unpack_option_tag(rest)?;
+                        let (arbiter, rest) = Self::unpack_optional_pubkey(rest)?;
+                        let (milestones, rest) = Self::unpack_milestones(rest)?;
+                        let (hashlock, rest) = Self::unpack_optional_hash(rest)?;
+                        let (non_custodial, rest) = Self::unpack_option_tag(rest)?;
+                        (
+                            Self::InitEscrow {
+                                lamports,
+                                sol_dir,
+                                amount_x,
+                                amount_y,
+                                lamports_x,
+                                lamports_y,
+                                seed,
+                                rent_destination,
+                                expires_at,
+                                enforce_royalties,
+                                arbiter,
+                                milestones,
+                                hashlock,
+                                non_custodial,
+                            },
+                            rest,
+                        )
+                    }
+                    1 => {
+                        let (metadata_bump_seeds, rest) = Self::unpack_bump_seeds(rest)?;
+                        let exchange =
+                            Self::Exchange { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y, metadata_bump_seeds };
+                        (exchange, rest)
+                    }
+                    _ => (Self::CancelEscrow { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y }, rest),
+                }
+            }
+            3 => {
+                let (expected_version, rest) = Self::unpack_amount(rest)?;
+                let (metadata_bump_seeds, rest) = Self::unpack_bump_seeds(rest)?;
+                (Self::ExchangeStored { expected_version, metadata_bump_seeds }, rest)
+            }
+            4 => (Self::CancelStored, rest),
+            5 => (Self::ReclaimExpired, rest),
+            6 => {
+                let (fill_numerator, rest) = Self::unpack_amount(rest)?;
+                let (expected_version, rest) = Self::unpack_amount(rest)?;
+                let (metadata_bump_seeds, rest) = Self::unpack_bump_seeds(rest)?;
+                (Self::ExchangePartial { fill_numerator, expected_version, metadata_bump_seeds }, rest)
+            }
+            7 => {
+                let (lamports, rest) = Self::unpack_amount(rest)?;
+                let (amount_x, top_up_x, rest) = Self::unpack_legs(rest)?;
+                let (amount_y, lamports_y, rest) = Self::unpack_legs(rest)?;
+                let (expires_at, rest) = Self::unpack_optional_timestamp(rest)?;
+                (Self::UpdateEscrow { lamports, amount_x, top_up_x, amount_y, lamports_y, expires_at }, rest)
+            }
+            8 => {
+                let (fee_bps, rest) = Self::unpack_fee_bps(rest)?;
+                let (treasury, rest) = Self::unpack_pubkey(rest)?;
+                (Self::InitConfig { fee_bps, treasury }, rest)
+            }
+            9 => {
+                let (admin, rest) = Self::unpack_pubkey(rest)?;
+                let (fee_bps, rest) = Self::unpack_fee_bps(rest)?;
+                let (treasury, rest) = Self::unpack_pubkey(rest)?;
+                let (&count, mut rest) = rest.split_first().ok_or(InstructionTooShort)?;
+                if count as usize > MAX_FEE_EXEMPT {
+                    return Err(TooManyFeeExempt.into());
+                }
+                let mut fee_exempt = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let (key, next) = Self::unpack_pubkey(rest)?;
+                    fee_exempt.push(key);
+                    rest = next;
+                }
+                (Self::UpdateConfig { admin, fee_bps, treasury, fee_exempt }, rest)
+            }
+            10 => {
+                let (evidence_hash, rest) = Self::unpack_hash(rest)?;
+                (Self::Dispute { evidence_hash }, rest)
+            }
+            11 => {
+                let (taker_lamports, rest) = Self::unpack_amount(rest)?;
+                let (amount_x, x_to_taker, rest) = Self::unpack_flags(rest)?;
+                (Self::Resolve { taker_lamports, amount_x, x_to_taker }, rest)
+            }
+            12 => {
+                let (&milestone, rest) = rest.split_first().ok_or(InstructionTooShort)?;
+                (Self::ReleaseMilestone { milestone }, rest)
+            }
+            13 => {
+                let (secret, rest) = Self::unpack_hash(rest)?;
+                (Self::ClaimWithSecret { secret }, rest)
+            }
+            14 => {
+                if rest.len() < Order::LEN {
+                    return Err(InstructionTooShort.into());
+                }
+                let (order, rest) = rest.split_at(Order::LEN);
+                let (&authority_bump_seed, rest) = rest.split_first().ok_or(InstructionTooShort)?;
+                (Self::FillSignedOrder { order: Order::unpack_from_slice(order)?, authority_bump_seed }, rest)
+            }
+            15 => {
+                let (nonce, rest) = Self::unpack_amount(rest)?;
+                (Self::CancelOrder { nonce }, rest)
+            }
+            _ => return Err(InvalidInstruction.into()),
+        };
 
-        let sol_dir = &input[1];
-        let lamports = Self::unpack_amount(&input[2..=9])?;
+        if !rest.is_empty() {
+            return Err(TrailingInstructionData.into());
+        }
+        Ok(instruction)
+    }
+
+    /// Packs a [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size_of::<Self>());
+        match self {
+            Self::InitEscrow {
+                lamports,
+                sol_dir,
+                amount_x,
+                amount_y,
+                lamports_x,
+                lamports_y,
+                seed,
+                rent_destination,
+                expires_at,
+                enforce_royalties,
+                arbiter,
+                milestones,
+                hashlock,
+                non_custodial,
+            } => {
+                buf.push(0);
+                Self::pack_terms(&mut buf, *lamports, *sol_dir, *amount_x, *amount_y, lamports_x, lamports_y);
+                buf.extend_from_slice(&seed.to_le_bytes());
+                Self::pack_optional_pubkey(&mut buf, rent_destination.as_ref());
+                Self::pack_optional_timestamp(&mut buf, *expires_at);
+                buf.push(*enforce_royalties as u8);
+                Self::pack_optional_pubkey(&mut buf, arbiter.as_ref());
+                buf.push(milestones.len() as u8);
+                for share in milestones {
+                    buf.extend_from_slice(&share.to_le_bytes());
+                }
+                match hashlock {
+                    Some(hashlock) => {
+                        buf.push(1);
+                        buf.extend_from_slice(hashlock);
+                    }
+                    None => buf.push(0),
+                }
+                buf.push(*non_custodial as u8);
+            }
+            Self::Exchange { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y, metadata_bump_seeds } => {
+                buf.push(1);
+                Self::pack_terms(&mut buf, *lamports, *sol_dir, *amount_x, *amount_y, lamports_x, lamports_y);
+                Self::pack_bump_seeds(&mut buf, metadata_bump_seeds);
+            }
+            Self::CancelEscrow { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } => {
+                buf.push(2);
+                Self::pack_terms(&mut buf, *lamports, *sol_dir, *amount_x, *amount_y, lamports_x, lamports_y);
+            }
+            Self::ExchangeStored { expected_version, metadata_bump_seeds } => {
+                buf.push(3);
+                buf.extend_from_slice(&expected_version.to_le_bytes());
+                Self::pack_bump_seeds(&mut buf, metadata_bump_seeds);
+            }
+            Self::CancelStored => buf.push(4),
+            Self::ReclaimExpired => buf.push(5),
+            Self::ExchangePartial { fill_numerator, expected_version, metadata_bump_seeds } => {
+                buf.push(6);
+                buf.extend_from_slice(&fill_numerator.to_le_bytes());
+                buf.extend_from_slice(&expected_version.to_le_bytes());
+                Self::pack_bump_seeds(&mut buf, metadata_bump_seeds);
+            }
+            Self::UpdateEscrow { lamports, amount_x, top_up_x, amount_y, lamports_y, expires_at } => {
+                buf.push(7);
+                buf.extend_from_slice(&lamports.to_le_bytes());
+                Self::pack_legs(&mut buf, *amount_x, top_up_x);
+                Self::pack_legs(&mut buf, *amount_y, lamports_y);
+                Self::pack_optional_timestamp(&mut buf, *expires_at);
+            }
+            Self::InitConfig { fee_bps, treasury } => {
+                buf.push(8);
+                buf.extend_from_slice(&fee_bps.to_le_bytes());
+                buf.extend_from_slice(treasury.as_ref());
+            }
+            Self::UpdateConfig { admin, fee_bps, treasury, fee_exempt } => {
+                buf.push(9);
+                buf.extend_from_slice(admin.as_ref());
+                buf.extend_from_slice(&fee_bps.to_le_bytes());
+                buf.extend_from_slice(treasury.as_ref());
+                buf.push(fee_exempt.len() as u8);
+                for key in fee_exempt {
+                    buf.extend_from_slice(key.as_ref());
+                }
+            }
+            Self::Dispute { evidence_hash } => {
+                buf.push(10);
+                buf.extend_from_slice(evidence_hash);
+            }
+            Self::Resolve { taker_lamports, amount_x, x_to_taker } => {
+                buf.push(11);
+                buf.extend_from_slice(&taker_lamports.to_le_bytes());
+                buf.push(*amount_x);
+                for to_taker in x_to_taker.iter().take(*amount_x as usize) {
+                    buf.push(*to_taker as u8);
+                }
+            }
+            Self::ReleaseMilestone { milestone } => {
+                buf.push(12);
+                buf.push(*milestone);
+            }
+            Self::ClaimWithSecret { secret } => {
+                buf.push(13);
+                buf.extend_from_slice(secret);
+            }
+            Self::FillSignedOrder { order, authority_bump_seed } => {
+                buf.push(14);
+                let mut order_buf = [0; Order::LEN];
+                order.pack_into_slice(&mut order_buf);
+                buf.extend_from_slice(&order_buf);
+                buf.push(*authority_bump_seed);
+            }
+            Self::CancelOrder { nonce } => {
+                buf.push(15);
+                buf.extend_from_slice(&nonce.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    fn unpack_amount(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
+        if input.len() < 8 {
+            return Err(InstructionTooShort.into());
+        }
+        let (amount, rest) = input.split_at(8);
+        let amount = amount
+            .try_into()
+            .ok()
+            .map(u64::from_le_bytes)
+            .ok_or(InvalidInstruction)?;
+        Ok((amount, rest))
+    }
+
+    fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
+        if input.len() < PUBKEY_BYTES {
+            return Err(InstructionTooShort.into());
+        }
+        let (key, rest) = input.split_at(PUBKEY_BYTES);
+        let key = Pubkey::try_from(key).map_err(|_| InvalidInstruction)?;
+        Ok((key, rest))
+    }
+
+    fn unpack_fee_bps(input: &[u8]) -> Result<(u16, &[u8]), ProgramError> {
+        if input.len() < 2 {
+            return Err(InstructionTooShort.into());
+        }
+        let (fee_bps, rest) = input.split_at(2);
+        Ok((u16::from_le_bytes([fee_bps[0], fee_bps[1]]), rest))
+    }
+
+    fn unpack_hash(input: &[u8]) -> Result<([u8; 32], &[u8]), ProgramError> {
+        if input.len() < 32 {
+            return Err(InstructionTooShort.into());
+        }
+        let (hash, rest) = input.split_at(32);
+        let hash = hash.try_into().map_err(|_| InvalidInstruction)?;
+        Ok((hash, rest))
+    }
+
+    /// Unpacks the `0` or `1` tag of an optional field, or a flag
+    fn unpack_option_tag(input: &[u8]) -> Result<(bool, &[u8]), ProgramError> {
+        match input.split_first() {
+            None => Err(InstructionTooShort.into()),
+            Some((0, rest)) => Ok((false, rest)),
+            Some((1, rest)) => Ok((true, rest)),
+            Some(_) => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn unpack_optional_pubkey(input: &[u8]) -> Result<(Option<Pubkey>, &[u8]), ProgramError> {
+        let (is_some, rest) = Self::unpack_option_tag(input)?;
+        if !is_some {
+            return Ok((None, rest));
+        }
+        let (key, rest) = Self::unpack_pubkey(rest)?;
+        Ok((Some(key), rest))
+    }
+
+    fn unpack_optional_hash(input: &[u8]) -> Result<(Option<[u8; 32]>, &[u8]), ProgramError> {
+        let (is_some, rest) = Self::unpack_option_tag(input)?;
+        if !is_some {
+            return Ok((None, rest));
+        }
+        let (hash, rest) = Self::unpack_hash(rest)?;
+        Ok((Some(hash), rest))
+    }
 
-        let num_x = &input[10];
+    fn unpack_optional_amount(input: &[u8]) -> Result<(Option<u64>, &[u8]), ProgramError> {
+        let (is_some, rest) = Self::unpack_option_tag(input)?;
+        if !is_some {
+            return Ok((None, rest));
+        }
+        let (amount, rest) = Self::unpack_amount(rest)?;
+        Ok((Some(amount), rest))
+    }
 
-        let mut lamp_x: [u64; 9] = [0; 9];
-        let mut lamp_y: [u64; 9] = [0; 9];
+    fn unpack_optional_timestamp(input: &[u8]) -> Result<(Option<i64>, &[u8]), ProgramError> {
+        let (timestamp, rest) = Self::unpack_optional_amount(input)?;
+        Ok((timestamp.map(|timestamp| timestamp as i64), rest))
+    }
 
-        for i in 0..(*num_x) {
-            let i = i as usize;
-            lamp_x[i] = Self::unpack_amount(&input[(11+i*8)..=(18+i*8)])?;
+    /// Unpacks a leg count followed by that many amounts
+    fn unpack_legs(input: &[u8]) -> Result<(u8, [u64; MAX_LEGS], &[u8]), ProgramError> {
+        let (&count, mut rest) = input.split_first().ok_or(InstructionTooShort)?;
+        if count as usize > MAX_LEGS {
+            return Err(TooManyLegs.into());
         }
 
-        let num_x_usize = (*num_x) as usize;
+        let mut amounts = [0; MAX_LEGS];
+        for amount in amounts.iter_mut().take(count as usize) {
+            let (value, next) = Self::unpack_amount(rest)?;
+            *amount = value;
+            rest = next;
+        }
+        Ok((count, amounts, rest))
+    }
+
+    /// Unpacks a leg count followed by a `0` or `1` flag per leg
+    fn unpack_flags(input: &[u8]) -> Result<(u8, [bool; MAX_LEGS], &[u8]), ProgramError> {
+        let (&count, rest) = input.split_first().ok_or(InstructionTooShort)?;
+        if count as usize > MAX_LEGS {
+            return Err(TooManyLegs.into());
+        }
+        if rest.len() < count as usize {
+            return Err(InstructionTooShort.into());
+        }
+
+        let (values, rest) = rest.split_at(count as usize);
+        let mut flags = [false; MAX_LEGS];
+        for (flag, value) in flags.iter_mut().zip(values) {
+            *flag = match value {
+                0 => false,
+                1 => true,
+                _ => return Err(InvalidInstruction.into()),
+            };
+        }
+        Ok((count, flags, rest))
+    }
+
+    /// Unpacks a milestone count followed by that many shares
+    fn unpack_milestones(input: &[u8]) -> Result<(Vec<u16>, &[u8]), ProgramError> {
+        let (&count, mut rest) = input.split_first().ok_or(InstructionTooShort)?;
+        if count as usize > MAX_MILESTONES {
+            return Err(InvalidMilestones.into());
+        }
+
+        let mut milestones = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (share, next) = Self::unpack_fee_bps(rest)?;
+            milestones.push(share);
+            rest = next;
+        }
+        Ok((milestones, rest))
+    }
+
+    /// Unpacks a count of at most `MAX_LEGS` followed by that many bump seeds
+    fn unpack_bump_seeds(input: &[u8]) -> Result<(Vec<u8>, &[u8]), ProgramError> {
+        let (&count, rest) = input.split_first().ok_or(InstructionTooShort)?;
+        if count as usize > MAX_LEGS {
+            return Err(TooManyLegs.into());
+        }
+        if rest.len() < count as usize {
+            return Err(InstructionTooShort.into());
+        }
+        let (bump_seeds, rest) = rest.split_at(count as usize);
+        Ok((bump_seeds.to_vec(), rest))
+    }
+
+    fn pack_terms(
+        buf: &mut Vec<u8>,
+        lamports: u64,
+        sol_dir: u8,
+        amount_x: u8,
+        amount_y: u8,
+        lamports_x: &[u64; MAX_LEGS],
+        lamports_y: &[u64; MAX_LEGS],
+    ) {
+        buf.push(sol_dir);
+        buf.extend_from_slice(&lamports.to_le_bytes());
+        Self::pack_legs(buf, amount_x, lamports_x);
+        Self::pack_legs(buf, amount_y, lamports_y);
+    }
+
+    fn pack_legs(buf: &mut Vec<u8>, count: u8, amounts: &[u64; MAX_LEGS]) {
+        buf.push(count);
+        for amount in amounts.iter().take(count as usize) {
+            buf.extend_from_slice(&amount.to_le_bytes());
+        }
+    }
+
+    fn pack_bump_seeds(buf: &mut Vec<u8>, bump_seeds: &[u8]) {
+        buf.push(bump_seeds.len() as u8);
+        buf.extend_from_slice(bump_seeds);
+    }
+
+    fn pack_optional_pubkey(buf: &mut Vec<u8>, key: Option<&Pubkey>) {
+        match key {
+            Some(key) => {
+                buf.push(1);
+                buf.extend_from_slice(key.as_ref());
+            }
+            None => buf.push(0),
+        }
+    }
+
+    fn pack_optional_amount(buf: &mut Vec<u8>, amount: Option<u64>) {
+        match amount {
+            Some(amount) => {
+                buf.push(1);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            None => buf.push(0),
+        }
+    }
+
+    fn pack_optional_timestamp(buf: &mut Vec<u8>, timestamp: Option<i64>) {
+        Self::pack_optional_amount(buf, timestamp.map(|timestamp| timestamp as u64));
+    }
+}
+
+/// Settings of an `InitEscrow` instruction besides its terms, the default opens a custodial escrow with seed 0 that
+/// settles by exchange, never expires and sends the rent back to the initializer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InitEscrowArgs {
+    /// Picked by the initializer so they can open several escrows, see `find_escrow_address`
+    pub seed: u64,
+    /// Receives the rent of the vaults and of the escrow account, the initializer when `None`
+    pub rent_destination: Option<Pubkey>,
+    /// Unix timestamp after which the escrow can no longer be taken, never when `None`
+    pub expires_at: Option<i64>,
+    /// Pay the Metaplex royalties of the NFT legs out of the SOL leg
+    pub enforce_royalties: bool,
+    /// Settles the trade if a party disputes it, no arbiter when `None`
+    pub arbiter: Option<Pubkey>,
+    /// Share of the legs each milestone releases to the taker, see `EscrowInstruction::InitEscrow`
+    pub milestones: Vec<u16>,
+    /// SHA-256 hash of the secret the taker claims the trade with until `expires_at`
+    pub hashlock: Option<[u8; 32]>,
+    /// Keep the X legs in the initializer's token accounts, approved to the PDA
+    pub non_custodial: bool,
+}
+
+/// Creates an `InitEscrow` instruction for the escrow at `find_escrow_address(program_id, initializer, args.seed)`.
+/// Each X leg comes from the initializer's associated token account for its mint, `temp_token_account` is ignored.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    legs_x: &[XLeg],
+    legs_y: &[YLeg],
+    sol_dir: u8,
+    lamports: u64,
+    args: &InitEscrowArgs,
+) -> Result<Instruction, ProgramError> {
+    if args.milestones.len() > MAX_MILESTONES {
+        return Err(InvalidMilestones.into());
+    }
+    let (amount_x, lamports_x) = leg_amounts(legs_x.iter().map(|leg| leg.amount))?;
+    let (amount_y, lamports_y) = leg_amounts(legs_y.iter().map(|leg| leg.amount))?;
+    let data = EscrowInstruction::InitEscrow {
+        lamports,
+        sol_dir,
+        amount_x,
+        amount_y,
+        lamports_x,
+        lamports_y,
+        seed: args.seed,
+        rent_destination: args.rent_destination,
+        expires_at: args.expires_at,
+        enforce_royalties: args.enforce_royalties,
+        arbiter: args.arbiter,
+        milestones: args.milestones.clone(),
+        hashlock: args.hashlock,
+        non_custodial: args.non_custodial,
+    }
+    .pack();
+    let (escrow, _escrow_bump_seed) = find_escrow_address(program_id, initializer, args.seed);
+    let escrow = &escrow;
 
-        let num_y = &input[11+8*num_x_usize];
-        for j in 0..(*num_y) {
-            let j= j as usize;
-            lamp_y[j] = Self::unpack_amount(&input[(12+8*(num_x_usize+j))..=(19+8*(num_x_usize+j))])?;
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new_readonly(*taker, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for (i, leg) in legs_x.iter().enumerate() {
+        accounts.push(AccountMeta::new(deposit_token_account(initializer, leg, args.non_custodial), false));
+        if !args.non_custodial {
+            accounts.push(AccountMeta::new(find_vault_address(program_id, escrow, i as u8).0, false));
         }
-        
-        Ok(match tag {
-            0 => Self::InitEscrow {
-                // amount: Self::unpack_amount(rest)?,
-                lamports: lamports,
-                sol_dir: *sol_dir,
-                amount_x: *num_x,
-                amount_y: *num_y,
-                lamports_x: lamp_x,
-                lamports_y: lamp_y
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+        accounts.push(AccountMeta::new_readonly(leg.token_program, false));
+    }
+    for leg in legs_y {
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+        accounts.push(AccountMeta::new_readonly(leg.token_program, false));
+    }
+    if args.non_custodial {
+        accounts.push(AccountMeta::new_readonly(find_escrow_authority(program_id, escrow).0, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `ExchangeStored` instruction, `rent_destination` is the one stored in the escrow.
+/// `treasury` is the one of the config and `treasury_token_accounts` hold the fee of every leg charged one.
+/// `royalties` lists the mint of each NFT leg sold and its paid creators when the escrow enforces royalties.
+/// The trade fails if the terms changed after `expected_version`, the version the taker read them at.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    treasury: &Pubkey,
+    treasury_token_accounts: &[Pubkey],
+    legs_x: &[XLeg],
+    legs_y: &[YLeg],
+    royalties: &[(Pubkey, Vec<Pubkey>)],
+    expected_version: u64,
+) -> Result<Instruction, ProgramError> {
+    exchange_instruction(
+        program_id,
+        initializer,
+        taker,
+        escrow,
+        rent_destination,
+        treasury,
+        treasury_token_accounts,
+        legs_x,
+        legs_y,
+        royalties,
+        |metadata_bump_seeds| EscrowInstruction::ExchangeStored { expected_version, metadata_bump_seeds },
+    )
+}
+
+/// Creates an `ExchangePartial` instruction settling `fill_numerator / FILL_DENOMINATOR` of the remaining terms.
+#[allow(clippy::too_many_arguments)]
+pub fn exchange_partial(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    treasury: &Pubkey,
+    treasury_token_accounts: &[Pubkey],
+    legs_x: &[XLeg],
+    legs_y: &[YLeg],
+    royalties: &[(Pubkey, Vec<Pubkey>)],
+    fill_numerator: u64,
+    expected_version: u64,
+) -> Result<Instruction, ProgramError> {
+    exchange_instruction(
+        program_id,
+        initializer,
+        taker,
+        escrow,
+        rent_destination,
+        treasury,
+        treasury_token_accounts,
+        legs_x,
+        legs_y,
+        royalties,
+        |metadata_bump_seeds| EscrowInstruction::ExchangePartial {
+            fill_numerator,
+            expected_version,
+            metadata_bump_seeds,
+        },
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn exchange_instruction(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    treasury: &Pubkey,
+    treasury_token_accounts: &[Pubkey],
+    legs_x: &[XLeg],
+    legs_y: &[YLeg],
+    royalties: &[(Pubkey, Vec<Pubkey>)],
+    instruction: impl FnOnce(Vec<u8>) -> EscrowInstruction,
+) -> Result<Instruction, ProgramError> {
+    check_leg_counts(legs_x, legs_y)?;
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        AccountMeta::new(*treasury, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+    ];
+    for treasury_token_account in treasury_token_accounts {
+        accounts.push(AccountMeta::new(*treasury_token_account, false));
+    }
+    for leg in legs_x {
+        push_x_leg_accounts(&mut accounts, leg, Some(taker));
+    }
+    push_y_leg_accounts(&mut accounts, initializer, taker, legs_y);
+    let mut metadata_bump_seeds = Vec::with_capacity(royalties.len());
+    for (mint, creators) in royalties {
+        let (metadata, metadata_bump_seed) = find_metadata_address(mint);
+        accounts.push(AccountMeta::new_readonly(metadata, false));
+        accounts.extend(creators.iter().map(|creator| AccountMeta::new(*creator, false)));
+        metadata_bump_seeds.push(metadata_bump_seed);
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: instruction(metadata_bump_seeds).pack(),
+    })
+}
+
+/// Creates a `CancelStored` instruction, `rent_destination` is the one stored in the escrow.
+/// The X legs go back to the initializer's associated token accounts, they stay put when `non_custodial`.
+/// `cosigner` is the arbiter or the taker approving the cancel of an escrow with an arbiter, `None` otherwise.
+#[allow(clippy::too_many_arguments)]
+pub fn cancel_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    legs_x: &[XLeg],
+    non_custodial: bool,
+    cosigner: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    check_leg_counts(legs_x, &[])?;
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new_readonly(*taker, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+    ];
+    for leg in legs_x {
+        push_x_leg_accounts(&mut accounts, leg, (!non_custodial).then_some(initializer));
+    }
+    if let Some(cosigner) = cosigner {
+        accounts.push(AccountMeta::new_readonly(*cosigner, true));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::CancelStored.pack(),
+    })
+}
+
+/// Creates a `ReclaimExpired` instruction, anyone can send it once the escrow has expired unless it is `non_custodial`,
+/// the initializer then signs it. The X legs go back like with `cancel_escrow`.
+pub fn reclaim_expired(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    legs_x: &[XLeg],
+    non_custodial: bool,
+) -> Result<Instruction, ProgramError> {
+    check_leg_counts(legs_x, &[])?;
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, non_custodial),
+        AccountMeta::new_readonly(*taker, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+    ];
+    for leg in legs_x {
+        push_x_leg_accounts(&mut accounts, leg, (!non_custodial).then_some(initializer));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ReclaimExpired.pack(),
+    })
+}
+
+/// Creates an `UpdateEscrow` instruction. `legs_x` lists every X leg, existing ones first, with the tokens to add
+/// as `amount`, and `lamports_y` the new amount of every Y leg. `non_custodial` is the mode stored in the escrow.
+/// `expires_at` replaces the expiry, `Some(0)` removes it and `None` keeps it.
+#[allow(clippy::too_many_arguments)]
+pub fn update_escrow(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    legs_x: &[XLeg],
+    lamports_y: &[u64],
+    lamports: u64,
+    expires_at: Option<i64>,
+    non_custodial: bool,
+) -> Result<Instruction, ProgramError> {
+    let (amount_x, top_up_x) = leg_amounts(legs_x.iter().map(|leg| leg.amount))?;
+    let (amount_y, lamports_y) = leg_amounts(lamports_y.iter().copied())?;
+    let data = EscrowInstruction::UpdateEscrow {
+        lamports,
+        amount_x,
+        top_up_x,
+        amount_y,
+        lamports_y,
+        expires_at,
+    }
+    .pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    push_deposit_accounts(&mut accounts, program_id, initializer, escrow, legs_x, non_custodial);
+    if non_custodial {
+        accounts.push(AccountMeta::new_readonly(find_escrow_authority(program_id, escrow).0, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an `InitConfig` instruction, `upgrade_authority` must be the upgrade authority of the program.
+pub fn init_config(
+    program_id: &Pubkey,
+    upgrade_authority: &Pubkey,
+    fee_bps: u16,
+    treasury: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (program_data, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let accounts = vec![
+        AccountMeta::new(*upgrade_authority, true),
+        AccountMeta::new(find_config_address(program_id).0, false),
+        AccountMeta::new_readonly(program_data, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitConfig { fee_bps, treasury: *treasury }.pack(),
+    })
+}
+
+/// Creates an `UpdateConfig` instruction signed by the current `admin`.
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    new_admin: &Pubkey,
+    fee_bps: u16,
+    treasury: &Pubkey,
+    fee_exempt: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    if fee_exempt.len() > MAX_FEE_EXEMPT {
+        return Err(TooManyFeeExempt.into());
+    }
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(find_config_address(program_id).0, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::UpdateConfig {
+            admin: *new_admin,
+            fee_bps,
+            treasury: *treasury,
+            fee_exempt: fee_exempt.to_vec(),
+        }
+        .pack(),
+    })
+}
+
+/// Creates a `Dispute` instruction signed by `party`, the initializer or the taker of the escrow.
+pub fn dispute(
+    program_id: &Pubkey,
+    party: &Pubkey,
+    escrow: &Pubkey,
+    evidence_hash: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*party, true),
+        AccountMeta::new(*escrow, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Dispute { evidence_hash }.pack(),
+    })
+}
+
+/// Creates a `Resolve` instruction signed by the `arbiter` of the escrow, `x_to_taker` tells for each X leg whether
+/// it goes to the taker and `taker_lamports` is the taker's share of the SOL leg.
+#[allow(clippy::too_many_arguments)]
+pub fn resolve(
+    program_id: &Pubkey,
+    arbiter: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    legs_x: &[XLeg],
+    x_to_taker: &[bool],
+    taker_lamports: u64,
+) -> Result<Instruction, ProgramError> {
+    if legs_x.len() > MAX_LEGS {
+        return Err(TooManyLegs.into());
+    }
+    if x_to_taker.len() != legs_x.len() {
+        return Err(InvalidInstruction.into());
+    }
+    let mut to_taker = [false; MAX_LEGS];
+    to_taker[..x_to_taker.len()].copy_from_slice(x_to_taker);
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*arbiter, true),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*taker, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+    ];
+    for (leg, &to_taker) in legs_x.iter().zip(x_to_taker) {
+        push_x_leg_accounts(&mut accounts, leg, Some(if to_taker { taker } else { initializer }));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Resolve { taker_lamports, amount_x: legs_x.len() as u8, x_to_taker: to_taker }.pack(),
+    })
+}
+
+/// Creates a `ReleaseMilestone` instruction signed by `approver`, the initializer or the arbiter of the escrow.
+#[allow(clippy::too_many_arguments)]
+pub fn release_milestone(
+    program_id: &Pubkey,
+    approver: &Pubkey,
+    initializer: &Pubkey,
+    taker: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    legs_x: &[XLeg],
+    milestone: u8,
+) -> Result<Instruction, ProgramError> {
+    check_leg_counts(legs_x, &[])?;
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*approver, true),
+        AccountMeta::new_readonly(*initializer, false),
+        AccountMeta::new(*taker, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+    ];
+    for leg in legs_x {
+        push_x_leg_accounts(&mut accounts, leg, Some(taker));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ReleaseMilestone { milestone }.pack(),
+    })
+}
+
+/// Creates a `ClaimWithSecret` instruction signed by the `taker` of a hashlocked escrow.
+#[allow(clippy::too_many_arguments)]
+pub fn claim_with_secret(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    rent_destination: &Pubkey,
+    legs_x: &[XLeg],
+    secret: [u8; 32],
+) -> Result<Instruction, ProgramError> {
+    check_leg_counts(legs_x, &[])?;
+    let (pda, _nonce) = find_escrow_authority(program_id, escrow);
+
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new_readonly(*initializer, false),
+        AccountMeta::new(*escrow, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new(*rent_destination, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+    ];
+    for leg in legs_x {
+        push_x_leg_accounts(&mut accounts, leg, Some(taker));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ClaimWithSecret { secret }.pack(),
+    })
+}
+
+/// Creates the Ed25519 program instruction verifying the maker's `signature` over `Order::message` for `program_id`,
+/// it must come right before the `FillSignedOrder` instruction
+pub fn verify_order_signature(program_id: &Pubkey, order: &Order, signature: &[u8; 64]) -> Instruction {
+    // one signature, its offsets, then the public key, the signature and the message, all in this instruction
+    const PUBLIC_KEY_OFFSET: u16 = 16;
+    const SIGNATURE_OFFSET: u16 = PUBLIC_KEY_OFFSET + PUBKEY_BYTES as u16;
+    const MESSAGE_OFFSET: u16 = SIGNATURE_OFFSET + 64;
+    let message = order.message(program_id);
+
+    let mut data = Vec::with_capacity(MESSAGE_OFFSET as usize + message.len());
+    data.extend_from_slice(&[1, 0]);
+    for value in [
+        SIGNATURE_OFFSET,
+        u16::MAX,
+        PUBLIC_KEY_OFFSET,
+        u16::MAX,
+        MESSAGE_OFFSET,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(order.maker.as_ref());
+    data.extend_from_slice(signature);
+    data.extend_from_slice(&message);
+
+    Instruction {
+        program_id: ed25519_program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// Creates a `FillSignedOrder` instruction for `taker`, it must follow `verify_order_signature`.
+/// `taker_token_account_y` is ignored when the order has no Y amount.
+pub fn fill_signed_order(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    order: &Order,
+    maker_token_account_x: &Pubkey,
+    token_program_x: &Pubkey,
+    taker_token_account_y: &Pubkey,
+    token_program_y: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (nonces, _nonces_bump_seed) =
+        find_order_nonces_address(program_id, &order.maker, NonceBitmap::page(order.nonce));
+    let (order_authority, authority_bump_seed) = find_order_authority(program_id, &order.maker);
+
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(order.maker, false),
+        AccountMeta::new_readonly(order_authority, false),
+        AccountMeta::new(nonces, false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new(*maker_token_account_x, false),
+        AccountMeta::new(
+            get_associated_token_address_with_program_id(taker, &order.mint_x, token_program_x),
+            false,
+        ),
+        AccountMeta::new_readonly(order.mint_x, false),
+        AccountMeta::new_readonly(*token_program_x, false),
+    ];
+    if order.amount_y > 0 {
+        accounts.push(AccountMeta::new(*taker_token_account_y, false));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(&order.maker, &order.mint_y, token_program_y),
+            false,
+        ));
+        accounts.push(AccountMeta::new_readonly(order.mint_y, false));
+        accounts.push(AccountMeta::new_readonly(*token_program_y, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::FillSignedOrder { order: *order, authority_bump_seed }.pack(),
+    })
+}
+
+/// Creates a `CancelOrder` instruction signed by the `maker` of the order.
+pub fn cancel_order(program_id: &Pubkey, maker: &Pubkey, nonce: u64) -> Result<Instruction, ProgramError> {
+    let (nonces, _nonces_bump_seed) = find_order_nonces_address(program_id, maker, NonceBitmap::page(nonce));
+    let accounts = vec![
+        AccountMeta::new(*maker, true),
+        AccountMeta::new(nonces, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::CancelOrder { nonce }.pack(),
+    })
+}
+
+/// Collects leg amounts into the fixed size array carried by the instruction
+fn leg_amounts(amounts: impl ExactSizeIterator<Item = u64>) -> Result<(u8, [u64; MAX_LEGS]), ProgramError> {
+    if amounts.len() > MAX_LEGS {
+        return Err(TooManyLegs.into());
+    }
+    let count = amounts.len() as u8;
+    let mut lamports = [0; MAX_LEGS];
+    for (slot, amount) in lamports.iter_mut().zip(amounts) {
+        *slot = amount;
+    }
+    Ok((count, lamports))
+}
+
+fn check_leg_counts(legs_x: &[XLeg], legs_y: &[YLeg]) -> Result<(), ProgramError> {
+    if legs_x.len() > MAX_LEGS || legs_y.len() > MAX_LEGS {
+        return Err(TooManyLegs.into());
+    }
+    Ok(())
+}
+
+/// The token account an X leg is deposited from, the one already backing the leg in a non-custodial escrow,
+/// otherwise the initializer's associated token account for the mint
+fn deposit_token_account(initializer: &Pubkey, leg: &XLeg, non_custodial: bool) -> Pubkey {
+    if non_custodial && leg.temp_token_account != Pubkey::default() {
+        return leg.temp_token_account;
+    }
+    get_associated_token_address_with_program_id(initializer, &leg.mint, &leg.token_program)
+}
+
+/// Appends the accounts of the X legs the initializer deposits, their vault derived from the leg index or, in a
+/// non-custodial escrow, their own token account
+fn push_deposit_accounts(
+    accounts: &mut Vec<AccountMeta>,
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow: &Pubkey,
+    legs_x: &[XLeg],
+    non_custodial: bool,
+) {
+    for (i, leg) in legs_x.iter().enumerate() {
+        let source = deposit_token_account(initializer, leg, non_custodial);
+        let vault = if non_custodial { source } else { find_vault_address(program_id, escrow, i as u8).0 };
+        accounts.push(AccountMeta::new(source, false));
+        accounts.push(AccountMeta::new(vault, false));
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+        accounts.push(AccountMeta::new_readonly(leg.token_program, false));
+    }
+}
+
+/// Appends the accounts of an X leg the program settles, after the associated token account of `recipient`
+/// receiving it when the leg moves
+fn push_x_leg_accounts(accounts: &mut Vec<AccountMeta>, leg: &XLeg, recipient: Option<&Pubkey>) {
+    if let Some(recipient) = recipient {
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(recipient, &leg.mint, &leg.token_program),
+            false,
+        ));
+    }
+    accounts.push(AccountMeta::new(leg.temp_token_account, false));
+    accounts.push(AccountMeta::new(leg.mint, false));
+    accounts.push(AccountMeta::new_readonly(leg.token_program, false));
+}
+
+/// Appends the accounts of the Y legs, the associated token accounts of `initializer` receiving them and of `taker`
+/// sending them
+fn push_y_leg_accounts(accounts: &mut Vec<AccountMeta>, initializer: &Pubkey, taker: &Pubkey, legs_y: &[YLeg]) {
+    for leg in legs_y {
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(initializer, &leg.mint, &leg.token_program),
+            false,
+        ));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(taker, &leg.mint, &leg.token_program),
+            false,
+        ));
+        accounts.push(AccountMeta::new_readonly(leg.mint, false));
+        accounts.push(AccountMeta::new_readonly(leg.token_program, false));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EscrowError::TooManyFeeExempt;
+
+    fn legs(amounts: &[u64]) -> [u64; MAX_LEGS] {
+        let mut legs = [0; MAX_LEGS];
+        legs[..amounts.len()].copy_from_slice(amounts);
+        legs
+    }
+
+    /// One instruction of every variant, with every optional field set
+    fn instructions() -> Vec<EscrowInstruction> {
+        let order = Order {
+            maker: Pubkey::new_unique(),
+            taker: Pubkey::new_unique(),
+            nonce: 7,
+            expires_at: 1_700_000_000,
+            mint_x: Pubkey::new_unique(),
+            amount_x: 1,
+            mint_y: Pubkey::new_unique(),
+            amount_y: 250,
+            lamports: 1_000_000,
+        };
+        vec![
+            EscrowInstruction::InitEscrow {
+                lamports: 2_000_000_000,
+                sol_dir: 1,
+                amount_x: 2,
+                amount_y: 1,
+                lamports_x: legs(&[1, 500]),
+                lamports_y: legs(&[3]),
+                seed: 42,
+                rent_destination: Some(Pubkey::new_unique()),
+                expires_at: Some(1_700_000_000),
+                enforce_royalties: true,
+                arbiter: Some(Pubkey::new_unique()),
+                milestones: vec![2_500, 7_500],
+                hashlock: Some([9; 32]),
+                non_custodial: true,
             },
-            1 => Self::Exchange {
-                // amount: Self::unpack_amount(rest)?,
-                lamports: lamports,
-                sol_dir: *sol_dir,
-                amount_x: *num_x,
-                amount_y: *num_y,
-                lamports_x: lamp_x,
-                lamports_y: lamp_y
+            EscrowInstruction::Exchange {
+                lamports: 5,
+                sol_dir: 2,
+                amount_x: 1,
+                amount_y: 2,
+                lamports_x: legs(&[10]),
+                lamports_y: legs(&[20, 30]),
+                metadata_bump_seeds: vec![255],
             },
-            2 => Self::CancelEscrow {
-                // amount: Self::unpack_amount(rest)?,
-                lamports: lamports,
-                sol_dir: *sol_dir,
-                amount_x: *num_x,
-                amount_y: *num_y,
-                lamports_x: lamp_x,
-                lamports_y: lamp_y
+            EscrowInstruction::CancelEscrow {
+                lamports: 5,
+                sol_dir: 1,
+                amount_x: MAX_LEGS as u8,
+                amount_y: 0,
+                lamports_x: [u64::MAX; MAX_LEGS],
+                lamports_y: legs(&[]),
             },
-            _ => return Err(InvalidInstruction.into()),
-        })
+            EscrowInstruction::ExchangeStored { expected_version: 3, metadata_bump_seeds: vec![254, 253] },
+            EscrowInstruction::CancelStored,
+            EscrowInstruction::ReclaimExpired,
+            EscrowInstruction::ExchangePartial {
+                fill_numerator: FILL_DENOMINATOR / 4,
+                expected_version: 0,
+                metadata_bump_seeds: vec![u8::MAX; MAX_LEGS],
+            },
+            EscrowInstruction::UpdateEscrow {
+                lamports: 1,
+                amount_x: 3,
+                top_up_x: legs(&[0, 0, 100]),
+                amount_y: 1,
+                lamports_y: legs(&[4]),
+                expires_at: Some(-1),
+            },
+            EscrowInstruction::InitConfig { fee_bps: 250, treasury: Pubkey::new_unique() },
+            EscrowInstruction::UpdateConfig {
+                admin: Pubkey::new_unique(),
+                fee_bps: 100,
+                treasury: Pubkey::new_unique(),
+                fee_exempt: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+            },
+            EscrowInstruction::Dispute { evidence_hash: [7; 32] },
+            EscrowInstruction::Resolve {
+                taker_lamports: 1_000,
+                amount_x: 3,
+                x_to_taker: [true, false, true, false, false, false, false, false, false],
+            },
+            EscrowInstruction::ReleaseMilestone { milestone: 1 },
+            EscrowInstruction::ClaimWithSecret { secret: [5; 32] },
+            EscrowInstruction::FillSignedOrder { order, authority_bump_seed: 254 },
+            EscrowInstruction::CancelOrder { nonce: u64::MAX },
+        ]
     }
 
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
-        let amount = input
-            .get(..8)
-            .and_then(|slice| slice.try_into().ok())
-            .map(u64::from_le_bytes)
-            .ok_or(InvalidInstruction)?;
-        Ok(amount)
+    /// The variants with optional fields, all of them absent
+    fn instructions_without_optional_fields() -> Vec<EscrowInstruction> {
+        vec![
+            EscrowInstruction::InitEscrow {
+                lamports: 0,
+                sol_dir: 0,
+                amount_x: 1,
+                amount_y: 0,
+                lamports_x: legs(&[1]),
+                lamports_y: legs(&[]),
+                seed: 0,
+                rent_destination: None,
+                expires_at: None,
+                enforce_royalties: false,
+                arbiter: None,
+                milestones: Vec::new(),
+                hashlock: None,
+                non_custodial: false,
+            },
+            EscrowInstruction::UpdateEscrow {
+                lamports: 0,
+                amount_x: 0,
+                top_up_x: legs(&[]),
+                amount_y: 0,
+                lamports_y: legs(&[]),
+                expires_at: None,
+            },
+            EscrowInstruction::UpdateConfig {
+                admin: Pubkey::new_unique(),
+                fee_bps: 0,
+                treasury: Pubkey::new_unique(),
+                fee_exempt: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn pack_round_trips_every_variant() {
+        for instruction in instructions() {
+            assert_eq!(EscrowInstruction::unpack(&instruction.pack()), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn pack_round_trips_absent_optional_fields() {
+        for instruction in instructions_without_optional_fields() {
+            assert_eq!(EscrowInstruction::unpack(&instruction.pack()), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_truncated_input() {
+        assert_eq!(EscrowInstruction::unpack(&[]), Err(InstructionTooShort.into()));
+        // every field is required, optional ones included, so no prefix of an instruction is one
+        for instruction in instructions().into_iter().chain(instructions_without_optional_fields()) {
+            let data = instruction.pack();
+            for len in 1..data.len() {
+                assert_eq!(
+                    EscrowInstruction::unpack(&data[..len]),
+                    Err(InstructionTooShort.into()),
+                    "{:?} cut to {} bytes",
+                    instruction,
+                    len
+                );
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unpack_accepts_only_canonical_input() {
+        // every one byte change of a valid instruction either fails or packs back to the same bytes
+        for instruction in instructions().into_iter().chain(instructions_without_optional_fields()) {
+            let data = instruction.pack();
+            for i in 0..data.len() {
+                for value in [0, 1, 2, u8::MAX] {
+                    let mut changed = data.clone();
+                    changed[i] = value;
+                    if let Ok(unpacked) = EscrowInstruction::unpack(&changed) {
+                        assert_eq!(unpacked.pack(), changed, "{:?} with byte {} set to {}", instruction, i, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_an_optional_field_cut_after_its_tag() {
+        let data = EscrowInstruction::UpdateEscrow {
+            lamports: 0,
+            amount_x: 0,
+            top_up_x: legs(&[]),
+            amount_y: 0,
+            lamports_y: legs(&[]),
+            expires_at: Some(2),
+        }
+        .pack();
+
+        assert_eq!(EscrowInstruction::unpack(&data[..data.len() - 2]), Err(InstructionTooShort.into()));
+        assert_eq!(EscrowInstruction::unpack(&data[..12]), Err(InstructionTooShort.into()));
+    }
+
+    #[test]
+    fn unpack_rejects_leg_counts_above_max_legs() {
+        let too_many = MAX_LEGS as u8 + 1;
+        let mut amount_x_data = vec![1, 1];
+        amount_x_data.extend_from_slice(&0u64.to_le_bytes());
+        amount_x_data.push(too_many);
+        amount_x_data.extend_from_slice(&[0; 8 * MAX_LEGS + 8]);
+        assert_eq!(EscrowInstruction::unpack(&amount_x_data), Err(TooManyLegs.into()));
+
+        let mut amount_y_data = vec![1, 1];
+        amount_y_data.extend_from_slice(&0u64.to_le_bytes());
+        amount_y_data.push(0);
+        amount_y_data.push(too_many);
+        amount_y_data.extend_from_slice(&[0; 8 * MAX_LEGS + 8]);
+        assert_eq!(EscrowInstruction::unpack(&amount_y_data), Err(TooManyLegs.into()));
+
+        let mut resolve_data = vec![11];
+        resolve_data.extend_from_slice(&0u64.to_le_bytes());
+        resolve_data.push(too_many);
+        resolve_data.extend_from_slice(&[0; MAX_LEGS + 1]);
+        assert_eq!(EscrowInstruction::unpack(&resolve_data), Err(TooManyLegs.into()));
+    }
+
+    #[test]
+    fn unpack_rejects_lists_above_their_max() {
+        let mut update_config_data = vec![9];
+        update_config_data.extend_from_slice(&[0; PUBKEY_BYTES + 2 + PUBKEY_BYTES]);
+        update_config_data.push(MAX_FEE_EXEMPT as u8 + 1);
+        update_config_data.extend_from_slice(&[0; PUBKEY_BYTES * (MAX_FEE_EXEMPT + 1)]);
+        assert_eq!(EscrowInstruction::unpack(&update_config_data), Err(TooManyFeeExempt.into()));
+
+        let mut exchange_data = vec![3];
+        exchange_data.extend_from_slice(&0u64.to_le_bytes());
+        exchange_data.push(MAX_LEGS as u8 + 1);
+        exchange_data.extend_from_slice(&[0; MAX_LEGS + 1]);
+        assert_eq!(EscrowInstruction::unpack(&exchange_data), Err(TooManyLegs.into()));
+
+        let mut init_data = EscrowInstruction::Exchange {
+            lamports: 0,
+            sol_dir: 1,
+            amount_x: 1,
+            amount_y: 0,
+            lamports_x: legs(&[1]),
+            lamports_y: legs(&[]),
+            metadata_bump_seeds: Vec::new(),
+        }
+        .pack();
+        // the terms are encoded like those of `InitEscrow`, the bump seed count is not
+        init_data[0] = 0;
+        init_data.pop();
+        init_data.extend_from_slice(&0u64.to_le_bytes());
+        init_data.extend_from_slice(&[0, 0, 0, 0]);
+        init_data.push(MAX_MILESTONES as u8 + 1);
+        init_data.extend_from_slice(&[0; 2 * (MAX_MILESTONES + 1)]);
+        assert_eq!(EscrowInstruction::unpack(&init_data), Err(InvalidMilestones.into()));
+    }
+
+    #[test]
+    fn unpack_rejects_trailing_bytes() {
+        for instruction in instructions() {
+            let mut data = instruction.pack();
+            data.push(0);
+            assert_eq!(
+                EscrowInstruction::unpack(&data),
+                Err(TrailingInstructionData.into()),
+                "{:?} with a trailing byte",
+                instruction
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_unknown_tags_and_flags() {
+        assert_eq!(EscrowInstruction::unpack(&[16]), Err(InvalidInstruction.into()));
+        assert_eq!(EscrowInstruction::unpack(&[u8::MAX]), Err(InvalidInstruction.into()));
+
+        let mut data = EscrowInstruction::UpdateEscrow {
+            lamports: 0,
+            amount_x: 0,
+            top_up_x: legs(&[]),
+            amount_y: 0,
+            lamports_y: legs(&[]),
+            expires_at: None,
+        }
+        .pack();
+        *data.last_mut().unwrap() = 2;
+        assert_eq!(EscrowInstruction::unpack(&data), Err(InvalidInstruction.into()));
+
+        let mut data = EscrowInstruction::Resolve { taker_lamports: 0, amount_x: 1, x_to_taker: [false; MAX_LEGS] }.pack();
+        *data.last_mut().unwrap() = 2;
+        assert_eq!(EscrowInstruction::unpack(&data), Err(InvalidInstruction.into()));
+    }
+}
@@ -1,6 +1,87 @@
 pub mod error;
 pub mod instruction;
+pub mod metadata;
+pub mod order;
 pub mod processor;
+pub mod state;
+pub mod validation;
 
+// `entrypoint!` of solana-program 1.x checks cfgs that only exist when building for the Solana target
 #[cfg(not(feature = "no-entrypoint"))]
-pub mod entrypoint;
\ No newline at end of file
+#[allow(unexpected_cfgs)]
+pub mod entrypoint;
+
+use solana_program::pubkey::{Pubkey, PubkeyError};
+
+/// Seed prefix of the PDA that owns an escrow's temp token accounts
+pub const AUTHORITY_SEED: &[u8] = b"escrow";
+
+/// Seed prefix of the escrow accounts created by the program
+pub const ESCROW_SEED: &[u8] = b"escrow_state";
+
+/// Seed prefix of the program created vault token accounts
+pub const VAULT_SEED: &[u8] = b"vault";
+
+/// Seed of the global config account
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Seed prefix of the PDA a maker approves as delegate to fill their signed orders
+pub const ORDER_AUTHORITY_SEED: &[u8] = b"order";
+
+/// Seed prefix of the accounts holding the used nonces of a maker's signed orders
+pub const NONCES_SEED: &[u8] = b"nonces";
+
+/// Derives the PDA that owns the temp token accounts of `escrow`, each escrow gets its own
+pub fn find_escrow_authority(program_id: &Pubkey, escrow: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[AUTHORITY_SEED, escrow.as_ref()], program_id)
+}
+
+/// Recreates the PDA of `escrow` from the bump seed stored at init, much cheaper than finding it again
+pub fn create_escrow_authority(program_id: &Pubkey, escrow: &Pubkey, bump_seed: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[AUTHORITY_SEED, escrow.as_ref(), &[bump_seed]], program_id)
+}
+
+/// Derives the address of the vault token account holding X leg `index` of `escrow`
+pub fn find_vault_address(program_id: &Pubkey, escrow: &Pubkey, index: u8) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, escrow.as_ref(), &[index]], program_id)
+}
+
+/// Derives the address of the escrow account `initializer` opens with `seed`
+pub fn find_escrow_address(program_id: &Pubkey, initializer: &Pubkey, seed: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_SEED, initializer.as_ref(), &seed.to_le_bytes()], program_id)
+}
+
+/// Derives the address of the global config account holding the protocol fee settings
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Recreates the address of the config account from the bump seed stored in it, much cheaper than finding it again
+pub fn create_config_address(program_id: &Pubkey, bump_seed: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[CONFIG_SEED, &[bump_seed]], program_id)
+}
+
+/// Derives the PDA `maker` approves as delegate on the token accounts their signed orders send from
+pub fn find_order_authority(program_id: &Pubkey, maker: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ORDER_AUTHORITY_SEED, maker.as_ref()], program_id)
+}
+
+/// Recreates the order authority of `maker` from the bump seed the fill carries, much cheaper than finding it again
+pub fn create_order_authority(program_id: &Pubkey, maker: &Pubkey, bump_seed: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[ORDER_AUTHORITY_SEED, maker.as_ref(), &[bump_seed]], program_id)
+}
+
+/// Derives the account holding the used nonces of `maker` on nonce account `page`, see `NonceBitmap::page`
+pub fn find_order_nonces_address(program_id: &Pubkey, maker: &Pubkey, page: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[NONCES_SEED, maker.as_ref(), &page.to_le_bytes()], program_id)
+}
+
+/// Recreates the address of a nonce account from the bump seed stored in it, much cheaper than finding it again
+pub fn create_order_nonces_address(
+    program_id: &Pubkey,
+    maker: &Pubkey,
+    page: u64,
+    bump_seed: u8,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[NONCES_SEED, maker.as_ref(), &page.to_le_bytes(), &[bump_seed]], program_id)
+}
@@ -0,0 +1,131 @@
+use solana_program::{
+    account_info::AccountInfo,
+    msg,
+    program_error::ProgramError,
+    pubkey,
+    pubkey::{Pubkey, PubkeyError, PUBKEY_BYTES},
+};
+
+use crate::error::EscrowError;
+
+/// The Metaplex Token Metadata program
+pub const METADATA_PROGRAM_ID: Pubkey = pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bfG5x8qs");
+
+/// Seed prefix of the Metaplex metadata accounts
+pub const METADATA_SEED: &[u8] = b"metadata";
+
+/// Account key Metaplex writes first in a `MetadataV1` account
+const KEY_METADATA_V1: u8 = 4;
+
+/// Derives the Metaplex metadata account of `mint`
+pub fn find_metadata_address(mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[METADATA_SEED, METADATA_PROGRAM_ID.as_ref(), mint.as_ref()], &METADATA_PROGRAM_ID)
+}
+
+/// Recreates the metadata account of `mint` from its bump seed, much cheaper than finding it again
+pub fn create_metadata_address(mint: &Pubkey, bump_seed: u8) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(
+        &[METADATA_SEED, METADATA_PROGRAM_ID.as_ref(), mint.as_ref(), &[bump_seed]],
+        &METADATA_PROGRAM_ID,
+    )
+}
+
+/// A creator listed in the metadata
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Creator {
+    pub address: Pubkey,
+    /// Is `true` if the creator signed the metadata
+    pub verified: bool,
+    /// Percentage of the royalties going to this creator
+    pub share: u8,
+}
+
+/// The part of a Metaplex metadata account the escrow reads, the fields before it are skipped
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub mint: Pubkey,
+    /// Royalty on every sale, in basis points
+    pub seller_fee_basis_points: u16,
+    pub creators: Vec<Creator>,
+}
+
+impl Metadata {
+    /// Reads the borsh encoded `MetadataV1` layout up to the creators
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let (&key, rest) = src.split_first().ok_or(ProgramError::InvalidAccountData)?;
+        if key != KEY_METADATA_V1 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (_update_authority, rest) = take(rest, PUBKEY_BYTES)?;
+        let (mint, rest) = take(rest, PUBKEY_BYTES)?;
+        let mut rest = rest;
+        for _ in 0..3 {
+            // name, symbol and uri
+            let (len, next) = take_u32(rest)?;
+            let (_string, next) = take(next, len as usize)?;
+            rest = next;
+        }
+        let (seller_fee_basis_points, rest) = take(rest, 2)?;
+        let (&has_creators, mut rest) = rest.split_first().ok_or(ProgramError::InvalidAccountData)?;
+
+        let mut creators = Vec::new();
+        if has_creators == 1 {
+            let (count, next) = take_u32(rest)?;
+            rest = next;
+            for _ in 0..count {
+                let (creator, next) = take(rest, PUBKEY_BYTES + 2)?;
+                creators.push(Creator {
+                    address: Pubkey::try_from(&creator[..PUBKEY_BYTES]).map_err(|_| ProgramError::InvalidAccountData)?,
+                    verified: creator[PUBKEY_BYTES] == 1,
+                    share: creator[PUBKEY_BYTES + 1],
+                });
+                rest = next;
+            }
+        }
+
+        Ok(Metadata {
+            mint: Pubkey::try_from(mint).map_err(|_| ProgramError::InvalidAccountData)?,
+            seller_fee_basis_points: u16::from_le_bytes([seller_fee_basis_points[0], seller_fee_basis_points[1]]),
+            creators,
+        })
+    }
+
+    /// Reads the metadata account of `mint`, checking that it is the Metaplex one with the caller's `bump_seed`
+    pub fn from_account_info(metadata_account: &AccountInfo, mint: &Pubkey, bump_seed: u8) -> Result<Self, ProgramError> {
+        if *metadata_account.owner != METADATA_PROGRAM_ID
+            || *metadata_account.key != create_metadata_address(mint, bump_seed)?
+        {
+            msg!("metadata account {} is not the Metaplex metadata of {} !", metadata_account.key, mint);
+            return Err(EscrowError::InvalidMetadataAccount.into());
+        }
+        let metadata = Self::unpack(&metadata_account.try_borrow_data()?)?;
+        if metadata.mint != *mint {
+            return Err(EscrowError::InvalidMetadataAccount.into());
+        }
+        Ok(metadata)
+    }
+
+    /// The verified creators with a share
+    pub fn paid_creators(&self) -> impl Iterator<Item = &Creator> {
+        self.creators.iter().filter(|creator| creator.verified && creator.share > 0)
+    }
+
+    /// The royalty each verified creator gets from a sale at `price`, rounded down
+    pub fn royalties(&self, price: u64) -> impl Iterator<Item = (Pubkey, u64)> + '_ {
+        let royalty = price as u128 * self.seller_fee_basis_points as u128 / 10_000;
+        self.paid_creators()
+            .map(move |creator| (creator.address, (royalty * creator.share as u128 / 100) as u64))
+    }
+}
+
+fn take(input: &[u8], len: usize) -> Result<(&[u8], &[u8]), ProgramError> {
+    if input.len() < len {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(input.split_at(len))
+}
+
+fn take_u32(input: &[u8]) -> Result<(u32, &[u8]), ProgramError> {
+    let (value, rest) = take(input, 4)?;
+    Ok((u32::from_le_bytes([value[0], value[1], value[2], value[3]]), rest))
+}
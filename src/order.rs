@@ -0,0 +1,410 @@
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    account_info::AccountInfo,
+    ed25519_program,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::{Pack, Sealed},
+    pubkey::{Pubkey, PUBKEY_BYTES},
+    sysvar::instructions::{load_current_index_checked, load_instruction_at_checked},
+};
+
+use crate::error::EscrowError;
+
+/// Prefix of every signed order message, so a maker's signature over an order means nothing anywhere else
+pub const ORDER_MESSAGE_PREFIX: &[u8] = b"solana-escrow order";
+
+/// Number of nonces tracked by one nonce account, see `find_order_nonces_address`
+pub const NONCES_PER_ACCOUNT: u64 = 8 * NonceBitmap::BITS_LEN as u64;
+
+/// Size of the offsets of one signature in an Ed25519 program instruction
+const ED25519_OFFSETS_LEN: usize = 14;
+
+/// Offsets index telling the Ed25519 program that the data is in its own instruction
+const ED25519_CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+/// An offer signed off chain by its maker and filled by a taker with `FillSignedOrder`.
+/// The maker's X tokens are pulled through a delegate approval to `find_order_authority(program_id, maker)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Order {
+    /// Signs the order and sends the X tokens
+    pub maker: Pubkey,
+    /// The only account allowed to fill the order, `Pubkey::default()` lets anyone fill it
+    pub taker: Pubkey,
+    /// Used once, a filled or cancelled nonce cannot be filled again
+    pub nonce: u64,
+    /// Unix timestamp after which the order can no longer be filled, 0 if it never expires
+    pub expires_at: i64,
+    /// The mint of the tokens the maker sends
+    pub mint_x: Pubkey,
+    /// The amount of tokens the maker sends
+    pub amount_x: u64,
+    /// The mint of the tokens the taker sends, ignored when `amount_y` is 0
+    pub mint_y: Pubkey,
+    /// The amount of tokens the taker sends, 0 for none
+    pub amount_y: u64,
+    /// The lamports the taker sends to the maker
+    pub lamports: u64,
+}
+
+impl Order {
+    /// Is `true` if any signer may fill the order
+    pub fn is_open(&self) -> bool {
+        self.taker == Pubkey::default()
+    }
+
+    /// Is `true` if the order has an expiry and `now` is past it
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && now >= self.expires_at
+    }
+
+    /// The message the maker signs, `ORDER_MESSAGE_PREFIX` followed by the id of the program filling the order and
+    /// the packed order, so the order cannot be replayed on another deployment
+    pub fn message(&self, program_id: &Pubkey) -> Vec<u8> {
+        let mut message = vec![0; ORDER_MESSAGE_PREFIX.len() + PUBKEY_BYTES + Self::LEN];
+        let (prefix, rest) = message.split_at_mut(ORDER_MESSAGE_PREFIX.len());
+        let (program_id_dst, order) = rest.split_at_mut(PUBKEY_BYTES);
+        prefix.copy_from_slice(ORDER_MESSAGE_PREFIX);
+        program_id_dst.copy_from_slice(program_id.as_ref());
+        self.pack_into_slice(order);
+        message
+    }
+
+    /// Checks that the instruction before the current one is an Ed25519 program instruction verifying the maker's
+    /// signature over this order for `program_id`. The Ed25519 program fails the transaction if the signature itself
+    /// is wrong.
+    pub fn check_signature(&self, instructions_sysvar: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_sysvar)?;
+        if current_index == 0 {
+            msg!("no Ed25519 instruction before the fill !");
+            return Err(EscrowError::InvalidOrderSignature.into());
+        }
+        let ed25519_ix = load_instruction_at_checked(current_index as usize - 1, instructions_sysvar)?;
+        if ed25519_ix.program_id != ed25519_program::id() {
+            msg!("instruction before the fill is not an Ed25519 instruction !");
+            return Err(EscrowError::InvalidOrderSignature.into());
+        }
+
+        let data = &ed25519_ix.data;
+        if data.len() < 2 + ED25519_OFFSETS_LEN || data[0] != 1 {
+            msg!("Ed25519 instruction must verify a single signature !");
+            return Err(EscrowError::InvalidOrderSignature.into());
+        }
+        let offsets = array_ref![data, 2, ED25519_OFFSETS_LEN];
+        let (
+            _signature_offset,
+            signature_instruction_index,
+            public_key_offset,
+            public_key_instruction_index,
+            message_offset,
+            message_size,
+            message_instruction_index,
+        ) = array_refs![offsets, 2, 2, 2, 2, 2, 2, 2];
+        if [signature_instruction_index, public_key_instruction_index, message_instruction_index]
+            .iter()
+            .any(|index| u16::from_le_bytes(**index) != ED25519_CURRENT_INSTRUCTION)
+        {
+            msg!("Ed25519 instruction must hold its own signature, key and message !");
+            return Err(EscrowError::InvalidOrderSignature.into());
+        }
+
+        let public_key_offset = u16::from_le_bytes(*public_key_offset) as usize;
+        let message_offset = u16::from_le_bytes(*message_offset) as usize;
+        let message_size = u16::from_le_bytes(*message_size) as usize;
+        let public_key = data.get(public_key_offset..public_key_offset + PUBKEY_BYTES);
+        let message = data.get(message_offset..message_offset + message_size);
+        if public_key != Some(self.maker.as_ref()) || message != Some(&self.message(program_id)[..]) {
+            msg!("Ed25519 instruction does not verify this order signed by {} !", self.maker);
+            return Err(EscrowError::InvalidOrderSignature.into());
+        }
+        Ok(())
+    }
+}
+
+impl Sealed for Order {}
+
+impl Pack for Order {
+    const LEN: usize = 168;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, Order::LEN];
+        let (maker, taker, nonce, expires_at, mint_x, amount_x, mint_y, amount_y, lamports) =
+            array_refs![src, PUBKEY_BYTES, PUBKEY_BYTES, 8, 8, PUBKEY_BYTES, 8, PUBKEY_BYTES, 8, 8];
+        Ok(Order {
+            maker: Pubkey::new_from_array(*maker),
+            taker: Pubkey::new_from_array(*taker),
+            nonce: u64::from_le_bytes(*nonce),
+            expires_at: i64::from_le_bytes(*expires_at),
+            mint_x: Pubkey::new_from_array(*mint_x),
+            amount_x: u64::from_le_bytes(*amount_x),
+            mint_y: Pubkey::new_from_array(*mint_y),
+            amount_y: u64::from_le_bytes(*amount_y),
+            lamports: u64::from_le_bytes(*lamports),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Order::LEN];
+        let (
+            maker_dst,
+            taker_dst,
+            nonce_dst,
+            expires_at_dst,
+            mint_x_dst,
+            amount_x_dst,
+            mint_y_dst,
+            amount_y_dst,
+            lamports_dst,
+        ) = mut_array_refs![dst, PUBKEY_BYTES, PUBKEY_BYTES, 8, 8, PUBKEY_BYTES, 8, PUBKEY_BYTES, 8, 8];
+        maker_dst.copy_from_slice(self.maker.as_ref());
+        taker_dst.copy_from_slice(self.taker.as_ref());
+        *nonce_dst = self.nonce.to_le_bytes();
+        *expires_at_dst = self.expires_at.to_le_bytes();
+        mint_x_dst.copy_from_slice(self.mint_x.as_ref());
+        *amount_x_dst = self.amount_x.to_le_bytes();
+        mint_y_dst.copy_from_slice(self.mint_y.as_ref());
+        *amount_y_dst = self.amount_y.to_le_bytes();
+        *lamports_dst = self.lamports.to_le_bytes();
+    }
+}
+
+/// The used nonces of a maker, `NONCES_PER_ACCOUNT` per account, stored at `find_order_nonces_address` with its bump seed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NonceBitmap {
+    /// Bump seed of the account address, found once when the account is created
+    pub bump_seed: u8,
+    /// One bit per nonce, set once the order is filled or cancelled
+    pub bits: [u8; NonceBitmap::BITS_LEN],
+}
+
+impl NonceBitmap {
+    const BITS_LEN: usize = 256;
+
+    /// Index of the nonce account tracking `nonce`
+    pub fn page(nonce: u64) -> u64 {
+        nonce / NONCES_PER_ACCOUNT
+    }
+
+    /// Is `true` once `nonce` has been filled or cancelled
+    pub fn is_used(&self, nonce: u64) -> bool {
+        let bit = nonce % NONCES_PER_ACCOUNT;
+        self.bits[bit as usize / 8] & (1 << (bit % 8)) != 0
+    }
+
+    /// Marks `nonce` as filled or cancelled
+    pub fn set_used(&mut self, nonce: u64) {
+        let bit = nonce % NONCES_PER_ACCOUNT;
+        self.bits[bit as usize / 8] |= 1 << (bit % 8);
+    }
+}
+
+impl Default for NonceBitmap {
+    fn default() -> Self {
+        NonceBitmap { bump_seed: 0, bits: [0; NonceBitmap::BITS_LEN] }
+    }
+}
+
+impl Sealed for NonceBitmap {}
+
+impl Pack for NonceBitmap {
+    const LEN: usize = 1 + NonceBitmap::BITS_LEN;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, NonceBitmap::LEN];
+        let (bump_seed, bits) = array_refs![src, 1, NonceBitmap::BITS_LEN];
+        Ok(NonceBitmap { bump_seed: bump_seed[0], bits: *bits })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, NonceBitmap::LEN];
+        let (bump_seed_dst, bits_dst) = mut_array_refs![dst, 1, NonceBitmap::BITS_LEN];
+        bump_seed_dst[0] = self.bump_seed;
+        *bits_dst = self.bits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_program::{
+        instruction::Instruction,
+        sysvar::{
+            self,
+            instructions::{construct_instructions_data, store_current_index, BorrowedAccountMeta, BorrowedInstruction},
+        },
+    };
+
+    use super::*;
+    use crate::instruction::verify_order_signature;
+
+    fn order() -> Order {
+        Order {
+            maker: Pubkey::new_unique(),
+            taker: Pubkey::default(),
+            nonce: 3,
+            expires_at: 0,
+            mint_x: Pubkey::new_unique(),
+            amount_x: 1,
+            mint_y: Pubkey::new_unique(),
+            amount_y: 0,
+            lamports: 5_000,
+        }
+    }
+
+    /// Runs `check_signature` as if the instruction at `current_index` of `instructions` were the fill
+    fn check_signature(
+        order: &Order,
+        program_id: &Pubkey,
+        instructions: &[Instruction],
+        current_index: u16,
+    ) -> ProgramResult {
+        let borrowed: Vec<BorrowedInstruction> = instructions
+            .iter()
+            .map(|instruction| BorrowedInstruction {
+                program_id: &instruction.program_id,
+                accounts: instruction
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &instruction.data,
+            })
+            .collect();
+        let mut data = construct_instructions_data(&borrowed);
+        store_current_index(&mut data, current_index);
+        let key = sysvar::instructions::id();
+        let owner = sysvar::id();
+        let mut lamports = 0;
+        let instructions_sysvar = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+        order.check_signature(&instructions_sysvar, program_id)
+    }
+
+    fn fill(program_id: &Pubkey) -> Instruction {
+        Instruction { program_id: *program_id, accounts: vec![], data: vec![14] }
+    }
+
+    #[test]
+    fn message_binds_the_prefix_and_the_program_id() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+
+        let message = order.message(&program_id);
+
+        assert_eq!(message.len(), ORDER_MESSAGE_PREFIX.len() + PUBKEY_BYTES + Order::LEN);
+        assert!(message.starts_with(ORDER_MESSAGE_PREFIX));
+        assert_eq!(&message[ORDER_MESSAGE_PREFIX.len()..][..PUBKEY_BYTES], program_id.as_ref());
+        assert_eq!(Order::unpack_from_slice(&message[ORDER_MESSAGE_PREFIX.len() + PUBKEY_BYTES..]), Ok(order));
+        assert_ne!(message, order.message(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn check_signature_accepts_the_verify_instruction_right_before() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+        let verify = verify_order_signature(&program_id, &order, &[7; 64]);
+
+        assert_eq!(check_signature(&order, &program_id, &[verify, fill(&program_id)], 1), Ok(()));
+    }
+
+    #[test]
+    fn check_signature_rejects_an_order_signed_for_another_program() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+        let verify = verify_order_signature(&Pubkey::new_unique(), &order, &[7; 64]);
+
+        assert_eq!(
+            check_signature(&order, &program_id, &[verify, fill(&program_id)], 1),
+            Err(EscrowError::InvalidOrderSignature.into())
+        );
+    }
+
+    #[test]
+    fn check_signature_rejects_another_maker_or_other_terms() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+        let other_maker = Order { maker: Pubkey::new_unique(), ..order };
+        let other_terms = Order { amount_x: 2, ..order };
+
+        for signed in [other_maker, other_terms] {
+            let verify = verify_order_signature(&program_id, &signed, &[7; 64]);
+            assert_eq!(
+                check_signature(&order, &program_id, &[verify, fill(&program_id)], 1),
+                Err(EscrowError::InvalidOrderSignature.into())
+            );
+        }
+    }
+
+    #[test]
+    fn check_signature_rejects_a_fill_without_a_verify_instruction_before_it() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+        let verify = verify_order_signature(&program_id, &order, &[7; 64]);
+        let not_ed25519 = Instruction { program_id: Pubkey::new_unique(), ..verify.clone() };
+
+        assert_eq!(
+            check_signature(&order, &program_id, &[fill(&program_id), verify], 0),
+            Err(EscrowError::InvalidOrderSignature.into())
+        );
+        assert_eq!(
+            check_signature(&order, &program_id, &[not_ed25519, fill(&program_id)], 1),
+            Err(EscrowError::InvalidOrderSignature.into())
+        );
+    }
+
+    #[test]
+    fn check_signature_rejects_unexpected_ed25519_layouts() {
+        let order = order();
+        let program_id = Pubkey::new_unique();
+        for (offset, value) in [(0, 2), (4, 0), (8, 0), (14, 0)] {
+            let mut verify = verify_order_signature(&program_id, &order, &[7; 64]);
+            verify.data[offset] = value;
+            verify.data[offset + 1] = 0;
+
+            assert_eq!(
+                check_signature(&order, &program_id, &[verify, fill(&program_id)], 1),
+                Err(EscrowError::InvalidOrderSignature.into()),
+                "byte {} set to {}",
+                offset,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn nonce_bitmap_tracks_each_nonce_of_its_page() {
+        let mut bitmap = NonceBitmap::default();
+        bitmap.set_used(9);
+        bitmap.set_used(NONCES_PER_ACCOUNT - 1);
+
+        assert!(bitmap.is_used(9));
+        assert!(!bitmap.is_used(8));
+        assert!(!bitmap.is_used(10));
+        assert!(bitmap.is_used(NONCES_PER_ACCOUNT - 1));
+        assert_eq!(bitmap.bits[1], 1 << 1);
+        assert_eq!(bitmap.bits[NonceBitmap::BITS_LEN - 1], 1 << 7);
+    }
+
+    #[test]
+    fn nonce_bitmap_pages_split_nonces_by_account() {
+        assert_eq!(NonceBitmap::page(0), 0);
+        assert_eq!(NonceBitmap::page(NONCES_PER_ACCOUNT - 1), 0);
+        assert_eq!(NonceBitmap::page(NONCES_PER_ACCOUNT), 1);
+        assert_eq!(NonceBitmap::page(u64::MAX), u64::MAX / NONCES_PER_ACCOUNT);
+    }
+
+    #[test]
+    fn nonce_bitmap_pack_round_trips_with_its_bump_seed() {
+        let mut bitmap = NonceBitmap { bump_seed: 253, ..NonceBitmap::default() };
+        bitmap.set_used(0);
+        bitmap.set_used(1_000);
+        let mut data = vec![0; NonceBitmap::LEN];
+
+        NonceBitmap::pack(bitmap, &mut data).unwrap();
+
+        assert_eq!(NonceBitmap::unpack_unchecked(&data), Ok(bitmap));
+    }
+}
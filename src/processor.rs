@@ -1,16 +1,80 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
+    hash::hash,
+    log::sol_log_data,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
-    system_instruction,
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
+    system_instruction, system_program,
 };
 
-use crate::{error::EscrowError, instruction::EscrowInstruction};
-use arrayref::{array_mut_ref, array_ref};
+use spl_associated_token_account::{
+    get_associated_token_address_with_program_id, instruction::create_associated_token_account_idempotent,
+};
+use spl_token_2022::{
+    extension::{
+        transfer_fee::{instruction::harvest_withheld_tokens_to_mint, TransferFeeAmount, TransferFeeConfig},
+        BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+    },
+    state::{Account as TokenAccount, Mint},
+};
+
+use crate::{
+    create_config_address, create_escrow_authority,
+    error::EscrowError,
+    find_config_address, find_escrow_address, find_escrow_authority, find_vault_address,
+    instruction::{EscrowInstruction, FILL_DENOMINATOR},
+    metadata::Metadata,
+    order::{NonceBitmap, Order},
+    state::{Config, EscrowState, XLeg, YLeg, MAX_FEE_BPS, MAX_FEE_EXEMPT, MAX_LEGS, MAX_MILESTONES, MILESTONE_DENOMINATOR},
+    validation::{
+        check_associated_token_program, check_delegation, check_escrow_owner, check_leg_token_program,
+        check_mint_extensions, check_not_delegated, check_system_program, check_token_account,
+        check_upgrade_authority, check_writable,
+    },
+    create_order_authority, create_order_nonces_address, find_order_nonces_address, AUTHORITY_SEED, CONFIG_SEED, ESCROW_SEED, NONCES_SEED,
+    ORDER_AUTHORITY_SEED, VAULT_SEED,
+};
+
+/// Escrow terms set by `InitEscrow` and resent by the `Exchange` and `CancelEscrow` instructions
+struct Terms {
+    lamports: u64,
+    sol_dir: u8,
+    amount_x: u8,
+    amount_y: u8,
+    lamports_x: [u64; MAX_LEGS],
+    lamports_y: [u64; MAX_LEGS],
+}
+
+/// New terms sent by `UpdateEscrow`
+struct Amendment {
+    lamports: u64,
+    amount_x: u8,
+    top_up_x: [u64; MAX_LEGS],
+    amount_y: u8,
+    lamports_y: [u64; MAX_LEGS],
+    expires_at: Option<i64>,
+}
+
+/// The accounts of an X leg, checked against the escrow state
+struct XLegAccounts<'a, 'b> {
+    temp_token_account: &'a AccountInfo<'b>,
+    mint: &'a AccountInfo<'b>,
+    token_program: &'a AccountInfo<'b>,
+}
+
+/// The accounts of a Y leg, the mint and token program checked against the escrow state
+struct YLegAccounts<'a, 'b> {
+    initializer_token_account: &'a AccountInfo<'b>,
+    taker_token_account: &'a AccountInfo<'b>,
+    mint: &'a AccountInfo<'b>,
+    token_program: &'a AccountInfo<'b>,
+}
 
 pub struct Processor;
 impl Processor {
@@ -24,31 +88,174 @@ impl Processor {
 
         msg!("Instruction -> Init");
         match instruction {
-            EscrowInstruction::InitEscrow { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } => {
+            EscrowInstruction::InitEscrow {
+                lamports,
+                sol_dir,
+                amount_x,
+                amount_y,
+                lamports_x,
+                lamports_y,
+                seed,
+                rent_destination,
+                expires_at,
+                enforce_royalties,
+                arbiter,
+                milestones,
+                hashlock,
+                non_custodial,
+            } => {
                 msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y, program_id)
+                let terms = Terms { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y };
+                Self::process_init_escrow(
+                    accounts,
+                    &terms,
+                    seed,
+                    rent_destination,
+                    expires_at,
+                    enforce_royalties,
+                    arbiter,
+                    &milestones,
+                    hashlock,
+                    non_custodial,
+                    program_id,
+                )
             }
-            EscrowInstruction::Exchange { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } => {
+            EscrowInstruction::Exchange {
+                lamports,
+                sol_dir,
+                amount_x,
+                amount_y,
+                lamports_x,
+                lamports_y,
+                metadata_bump_seeds,
+            } => {
                 msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y, program_id)
+                let terms = Terms { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y };
+                Self::process_exchange(accounts, Some(&terms), FILL_DENOMINATOR, None, &metadata_bump_seeds, program_id)
             }
             EscrowInstruction::CancelEscrow { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } => {
                 msg!("Instruction: CancelEscrow");
-                Self::process_cancel_escrow(accounts, lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y, program_id)
+                let terms = Terms { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y };
+                Self::process_cancel_escrow(accounts, Some(&terms), false, program_id)
+            }
+            EscrowInstruction::ExchangeStored { expected_version, metadata_bump_seeds } => {
+                msg!("Instruction: ExchangeStored");
+                Self::process_exchange(
+                    accounts,
+                    None,
+                    FILL_DENOMINATOR,
+                    Some(expected_version),
+                    &metadata_bump_seeds,
+                    program_id,
+                )
+            }
+            EscrowInstruction::ExchangePartial { fill_numerator, expected_version, metadata_bump_seeds } => {
+                msg!("Instruction: ExchangePartial");
+                Self::process_exchange(
+                    accounts,
+                    None,
+                    fill_numerator,
+                    Some(expected_version),
+                    &metadata_bump_seeds,
+                    program_id,
+                )
+            }
+            EscrowInstruction::UpdateEscrow { lamports, amount_x, top_up_x, amount_y, lamports_y, expires_at } => {
+                msg!("Instruction: UpdateEscrow");
+                let amendment = Amendment { lamports, amount_x, top_up_x, amount_y, lamports_y, expires_at };
+                Self::process_update_escrow(accounts, &amendment, program_id)
+            }
+            EscrowInstruction::InitConfig { fee_bps, treasury } => {
+                msg!("Instruction: InitConfig");
+                Self::process_init_config(accounts, fee_bps, treasury, program_id)
+            }
+            EscrowInstruction::UpdateConfig { admin, fee_bps, treasury, fee_exempt } => {
+                msg!("Instruction: UpdateConfig");
+                Self::process_update_config(accounts, admin, fee_bps, treasury, &fee_exempt, program_id)
+            }
+            EscrowInstruction::CancelStored => {
+                msg!("Instruction: CancelStored");
+                Self::process_cancel_escrow(accounts, None, false, program_id)
+            }
+            EscrowInstruction::ReclaimExpired => {
+                msg!("Instruction: ReclaimExpired");
+                Self::process_cancel_escrow(accounts, None, true, program_id)
+            }
+            EscrowInstruction::Dispute { evidence_hash } => {
+                msg!("Instruction: Dispute");
+                Self::process_dispute(accounts, evidence_hash, program_id)
+            }
+            EscrowInstruction::Resolve { taker_lamports, amount_x, x_to_taker } => {
+                msg!("Instruction: Resolve");
+                Self::process_resolve(accounts, taker_lamports, amount_x, &x_to_taker, program_id)
+            }
+            EscrowInstruction::ReleaseMilestone { milestone } => {
+                msg!("Instruction: ReleaseMilestone");
+                Self::process_release_milestone(accounts, milestone, program_id)
+            }
+            EscrowInstruction::ClaimWithSecret { secret } => {
+                msg!("Instruction: ClaimWithSecret");
+                Self::process_claim_with_secret(accounts, &secret, program_id)
+            }
+            EscrowInstruction::FillSignedOrder { order, authority_bump_seed } => {
+                msg!("Instruction: FillSignedOrder");
+                Self::process_fill_signed_order(accounts, &order, authority_bump_seed, program_id)
+            }
+            EscrowInstruction::CancelOrder { nonce } => {
+                msg!("Instruction: CancelOrder");
+                Self::process_cancel_order(accounts, nonce, program_id)
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_init_escrow(
         accounts: &[AccountInfo],
-        lamports: u64,
-        sol_dir: u8,
-        amount_x: u8,
-        amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9],
+        terms: &Terms,
+        seed: u64,
+        rent_destination: Option<Pubkey>,
+        expires_at: Option<i64>,
+        enforce_royalties: bool,
+        arbiter: Option<Pubkey>,
+        milestones: &[u16],
+        hashlock: Option<[u8; 32]>,
+        non_custodial: bool,
         program_id: &Pubkey,
     ) -> ProgramResult {
+        let Terms { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } = *terms;
+
+        let mut milestone_shares = [0; MAX_MILESTONES];
+        if !milestones.is_empty() {
+            let total = milestones.iter().try_fold(0u16, |total, &share| total.checked_add(share));
+            if milestones.len() > MAX_MILESTONES
+                || milestones.contains(&0)
+                || total != Some(MILESTONE_DENOMINATOR)
+                || amount_y > 0
+                || (sol_dir == 2 && lamports > 0)
+            {
+                msg!("milestones must sum to {} and only release the initializer's legs !", MILESTONE_DENOMINATOR);
+                return Err(EscrowError::InvalidMilestones.into());
+            }
+            milestone_shares[..milestones.len()].copy_from_slice(milestones);
+        }
+        if let Some(hashlock) = hashlock {
+            if hashlock == [0; 32]
+                || expires_at.is_none()
+                || !milestones.is_empty()
+                || amount_y > 0
+                || (sol_dir == 2 && lamports > 0)
+            {
+                msg!("hashlock needs an expiry and only locks the initializer's legs !");
+                return Err(EscrowError::InvalidHashlock.into());
+            }
+        }
+
+        if let Some(expires_at) = expires_at {
+            if expires_at <= Clock::get()?.unix_timestamp {
+                msg!("expires_at is not in the future !");
+                return Err(EscrowError::InvalidExpiry.into());
+            }
+        }
 
         let account_info_iter = &mut accounts.iter();
 
@@ -57,197 +264,211 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        check_writable(initializer)?;
+
         msg!("initializer Pubkey : {}", initializer.key);
 
         let taker_account = next_account_info(account_info_iter)?;
         msg!("Taker Pubkey : {}", taker_account.key);
-        
+        let open = *taker_account.key == Pubkey::default();
+        if open {
+            msg!("open escrow, any taker can fill it");
+        }
+        if non_custodial && !milestones.is_empty() {
+            msg!("milestones need the X legs in vaults !");
+            return Err(EscrowError::InvalidMilestones.into());
+        }
+        if non_custodial && hashlock.is_some() {
+            msg!("a hashlock needs the X legs in vaults !");
+            return Err(EscrowError::InvalidHashlock.into());
+        }
+        if open && hashlock.is_some() {
+            msg!("a hashlocked escrow needs a named taker !");
+            return Err(EscrowError::InvalidHashlock.into());
+        }
+        if non_custodial && arbiter.is_some() {
+            msg!("an arbiter needs the X legs in vaults !");
+            return Err(EscrowError::InvalidArbiter.into());
+        }
+        if let Some(arbiter) = arbiter {
+            if open || arbiter == Pubkey::default() || arbiter == *initializer.key || arbiter == *taker_account.key {
+                msg!("arbiter must be a third party of a trade with a named taker !");
+                return Err(EscrowError::InvalidArbiter.into());
+            }
+            msg!("arbiter Pubkey : {}", arbiter);
+        }
+
         let escrow_account = next_account_info(account_info_iter)?;
+        check_writable(escrow_account)?;
         msg!("Escrow account Pubkey : {}", escrow_account.key );
-
-        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
-
-        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
-            msg!("Rent error --------> ???");
-            return Err(EscrowError::NotRentExempt.into());
+        let (escrow_address, escrow_bump_seed) = find_escrow_address(program_id, initializer.key, seed);
+        if *escrow_account.key != escrow_address {
+            msg!("escrow account is not the address of this initializer and seed !");
+            return Err(EscrowError::InvalidEscrowAddress.into());
         }
 
-        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
-        let token_program = next_account_info(account_info_iter)?;
-        msg!("token_program : {}", token_program.key);
+        let rent_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_account)?;
 
-        let mut initializer_token_account;
-        let mut taker_token_account;
-        let mut temp_token_account;
+        let (pda, bump_seed) = find_escrow_authority(program_id, escrow_account.key);
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+
+        let mut escrow_info = EscrowState {
+            is_initialized: true,
+            amount_x,
+            amount_y,
+            sol_dir,
+            bump_seed,
+            lamports,
+            initializer_pubkey: *initializer.key,
+            taker_pubkey: *taker_account.key,
+            rent_destination: rent_destination.unwrap_or(*initializer.key),
+            expires_at: expires_at.unwrap_or(0),
+            enforce_royalties,
+            arbiter: arbiter.unwrap_or_default(),
+            milestone_count: milestones.len() as u8,
+            milestone_shares,
+            hashlock: hashlock.unwrap_or_default(),
+            non_custodial,
+            ..EscrowState::default()
+        };
+
+        let mut x_leg_accounts: Vec<(&AccountInfo, &AccountInfo, &AccountInfo, &AccountInfo, u8)> =
+            Vec::with_capacity(amount_x as usize);
+        for (i, leg) in escrow_info.x_legs[..amount_x as usize].iter_mut().enumerate() {
+            let initializer_token_account = next_account_info(account_info_iter)?;
+            check_writable(initializer_token_account)?;
+            msg!("initializer_token_account_x{} : {}", i, initializer_token_account.key);
+            let temp_token_account = if non_custodial {
+                initializer_token_account
+            } else {
+                next_account_info(account_info_iter)?
+            };
+            check_writable(temp_token_account)?;
+            msg!("temp_token_account_x{} : {}", i, temp_token_account.key);
+            let mint = next_account_info(account_info_iter)?;
+            msg!("mint_x{} : {}", i, mint.key);
+            let leg_token_program = next_account_info(account_info_iter)?;
+            check_leg_token_program(leg_token_program, mint)?;
+            check_mint_extensions(mint)?;
+            check_token_account(initializer_token_account, mint.key, initializer.key)?;
+
+            let vault_bump_seed = if non_custodial {
+                if x_leg_accounts.iter().any(|(account, ..)| account.key == initializer_token_account.key) {
+                    msg!("initializer token account{} already backs another x leg !", i);
+                    return Err(EscrowError::DuplicateTokenAccount.into());
+                }
+                check_not_delegated(initializer_token_account, &pda)?;
+                0
+            } else {
+                let (vault, vault_bump_seed) = find_vault_address(program_id, escrow_account.key, i as u8);
+                if *temp_token_account.key != vault {
+                    msg!("temp x token account{} is not the vault address !", i);
+                    return Err(EscrowError::InvalidVaultAddress.into());
+                }
+                vault_bump_seed
+            };
+
+            *leg = XLeg {
+                temp_token_account: *temp_token_account.key,
+                mint: *mint.key,
+                token_program: *leg_token_program.key,
+                amount: lamports_x[i],
+            };
+            msg!("lamports_x{} : {}", i, lamports_x[i]);
+            x_leg_accounts.push((initializer_token_account, temp_token_account, mint, leg_token_program, vault_bump_seed));
+        }
 
-        let mut transfer_initializer_to_temp_ix;
-        let mut owner_change_ix;
+        for (j, leg) in escrow_info.y_legs[..amount_y as usize].iter_mut().enumerate() {
+            let mint = next_account_info(account_info_iter)?;
+            msg!("mint_y{} : {}", j, mint.key);
+            let leg_token_program = next_account_info(account_info_iter)?;
+            check_leg_token_program(leg_token_program, mint)?;
+            check_mint_extensions(mint)?;
+
+            *leg = YLeg {
+                mint: *mint.key,
+                token_program: *leg_token_program.key,
+                amount: lamports_y[j],
+            };
+            msg!("lamports_y{} : {}", j, lamports_y[j]);
+        }
 
-        msg!("escrow_account : {}", escrow_account.key);
+        let pda_account = if non_custodial {
+            let pda_account = next_account_info(account_info_iter)?;
+            if *pda_account.key != pda {
+                msg!("pda account is not the escrow authority !");
+                return Err(EscrowError::InvalidAuthority.into());
+            }
+            Some(pda_account)
+        } else {
+            None
+        };
+
+        if escrow_account.owner == program_id {
+            let existing_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+            if existing_info == escrow_info {
+                msg!("escrow_account already initialized with these terms, nothing to do");
+                return Ok(());
+            }
+            msg!("escrow_account data exists already!!!");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
 
-        let mut escrow_data_pos = 0;
+        let escrow_len = EscrowState::packed_len(amount_x, amount_y);
+        Self::create_pda_account(
+            initializer,
+            escrow_account,
+            system_program_account,
+            rent.minimum_balance(escrow_len),
+            escrow_len,
+            program_id,
+            &[ESCROW_SEED, initializer.key.as_ref(), &seed.to_le_bytes(), &[escrow_bump_seed]],
+        )?;
+        msg!("escrow_account -> OK");
+
+        for (i, (initializer_token_account, temp_token_account, mint, leg_token_program, vault_bump_seed)) in
+            x_leg_accounts.into_iter().enumerate()
         {
-            let escrow_mut_data = &mut escrow_account.try_borrow_mut_data()?;
-
-            if escrow_mut_data[0] != 0 {
-                msg!("escrow_account data exists already!!!");
-                return Err(ProgramError::AccountAlreadyInitialized);
-            }
-            msg!("escrow_account -> OK");
-
-            escrow_mut_data[escrow_data_pos] = 1;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = amount_x as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = amount_y as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = sol_dir as u8;
-            escrow_data_pos += 1;
-
-            escrow_mut_data[escrow_data_pos] = (lamports >> 56) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 48) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 40) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 32) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 24) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 16) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = (lamports >> 8) as u8;
-            escrow_data_pos += 1;
-            escrow_mut_data[escrow_data_pos] = lamports as u8;
-            escrow_data_pos += 1;
-
-            array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(initializer.key.as_ref());
-            escrow_data_pos += 32;
-            msg!("Initializer_account -> OK");
-
-            array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(taker_account.key.as_ref());
-            escrow_data_pos += 32;
-
-            msg!("taker_account -> OK");
-        }
-
-            for i in 0..amount_x {
-                let i = i as usize;
-                {
-                    let escrow_mut_data = &mut escrow_account.try_borrow_mut_data()?;
-
-                    initializer_token_account = next_account_info(account_info_iter)?;
-                    array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(initializer_token_account.key.as_ref());
-                    escrow_data_pos += 32;
-                    msg!("initializer_token_account_x{} : {}", i, initializer_token_account.key);
-
-                    taker_token_account = next_account_info(account_info_iter)?;
-                    array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(taker_token_account.key.as_ref());
-                    escrow_data_pos += 32;
-                    msg!("taker_token_account_x{} : {}", i, taker_token_account.key);
-
-                    temp_token_account = next_account_info(account_info_iter)?;
-                    array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(temp_token_account.key.as_ref());
-                    escrow_data_pos += 32;
-                    msg!("temp_token_account_x{} : {}", i, temp_token_account.key);
-
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 56) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 48) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 40) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 32) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 24) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 16) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_x[i] >> 8) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = lamports_x[i] as u8;
-                    escrow_data_pos += 1;
-                    msg!("lamports_x{} : {}", i, lamports_x[i]);
-                }
-    
-                transfer_initializer_to_temp_ix = spl_token::instruction::transfer(
-                    token_program.key,
-                    initializer_token_account.key,
-                    temp_token_account.key,
-                    initializer.key,
-                    &[&initializer.key],
+            if let Some(pda_account) = pda_account {
+                msg!("Calling the token program to approve the escrow authority on the initializer token account");
+                Self::approve_delegate(
+                    leg_token_program,
+                    initializer_token_account,
+                    mint,
+                    pda_account,
+                    initializer,
                     lamports_x[i],
                 )?;
-                msg!("Calling the token program to transfer initializer ---> temp token account");
-                invoke(
-                    &transfer_initializer_to_temp_ix,
-                    &[
-                        initializer.clone(),
-                        initializer_token_account.clone(),
-                        temp_token_account.clone(),
-                        token_program.clone(),
-                    ],
-                )?;
-
-                owner_change_ix = spl_token::instruction::set_authority(
-                    token_program.key,
-                    temp_token_account.key,
-                    Some(&pda),
-                    spl_token::instruction::AuthorityType::AccountOwner,
-                    escrow_account.key,
-                    &[&escrow_account.key],
-                )?;
-        
-                msg!("Calling the token program to transfer token account ownership...");
-                invoke(
-                    &owner_change_ix,
-                    &[
-                        token_program.clone(),
-                        temp_token_account.clone(),
-                        escrow_account.clone(),
-                    ],
-                )?;
-
+                check_delegation(initializer_token_account, &pda, lamports_x[i])?;
+                continue;
             }
+            Self::create_vault(
+                initializer,
+                temp_token_account,
+                mint,
+                rent_account,
+                leg_token_program,
+                system_program_account,
+                &pda,
+                &[VAULT_SEED, escrow_account.key.as_ref(), &[i as u8], &[vault_bump_seed]],
+            )?;
+            msg!("Calling the token program to transfer initializer ---> temp token account");
+            Self::transfer_tokens(
+                leg_token_program,
+                initializer_token_account,
+                mint,
+                temp_token_account,
+                initializer,
+                lamports_x[i],
+                true,
+                &[],
+            )?;
+        }
 
-            {
-                let escrow_mut_data = &mut escrow_account.try_borrow_mut_data()?;
-                for j in 0..amount_y {
-                    let j = j as usize;
-
-                    initializer_token_account = next_account_info(account_info_iter)?;
-                    array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(initializer_token_account.key.as_ref());
-                    escrow_data_pos += 32;
-                    msg!("initializer_token_account_y{} : {}", j, initializer_token_account.key);
-
-                    taker_token_account = next_account_info(account_info_iter)?;
-                    array_mut_ref![escrow_mut_data, escrow_data_pos, 32].copy_from_slice(taker_token_account.key.as_ref());
-                    escrow_data_pos += 32;
-                    msg!("taker_token_account{} : {}", j, taker_token_account.key);
-
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 56) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 48) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 40) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 32) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 24) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 16) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = (lamports_y[j] >> 8) as u8;
-                    escrow_data_pos += 1;
-                    escrow_mut_data[escrow_data_pos] = lamports_y[j] as u8;
-                    escrow_data_pos += 1;
-                    msg!("lamports_y{} : {}", j, lamports_y[j]);
-                }
-                msg!("Escrow Mut Data -> {:?}", escrow_mut_data);
-            }
-        // }
+        escrow_info.pack_account(&mut escrow_account.try_borrow_mut_data()?)?;
 
-        let system_program_account = next_account_info(account_info_iter)?;
         if (sol_dir == 1) && (lamports) > 0 {
             let sol_ix = system_instruction::transfer(
                 initializer.key,
@@ -269,28 +490,27 @@ impl Processor {
     //==========================================================================
     fn process_cancel_escrow(
         accounts: &[AccountInfo],
-        lamports: u64,
-        sol_dir: u8,
-        amount_x: u8,
-        amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9],
+        terms: Option<&Terms>,
+        reclaim: bool,
         program_id: &Pubkey,
     ) -> ProgramResult {
 
         let account_info_iter = &mut accounts.iter();
 
         let initializer = next_account_info(account_info_iter)?;
-        if !initializer.is_signer {
+        if !reclaim && !initializer.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
+        check_writable(initializer)?;
 
         msg!("initializer Pubkey : {}", initializer.key);
 
         let taker_account = next_account_info(account_info_iter)?;
         msg!("Taker Pubkey : {}", taker_account.key);
-        
+
         let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
         msg!("Escrow account Pubkey : {}", escrow_account.key );
 
         let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
@@ -301,262 +521,121 @@ impl Processor {
         }
         msg!("Rent OK -------------->");
 
-        let token_program = next_account_info(account_info_iter)?;
-        msg!("token_program : {}", token_program.key);
         let pda_account = next_account_info(account_info_iter)?;
-        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
-
-        {
-            let escrow_data = &escrow_account.try_borrow_data()?;
-            let mut escrow_data_pos = 0;
-
-            if escrow_data[escrow_data_pos] == 0 {
-                msg!("escrow_account data does not exist !!!");
-                return Err(ProgramError::AccountAlreadyInitialized);
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != amount_x as u8 {
-                msg!("amount_x is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != amount_y as u8 {
-                msg!("amount_y is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != sol_dir as u8 {
-                msg!("sol_dir is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            let mut temp_lamports = escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-
-            msg!("Lamports --> {}, temp_lamports --> {}", lamports, temp_lamports);
-
-            if lamports != temp_lamports {
-                msg!("lamports is not the same !");
-                return Err(EscrowError::InvalidAccount.into());
-            }
+        let rent_destination_account = next_account_info(account_info_iter)?;
+        check_writable(rent_destination_account)?;
 
-            msg!("Lamports OK -------------->");
-
-            let mut initializer_token_account;
-            let mut taker_token_account;
-            let mut temp_token_account;
-
-            let mut transfer_temp_to_initializer_ix;
-            let mut close_escrow_temp_acc_ix;
-
-            if initializer.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                msg!("initializer pubkey is not the same !");
-                return Err(EscrowError::InvalidAccount.into());
-            }
-            escrow_data_pos += 32;
-            msg!("Initializer Account OK -------------->");
-
-            if taker_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                msg!("taker pubkey is not the same !");
-                return Err(EscrowError::InvalidAccount.into());
-            }
-            escrow_data_pos += 32;
-            msg!("Taker Account OK -------------->");
-
-            for i in 0..amount_x {
-                let i = i as usize;
-
-                initializer_token_account = next_account_info(account_info_iter)?;
-                if initializer_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("initializer x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("initializer x token account pubkey{} is okay !", i);
-
-                taker_token_account = next_account_info(account_info_iter)?;
-                if taker_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("taker x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("taker x token account pubkey{} is okay !", i);
-
-                temp_token_account = next_account_info(account_info_iter)?;
-                if temp_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("temp x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("temp x token account pubkey{} is okay !", i);
-                 
-                temp_lamports = escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                msg!("Lamports_x --> {}, temp_lamport --> {}", lamports_x[i], temp_lamports);
-
-                if lamports_x[i] != temp_lamports {
-                    msg!("lamports_x is not the same !");
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                msg!("Lamports_x{} OK -------------->", i);
-    
-                transfer_temp_to_initializer_ix = spl_token::instruction::transfer(
-                    token_program.key,
-                    temp_token_account.key,
-                    initializer_token_account.key,
-                    &pda,
-                    &[&pda],
-                    lamports_x[i],
-                )?;
-                msg!("Calling the token program to transfer tokens to the Initializer token account...");
-                invoke_signed(
-                    &transfer_temp_to_initializer_ix,
-                    &[
-                        pda_account.clone(),
-                        token_program.clone(),
-                        temp_token_account.clone(),
-                        initializer_token_account.clone(),
-                    ],
-                    &[&[&b"escrow"[..], &[_nonce]]],
-                )?;
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        if reclaim && escrow_info.non_custodial && !initializer.is_signer {
+            msg!("the initializer reclaims a non-custodial escrow, revoking its approvals !");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+        if *pda_account.key != pda {
+            msg!("pda account is not the escrow authority !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+        if let Some(terms) = terms {
+            Self::check_terms(&escrow_info, terms)?;
+        }
+        Self::check_parties(&escrow_info, initializer, taker_account)?;
+        Self::check_not_disputed(&escrow_info)?;
+        if *rent_destination_account.key != escrow_info.rent_destination {
+            msg!("rent destination is not the one stored in the escrow !");
+            return Err(EscrowError::InvalidRentDestination.into());
+        }
+        if (reclaim || escrow_info.is_hashlocked()) && !escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            msg!("escrow has not expired yet !");
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
 
-                close_escrow_temp_acc_ix = spl_token::instruction::close_account(
-                    token_program.key,
-                    temp_token_account.key,
-                    initializer_token_account.key,
-                    &pda,
-                    &[&pda],
-                )?;
-                msg!("Calling the token program to close pda's temp account...");
-                invoke_signed(
-                    &close_escrow_temp_acc_ix,
-                    &[
-                        pda_account.clone(),
-                        token_program.clone(),
-                        temp_token_account.clone(),
-                        initializer_token_account.clone(),
-                    ],
-                    &[&[&b"escrow"[..], &[_nonce]]],
-                )?;
+        for (i, leg) in escrow_info.x_legs().iter().enumerate() {
+            let initializer_token_account = if escrow_info.non_custodial {
+                None
+            } else {
+                Some(next_account_info(account_info_iter)?)
+            };
+            let XLegAccounts { temp_token_account, mint, token_program } =
+                Self::next_x_leg_accounts(account_info_iter, leg, i)?;
+            check_writable(temp_token_account)?;
+            let Some(initializer_token_account) = initializer_token_account else {
+                Self::revoke_delegate(token_program, temp_token_account, initializer, pda_account, &[authority_seeds])?;
+                continue;
+            };
+            Self::check_temp_authority(temp_token_account, leg, &pda, i)?;
+            check_writable(initializer_token_account)?;
+            check_token_account(initializer_token_account, &leg.mint, &escrow_info.initializer_pubkey)?;
+
+            msg!("Calling the token program to transfer tokens to the Initializer token account...");
+            Self::transfer_tokens(
+                token_program,
+                temp_token_account,
+                mint,
+                initializer_token_account,
+                pda_account,
+                leg.amount,
+                false,
+                &[authority_seeds],
+            )?;
+            Self::close_leg(
+                &escrow_info,
+                token_program,
+                temp_token_account,
+                mint,
+                rent_destination_account,
+                pda_account,
+                &[authority_seeds],
+            )?;
+        }
 
+        if escrow_info.has_arbiter() && !reclaim {
+            let cosigner = next_account_info(account_info_iter)?;
+            if !cosigner.is_signer {
+                return Err(ProgramError::MissingRequiredSignature);
             }
-
-            //---- check taker account & lamports
-
-            // let escrow_mut_data = &mut escrow_account.try_borrow_mut_data()?;
-            for j in 0..amount_y {
-                let j = j as usize;
-
-                initializer_token_account = next_account_info(account_info_iter)?;
-                if initializer_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("initializer y token account pubkey{} is not the same !", j);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("initializer y token account pubkey{} is okay !", j);
-
-                taker_token_account = next_account_info(account_info_iter)?;
-                if taker_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("taker y token account pubkey{} is not the same !", j);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("taker y token account pubkey{} is okay !", j);
-
-                temp_lamports = escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                msg!("Lamports_y --> {}, temp_lamports --> {}", lamports_y[j], temp_lamports);
-
-                if lamports_y[j] != temp_lamports {
-                    msg!("lamports_y is not the same !");
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                msg!("Lamports_y{} OK -------------->", j);
+            let is_taker = escrow_info.taker_pubkey != Pubkey::default() && *cosigner.key == escrow_info.taker_pubkey;
+            if *cosigner.key != escrow_info.arbiter && !is_taker {
+                msg!("{} is neither the arbiter nor the taker of the escrow !", cosigner.key);
+                return Err(EscrowError::ArbiterApprovalRequired.into());
             }
-            // msg!("Escrow Mut Data -> {:?}", escrow_mut_data);
+            msg!("cancel approved by {}", cosigner.key);
         }
 
-        msg!("Closing the escrow account...");
-        **initializer.try_borrow_mut_lamports()? = initializer
-            .lamports()
-            .checked_add(escrow_account.lamports())
-            .ok_or(EscrowError::AmountOverflow)?;
-        **escrow_account.try_borrow_mut_lamports()? = 0;
-        *escrow_account.try_borrow_mut_data()? = &mut [];
+        if (escrow_info.sol_dir == 1) && (escrow_info.lamports) > 0 {
+            **escrow_account.try_borrow_mut_lamports()? -= escrow_info.lamports;
+            **initializer.try_borrow_mut_lamports()? += escrow_info.lamports;
+        }
 
-        Ok(())
+        Self::close_escrow_account(rent_destination_account, escrow_account)
     }
 
     //==========================================================================
     fn process_exchange(
         accounts: &[AccountInfo],
-        lamports: u64,
-        sol_dir: u8,
-        amount_x: u8,
-        amount_y: u8,
-        lamports_x: [u64; 9],
-        lamports_y: [u64; 9],
+        terms: Option<&Terms>,
+        fill_numerator: u64,
+        expected_version: Option<u64>,
+        metadata_bump_seeds: &[u8],
         program_id: &Pubkey,
     ) -> ProgramResult {
 
         let account_info_iter = &mut accounts.iter();
 
         let initializer = next_account_info(account_info_iter)?;
+        check_writable(initializer)?;
         msg!("initializer Pubkey : {}", initializer.key);
 
         let taker_account = next_account_info(account_info_iter)?;
         if !taker_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
+        check_writable(taker_account)?;
         msg!("Taker Pubkey : {}", taker_account.key);
-        
+
         let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
         msg!("Escrow account Pubkey : {}", escrow_account.key );
 
         let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
@@ -567,276 +646,1938 @@ impl Processor {
         }
         msg!("Rent OK -------------->");
 
-        let token_program = next_account_info(account_info_iter)?;
-        msg!("token_program : {}", token_program.key);
         let pda_account = next_account_info(account_info_iter)?;
-        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
-
-        {
-            let escrow_data = &escrow_account.try_borrow_data()?;
-            let mut escrow_data_pos = 0;
-
-            if escrow_data[escrow_data_pos] == 0 {
-                msg!("escrow_account data does not exist !!!");
-                return Err(ProgramError::AccountAlreadyInitialized);
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != amount_x as u8 {
-                msg!("amount_x is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != amount_y as u8 {
-                msg!("amount_y is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            if escrow_data[escrow_data_pos] != sol_dir as u8 {
-                msg!("sol_dir is not the same !");
-                return Err(EscrowError::InvalidAmount.into());
-            }
-            escrow_data_pos += 1;
-
-            let mut temp_lamports = escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-            temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-            escrow_data_pos += 1;
-
-            msg!("Lamports --> {}, temp_lamports --> {}", lamports, temp_lamports);
-
-            if lamports != temp_lamports {
-                msg!("lamports is not the same !");
-                return Err(EscrowError::InvalidAccount.into());
+        let rent_destination_account = next_account_info(account_info_iter)?;
+        check_writable(rent_destination_account)?;
+        let config_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        check_associated_token_program(associated_token_program)?;
+
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+        if *pda_account.key != pda {
+            msg!("pda account is not the escrow authority !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+        if let Some(terms) = terms {
+            Self::check_terms(&escrow_info, terms)?;
+        }
+        Self::check_parties(&escrow_info, initializer, taker_account)?;
+        Self::check_not_disputed(&escrow_info)?;
+        if escrow_info.is_milestone() {
+            msg!("escrow releases its legs by milestone !");
+            return Err(EscrowError::MilestoneEscrow.into());
+        }
+        if escrow_info.is_hashlocked() {
+            msg!("escrow is claimed with a secret !");
+            return Err(EscrowError::InvalidHashlock.into());
+        }
+        if *rent_destination_account.key != escrow_info.rent_destination {
+            msg!("rent destination is not the one stored in the escrow !");
+            return Err(EscrowError::InvalidRentDestination.into());
+        }
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            msg!("escrow has expired !");
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        if let Some(expected_version) = expected_version {
+            if escrow_info.version != expected_version {
+                msg!("escrow is at version {}, the taker expected {} !", escrow_info.version, expected_version);
+                return Err(EscrowError::StaleEscrowVersion.into());
+            }
+        }
+        let mut remaining_info = Self::fill_terms(&escrow_info, fill_numerator)?;
+        let exhausted = remaining_info.is_exhausted();
+        let partial = fill_numerator < FILL_DENOMINATOR;
+
+        let config = Self::fee_config(config_account, initializer, taker_account, program_id)?;
+        if let Some(config) = &config {
+            if *treasury_account.key != config.treasury {
+                msg!("treasury is not the one stored in the config !");
+                return Err(EscrowError::InvalidTreasury.into());
+            }
+            check_writable(treasury_account)?;
+        }
+        let fee = |amount: u64| config.as_ref().map_or(0, |config| config.fee(amount));
+        let x_fees: Vec<u64> = escrow_info
+            .x_legs()
+            .iter()
+            .zip(remaining_info.x_legs())
+            .map(|(leg, remaining_leg)| fee(leg.amount - remaining_leg.amount))
+            .collect();
+        let y_fees: Vec<u64> = escrow_info
+            .y_legs()
+            .iter()
+            .zip(remaining_info.y_legs())
+            .map(|(leg, remaining_leg)| fee(leg.amount - remaining_leg.amount))
+            .collect();
+        let mints = escrow_info
+            .x_legs()
+            .iter()
+            .map(|leg| leg.mint)
+            .chain(escrow_info.y_legs().iter().map(|leg| leg.mint));
+        let mut treasury_token_accounts = Vec::with_capacity(x_fees.len() + y_fees.len());
+        for (mint, &leg_fee) in mints.zip(x_fees.iter().chain(&y_fees)) {
+            if leg_fee == 0 {
+                treasury_token_accounts.push(None);
+                continue;
+            }
+            let treasury_token_account = next_account_info(account_info_iter)?;
+            check_writable(treasury_token_account)?;
+            check_token_account(treasury_token_account, &mint, treasury_account.key)?;
+            treasury_token_accounts.push(Some(treasury_token_account));
+        }
+        let (x_treasury_token_accounts, y_treasury_token_accounts) = treasury_token_accounts.split_at(x_fees.len());
+
+        let mut x_mints = Vec::with_capacity(escrow_info.x_legs().len());
+        for (i, (leg, remaining_leg)) in escrow_info.x_legs().iter().zip(remaining_info.x_legs()).enumerate() {
+            let taker_token_account = next_account_info(account_info_iter)?;
+            let XLegAccounts { temp_token_account, mint, token_program } =
+                Self::next_x_leg_accounts(account_info_iter, leg, i)?;
+            x_mints.push(mint);
+            if partial && Self::is_nft(mint)? {
+                msg!("x leg{} is an NFT, it cannot be filled partially !", i);
+                return Err(EscrowError::InvalidFill.into());
+            }
+            check_writable(taker_token_account)?;
+            check_writable(temp_token_account)?;
+            Self::create_associated_token_account(
+                taker_account,
+                taker_token_account,
+                taker_account,
+                mint,
+                token_program,
+                system_program_account,
+                associated_token_program,
+            )?;
+            check_token_account(taker_token_account, &leg.mint, taker_account.key)?;
+            Self::check_leg_custody(&escrow_info, temp_token_account, leg, &pda, leg.amount - remaining_leg.amount, i)?;
+
+            msg!("Calling the token program to exchange tokens ...");
+            Self::transfer_tokens(
+                token_program,
+                temp_token_account,
+                mint,
+                taker_token_account,
+                pda_account,
+                leg.amount - remaining_leg.amount - x_fees[i],
+                false,
+                &[authority_seeds],
+            )?;
+            if let Some(treasury_token_account) = x_treasury_token_accounts[i] {
+                msg!("Calling the token program to transfer the fee to the treasury...");
+                Self::transfer_tokens(
+                    token_program,
+                    temp_token_account,
+                    mint,
+                    treasury_token_account,
+                    pda_account,
+                    x_fees[i],
+                    false,
+                    &[authority_seeds],
+                )?;
             }
 
-            msg!("Lamports OK -------------->");
-
-            let mut initializer_token_account;
-            let mut taker_token_account;
-            let mut temp_token_account;
+            if !exhausted {
+                continue;
+            }
+            Self::close_leg(
+                &escrow_info,
+                token_program,
+                temp_token_account,
+                mint,
+                rent_destination_account,
+                pda_account,
+                &[authority_seeds],
+            )?;
+        }
 
-            let mut transfer_taker_to_initializer_ix;
-            let mut transfer_temp_to_taker_ix;
-            let mut close_escrow_temp_acc_ix;
+        let mut y_mints = Vec::with_capacity(escrow_info.y_legs().len());
+        for (j, (leg, remaining_leg)) in escrow_info.y_legs().iter().zip(remaining_info.y_legs()).enumerate() {
+            let YLegAccounts { initializer_token_account, taker_token_account, mint, token_program } =
+                Self::next_y_leg_accounts(account_info_iter, leg, j)?;
+            y_mints.push(mint);
+            if partial && Self::is_nft(mint)? {
+                msg!("y leg{} is an NFT, it cannot be filled partially !", j);
+                return Err(EscrowError::InvalidFill.into());
+            }
+            check_writable(initializer_token_account)?;
+            check_writable(taker_token_account)?;
+            Self::create_associated_token_account(
+                taker_account,
+                initializer_token_account,
+                initializer,
+                mint,
+                token_program,
+                system_program_account,
+                associated_token_program,
+            )?;
+            check_token_account(initializer_token_account, &leg.mint, &escrow_info.initializer_pubkey)?;
+            check_token_account(taker_token_account, &leg.mint, taker_account.key)?;
+
+            msg!("Calling the token program to transfer tokens to the Initializer token account...");
+            Self::transfer_tokens(
+                token_program,
+                taker_token_account,
+                mint,
+                initializer_token_account,
+                taker_account,
+                leg.amount - remaining_leg.amount - y_fees[j],
+                true,
+                &[],
+            )?;
+            if let Some(treasury_token_account) = y_treasury_token_accounts[j] {
+                msg!("Calling the token program to transfer the fee to the treasury...");
+                Self::transfer_tokens(
+                    token_program,
+                    taker_token_account,
+                    mint,
+                    treasury_token_account,
+                    taker_account,
+                    y_fees[j],
+                    true,
+                    &[],
+                )?;
+            }
+        }
 
-            if initializer.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                msg!("initializer pubkey is not the same !");
-                return Err(EscrowError::InvalidAccount.into());
+        let lamports = escrow_info.lamports - remaining_info.lamports;
+        let sol_fee = fee(lamports);
+        let royalties = Self::royalties(
+            account_info_iter,
+            &escrow_info,
+            &remaining_info,
+            &x_mints,
+            &y_mints,
+            lamports,
+            metadata_bump_seeds,
+        )?;
+        let royalty_total = royalties
+            .iter()
+            .try_fold(0u64, |total, (_, royalty)| total.checked_add(*royalty))
+            .ok_or(EscrowError::AmountOverflow)?;
+        let seller_lamports = lamports
+            .checked_sub(sol_fee)
+            .and_then(|rest| rest.checked_sub(royalty_total))
+            .ok_or(EscrowError::InvalidAmount)?;
+        if (escrow_info.sol_dir == 1) && (lamports) > 0 {
+            **escrow_account.try_borrow_mut_lamports()? -= lamports;
+            **taker_account.try_borrow_mut_lamports()? += seller_lamports;
+            if sol_fee > 0 {
+                **treasury_account.try_borrow_mut_lamports()? += sol_fee;
+            }
+            for (creator_account, royalty) in &royalties {
+                **creator_account.try_borrow_mut_lamports()? += royalty;
             }
-            escrow_data_pos += 32;
-            msg!("Initializer Account OK -------------->");
+        }
 
-            if taker_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                msg!("taker pubkey is not the same ! : {:?}", array_ref!(escrow_data, escrow_data_pos, 32));
-                return Err(EscrowError::InvalidAccount.into());
+        if (escrow_info.sol_dir == 2) && (lamports) > 0 {
+            if sol_fee > 0 {
+                invoke(
+                    &system_instruction::transfer(taker_account.key, treasury_account.key, sol_fee),
+                    &[taker_account.clone(), treasury_account.clone(), system_program_account.clone()],
+                )?;
             }
-            escrow_data_pos += 32;
-            msg!("Taker Account OK -------------->");
+            for (creator_account, royalty) in &royalties {
+                invoke(
+                    &system_instruction::transfer(taker_account.key, creator_account.key, *royalty),
+                    &[taker_account.clone(), (*creator_account).clone(), system_program_account.clone()],
+                )?;
+            }
+            let sol_ix = system_instruction::transfer(
+                taker_account.key,
+                initializer.key,
+                seller_lamports,
+            );
+            invoke(
+                &sol_ix,
+                &[
+                    taker_account.clone(),
+                    initializer.clone(),
+                    system_program_account.clone(),
+                ],
+            )?;
+        }
 
-            for i in 0..amount_x {
-                let i = i as usize;
+        if !exhausted {
+            remaining_info.bump_version()?;
+            msg!("escrow partially filled, remaining terms stored at version {}", remaining_info.version);
+            return remaining_info.pack_account(&mut escrow_account.try_borrow_mut_data()?);
+        }
+        Self::close_escrow_account(rent_destination_account, escrow_account)
+    }
 
-                initializer_token_account = next_account_info(account_info_iter)?;
-                if initializer_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("initializer x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("initializer x token account pubkey{} is okay !", i);
+    //==========================================================================
+    fn process_update_escrow(
+        accounts: &[AccountInfo],
+        amendment: &Amendment,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let Amendment { lamports, amount_x, top_up_x, amount_y, lamports_y, expires_at } = *amendment;
 
-                taker_token_account = next_account_info(account_info_iter)?;
-                if taker_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("taker x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("taker x token account pubkey{} is okay !", i);
+        let account_info_iter = &mut accounts.iter();
 
-                temp_token_account = next_account_info(account_info_iter)?;
-                if temp_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("temp x token account pubkey{} is not the same !", i);
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                escrow_data_pos += 32;
-                msg!("temp x token account pubkey{} is okay !", i);
-
-                temp_lamports = escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                msg!("Lamports_x --> {}, temp_lamport --> {}", lamports_x[i], temp_lamports);
-
-                if lamports_x[i] != temp_lamports {
-                    msg!("lamports_x is not the same !");
-                    return Err(EscrowError::InvalidAccount.into());
-                }
-                msg!("Lamports_x{} OK -------------->", i);
+        let initializer = next_account_info(account_info_iter)?;
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(initializer)?;
+        msg!("initializer Pubkey : {}", initializer.key);
 
-                transfer_temp_to_taker_ix = spl_token::instruction::transfer(
-                    token_program.key,
-                    temp_token_account.key,
-                    taker_token_account.key,
-                    &pda,
-                    &[&pda],
-                    lamports_x[i],
-                )?;
-                msg!("Calling the token program to exchange tokens ...");
-                invoke_signed(
-                    &transfer_temp_to_taker_ix,
-                    &[
-                        pda_account.clone(),
-                        token_program.clone(),
-                        temp_token_account.clone(),
-                        taker_token_account.clone(),
-                    ],
-                    &[&[&b"escrow"[..], &[_nonce]]],
-                )?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
+        msg!("Escrow account Pubkey : {}", escrow_account.key );
 
-                close_escrow_temp_acc_ix = spl_token::instruction::close_account(
-                    token_program.key,
-                    temp_token_account.key,
-                    initializer_token_account.key,
-                    &pda,
-                    &[&pda],
-                )?;
-                msg!("Calling the token program to close pda's temp account...");
-                invoke_signed(
-                    &close_escrow_temp_acc_ix,
-                    &[
-                        pda_account.clone(),
-                        token_program.clone(),
-                        temp_token_account.clone(),
-                        initializer_token_account.clone(),
-                    ],
-                    &[&[&b"escrow"[..], &[_nonce]]],
-                )?;
+        let rent_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_account)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
 
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        if escrow_info.initializer_pubkey != *initializer.key {
+            msg!("initializer pubkey is not the same !");
+            return Err(EscrowError::InvalidInitializer.into());
+        }
+        Self::check_not_disputed(&escrow_info)?;
+        if escrow_info.is_hashlocked() {
+            msg!("the terms of a hashlocked escrow cannot be amended !");
+            return Err(EscrowError::InvalidHashlock.into());
+        }
+        if escrow_info.is_milestone() && escrow_info.sol_dir == 2 && lamports > 0 {
+            msg!("milestones only release the initializer's legs !");
+            return Err(EscrowError::InvalidMilestones.into());
+        }
+        if amount_x < escrow_info.amount_x {
+            msg!("x legs can only be added !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        if amount_y != escrow_info.amount_y {
+            msg!("amount_y is not the same !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        if escrow_info.has_arbiter() {
+            if escrow_info.sol_dir == 1 && lamports < escrow_info.lamports {
+                msg!("the SOL leg held by an escrow with an arbiter cannot be lowered !");
+                return Err(EscrowError::ArbiterApprovalRequired.into());
+            }
+            let sooner = |expires_at: i64| {
+                expires_at != 0 && (escrow_info.expires_at == 0 || expires_at < escrow_info.expires_at)
+            };
+            if expires_at.is_some_and(sooner) {
+                msg!("the expiry of an escrow with an arbiter cannot be brought forward !");
+                return Err(EscrowError::ArbiterApprovalRequired.into());
             }
+        }
+        if let Some(expires_at) = expires_at {
+            if expires_at != 0 && expires_at <= Clock::get()?.unix_timestamp {
+                msg!("expires_at is not in the future !");
+                return Err(EscrowError::InvalidExpiry.into());
+            }
+        }
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+
+        let mut new_info = EscrowState {
+            amount_x,
+            lamports,
+            expires_at: expires_at.unwrap_or(escrow_info.expires_at),
+            ..escrow_info
+        };
+        new_info.bump_version()?;
+        for (leg, amount) in new_info.y_legs[..amount_y as usize].iter_mut().zip(lamports_y) {
+            leg.amount = amount;
+        }
 
-            for j in 0..amount_y {
-                let j = j as usize;
+        let escrow_len = EscrowState::packed_len(amount_x, amount_y);
+        if escrow_len > escrow_account.data_len() {
+            let missing_lamports = rent
+                .minimum_balance(escrow_len)
+                .saturating_sub(rent.minimum_balance(escrow_account.data_len()));
+            invoke(
+                &system_instruction::transfer(initializer.key, escrow_account.key, missing_lamports),
+                &[initializer.clone(), escrow_account.clone(), system_program_account.clone()],
+            )?;
+            escrow_account.realloc(escrow_len, false)?;
+        }
 
-                initializer_token_account = next_account_info(account_info_iter)?;
-                if initializer_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("initializer y token account pubkey{} is not the same !", j);
-                    return Err(EscrowError::InvalidAccount.into());
+        let mut source_accounts = Vec::with_capacity(amount_x as usize);
+        let mut approvals = Vec::with_capacity(amount_x as usize);
+        for (i, leg) in new_info.x_legs[..amount_x as usize].iter_mut().enumerate() {
+            let initializer_token_account = next_account_info(account_info_iter)?;
+            check_writable(initializer_token_account)?;
+            let temp_token_account = next_account_info(account_info_iter)?;
+            check_writable(temp_token_account)?;
+            let mint = next_account_info(account_info_iter)?;
+            let leg_token_program = next_account_info(account_info_iter)?;
+
+            if i < escrow_info.amount_x as usize {
+                if *temp_token_account.key != leg.temp_token_account {
+                    msg!("temp x token account{} is not the same !", i);
+                    return Err(EscrowError::InvalidTempTokenAccount.into());
                 }
-                escrow_data_pos += 32;
-                msg!("initializer y token account pubkey{} is okay !", j);
-
-                taker_token_account = next_account_info(account_info_iter)?;
-                if taker_token_account.key.as_ref() != array_ref!(escrow_data, escrow_data_pos, 32) {
-                    msg!("taker y token account pubkey{} is not the same !", j);
-                    return Err(EscrowError::InvalidAccount.into());
+                if *mint.key != leg.mint {
+                    msg!("mint x{} is not the same !", i);
+                    return Err(EscrowError::InvalidTokenMint.into());
                 }
-                escrow_data_pos += 32;
-                msg!("taker y token account pubkey{} is okay !", j);
-
-                temp_lamports = escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                temp_lamports = (temp_lamports << 8) + escrow_data[escrow_data_pos] as u64;
-                escrow_data_pos += 1;
-                msg!("Lamports_y --> {}, temp_lamports --> {}", lamports_y[j], temp_lamports);
-
-                if lamports_y[j] != temp_lamports {
-                    msg!("lamports_y is not the same !");
-                    return Err(EscrowError::InvalidAccount.into());
+                if *leg_token_program.key != leg.token_program {
+                    msg!("token program x{} is not the same !", i);
+                    return Err(EscrowError::InvalidTokenProgram.into());
                 }
-                msg!("Lamports_y{} OK -------------->", j);
-                
-                transfer_taker_to_initializer_ix = spl_token::instruction::transfer(
-                    token_program.key,
-                    taker_token_account.key,
-                    initializer_token_account.key,
-                    taker_account.key,
-                    &[&taker_account.key],
-                    lamports_y[j]
-                )?;
-                msg!("Calling the token program to transfer tokens to the Initializer token account...");
-                invoke(
-                    &transfer_taker_to_initializer_ix,
-                    &[
-                        taker_account.clone(),
-                        taker_token_account.clone(),
-                        initializer_token_account.clone(),
-                        token_program.clone(),
-                    ],
+                check_token_account(initializer_token_account, mint.key, initializer.key)?;
+                if !escrow_info.non_custodial {
+                    Self::check_temp_authority(temp_token_account, leg, &pda, i)?;
+                } else if *initializer_token_account.key != leg.temp_token_account {
+                    msg!("initializer token account{} is not the one backing the x leg !", i);
+                    return Err(EscrowError::InvalidTempTokenAccount.into());
+                }
+            } else if escrow_info.non_custodial {
+                msg!("adding x leg{} : {}", i, mint.key);
+                check_leg_token_program(leg_token_program, mint)?;
+                check_mint_extensions(mint)?;
+                check_token_account(initializer_token_account, mint.key, initializer.key)?;
+                if *temp_token_account.key != *initializer_token_account.key {
+                    msg!("temp x token account{} is not the initializer token account !", i);
+                    return Err(EscrowError::InvalidTempTokenAccount.into());
+                }
+                *leg = XLeg {
+                    temp_token_account: *initializer_token_account.key,
+                    mint: *mint.key,
+                    token_program: *leg_token_program.key,
+                    amount: 0,
+                };
+            } else {
+                msg!("adding x leg{} : {}", i, mint.key);
+                check_leg_token_program(leg_token_program, mint)?;
+                check_mint_extensions(mint)?;
+                check_token_account(initializer_token_account, mint.key, initializer.key)?;
+                let (vault, vault_bump_seed) = find_vault_address(program_id, escrow_account.key, i as u8);
+                if *temp_token_account.key != vault {
+                    msg!("temp x token account{} is not the vault address !", i);
+                    return Err(EscrowError::InvalidVaultAddress.into());
+                }
+                Self::create_vault(
+                    initializer,
+                    temp_token_account,
+                    mint,
+                    rent_account,
+                    leg_token_program,
+                    system_program_account,
+                    &pda,
+                    &[VAULT_SEED, escrow_account.key.as_ref(), &[i as u8], &[vault_bump_seed]],
                 )?;
-
+                *leg = XLeg {
+                    temp_token_account: vault,
+                    mint: *mint.key,
+                    token_program: *leg_token_program.key,
+                    amount: 0,
+                };
             }
 
+            if escrow_info.non_custodial && source_accounts.contains(initializer_token_account.key) {
+                msg!("initializer token account{} already backs another x leg !", i);
+                return Err(EscrowError::DuplicateTokenAccount.into());
+            }
+            source_accounts.push(*initializer_token_account.key);
+            if escrow_info.non_custodial {
+                check_not_delegated(initializer_token_account, &pda)?;
+                leg.amount = leg.amount.checked_add(top_up_x[i]).ok_or(EscrowError::AmountOverflow)?;
+                approvals.push((initializer_token_account, mint, leg_token_program, leg.amount));
+            } else if top_up_x[i] > 0 {
+                msg!("Calling the token program to transfer initializer ---> temp token account");
+                Self::transfer_tokens(
+                    leg_token_program,
+                    initializer_token_account,
+                    mint,
+                    temp_token_account,
+                    initializer,
+                    top_up_x[i],
+                    true,
+                    &[],
+                )?;
+                leg.amount = leg.amount.checked_add(top_up_x[i]).ok_or(EscrowError::AmountOverflow)?;
+            }
+            msg!("lamports_x{} : {}", i, leg.amount);
         }
 
-        let system_program_account = next_account_info(account_info_iter)?;
-        
-        if (sol_dir == 1) && (lamports) > 0 {
-            **escrow_account.try_borrow_mut_lamports()? -= lamports;
-            **taker_account.try_borrow_mut_lamports()? += lamports;
+        if escrow_info.non_custodial {
+            let pda_account = next_account_info(account_info_iter)?;
+            if *pda_account.key != pda {
+                msg!("pda account is not the escrow authority !");
+                return Err(EscrowError::InvalidAuthority.into());
+            }
+            for (initializer_token_account, mint, leg_token_program, amount) in approvals {
+                msg!("Calling the token program to approve the escrow authority on the initializer token account");
+                Self::approve_delegate(
+                    leg_token_program,
+                    initializer_token_account,
+                    mint,
+                    pda_account,
+                    initializer,
+                    amount,
+                )?;
+                check_delegation(initializer_token_account, &pda, amount)?;
+            }
         }
 
-        if (sol_dir == 2) && (lamports) > 0 {
-            let sol_ix = system_instruction::transfer(
-                taker_account.key,
-                escrow_account.key,
-                lamports,
-            );
+        if escrow_info.sol_dir == 1 && lamports > escrow_info.lamports {
             invoke(
-                &sol_ix,
-                &[
-                    taker_account.clone(),
-                    escrow_account.clone(),
-                    system_program_account.clone(),
-                ],
+                &system_instruction::transfer(initializer.key, escrow_account.key, lamports - escrow_info.lamports),
+                &[initializer.clone(), escrow_account.clone(), system_program_account.clone()],
             )?;
         }
+        if escrow_info.sol_dir == 1 && lamports < escrow_info.lamports {
+            **escrow_account.try_borrow_mut_lamports()? -= escrow_info.lamports - lamports;
+            **initializer.try_borrow_mut_lamports()? += escrow_info.lamports - lamports;
+        }
 
-        msg!("Closing the escrow account...");
-        **initializer.try_borrow_mut_lamports()? = initializer
-            .lamports()
+        msg!("escrow updated to version {}", new_info.version);
+        new_info.pack_account(&mut escrow_account.try_borrow_mut_data()?)
+    }
+
+    //==========================================================================
+    fn process_dispute(
+        accounts: &[AccountInfo],
+        evidence_hash: [u8; 32],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let party = next_account_info(account_info_iter)?;
+        if !party.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        msg!("party Pubkey : {}", party.key);
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
+        msg!("Escrow account Pubkey : {}", escrow_account.key );
+
+        let mut escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        if !escrow_info.has_arbiter() {
+            msg!("escrow has no arbiter to settle a dispute !");
+            return Err(EscrowError::InvalidArbiter.into());
+        }
+        if *party.key != escrow_info.initializer_pubkey && *party.key != escrow_info.taker_pubkey {
+            msg!("only the initializer or the taker can dispute the escrow !");
+            return Err(EscrowError::InvalidParty.into());
+        }
+
+        escrow_info.disputed = true;
+        escrow_info.evidence_hash = evidence_hash;
+        escrow_info.bump_version()?;
+        msg!("escrow disputed, waiting for arbiter {}", escrow_info.arbiter);
+        escrow_info.pack_account(&mut escrow_account.try_borrow_mut_data()?)
+    }
+
+    //==========================================================================
+    fn process_resolve(
+        accounts: &[AccountInfo],
+        taker_lamports: u64,
+        amount_x: u8,
+        x_to_taker: &[bool; MAX_LEGS],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let arbiter = next_account_info(account_info_iter)?;
+        if !arbiter.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(arbiter)?;
+        msg!("arbiter Pubkey : {}", arbiter.key);
+
+        let initializer = next_account_info(account_info_iter)?;
+        check_writable(initializer)?;
+        let taker_account = next_account_info(account_info_iter)?;
+        check_writable(taker_account)?;
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
+        msg!("Escrow account Pubkey : {}", escrow_account.key );
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let rent_destination_account = next_account_info(account_info_iter)?;
+        check_writable(rent_destination_account)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        check_associated_token_program(associated_token_program)?;
+
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        if !escrow_info.has_arbiter() || escrow_info.arbiter != *arbiter.key {
+            msg!("signer is not the arbiter of the escrow !");
+            return Err(EscrowError::InvalidArbiter.into());
+        }
+        if !escrow_info.disputed {
+            msg!("escrow is not disputed !");
+            return Err(EscrowError::EscrowNotDisputed.into());
+        }
+        Self::check_parties(&escrow_info, initializer, taker_account)?;
+        if escrow_info.amount_x != amount_x {
+            msg!("amount_x is not the same !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        let held_lamports = if escrow_info.sol_dir == 1 { escrow_info.lamports } else { 0 };
+        if taker_lamports > held_lamports {
+            msg!("taker_lamports is more than the {} lamports held by the escrow !", held_lamports);
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        if *rent_destination_account.key != escrow_info.rent_destination {
+            msg!("rent destination is not the one stored in the escrow !");
+            return Err(EscrowError::InvalidRentDestination.into());
+        }
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+        if *pda_account.key != pda {
+            msg!("pda account is not the escrow authority !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        for (i, leg) in escrow_info.x_legs().iter().enumerate() {
+            let recipient_token_account = next_account_info(account_info_iter)?;
+            let XLegAccounts { temp_token_account, mint, token_program } =
+                Self::next_x_leg_accounts(account_info_iter, leg, i)?;
+            check_writable(temp_token_account)?;
+            Self::check_temp_authority(temp_token_account, leg, &pda, i)?;
+
+            check_writable(recipient_token_account)?;
+            if x_to_taker[i] {
+                Self::create_associated_token_account(
+                    arbiter,
+                    recipient_token_account,
+                    taker_account,
+                    mint,
+                    token_program,
+                    system_program_account,
+                    associated_token_program,
+                )?;
+                check_token_account(recipient_token_account, &leg.mint, taker_account.key)?;
+                msg!("x leg{} goes to the taker", i);
+            } else {
+                check_token_account(recipient_token_account, &leg.mint, &escrow_info.initializer_pubkey)?;
+                msg!("x leg{} goes back to the initializer", i);
+            }
+
+            Self::transfer_tokens(
+                token_program,
+                temp_token_account,
+                mint,
+                recipient_token_account,
+                pda_account,
+                leg.amount,
+                false,
+                &[authority_seeds],
+            )?;
+            Self::close_vault(
+                token_program,
+                temp_token_account,
+                mint,
+                rent_destination_account,
+                pda_account,
+                &[authority_seeds],
+            )?;
+        }
+
+        if held_lamports > 0 {
+            msg!(
+                "SOL leg split, {} lamports to the taker, {} back to the initializer",
+                taker_lamports,
+                held_lamports - taker_lamports
+            );
+            **escrow_account.try_borrow_mut_lamports()? -= held_lamports;
+            **taker_account.try_borrow_mut_lamports()? += taker_lamports;
+            **initializer.try_borrow_mut_lamports()? += held_lamports - taker_lamports;
+        }
+
+        Self::close_escrow_account(rent_destination_account, escrow_account)
+    }
+
+    //==========================================================================
+    fn process_release_milestone(
+        accounts: &[AccountInfo],
+        milestone: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let approver = next_account_info(account_info_iter)?;
+        if !approver.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(approver)?;
+        msg!("approver Pubkey : {}", approver.key);
+
+        let initializer = next_account_info(account_info_iter)?;
+        let taker_account = next_account_info(account_info_iter)?;
+        check_writable(taker_account)?;
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
+        msg!("Escrow account Pubkey : {}", escrow_account.key );
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let rent_destination_account = next_account_info(account_info_iter)?;
+        check_writable(rent_destination_account)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        check_associated_token_program(associated_token_program)?;
+
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        Self::check_parties(&escrow_info, initializer, taker_account)?;
+        Self::check_not_disputed(&escrow_info)?;
+        if *approver.key != escrow_info.initializer_pubkey
+            && !(escrow_info.has_arbiter() && *approver.key == escrow_info.arbiter)
+        {
+            msg!("only the initializer or the arbiter can release a milestone !");
+            return Err(EscrowError::InvalidApprover.into());
+        }
+        if !escrow_info.is_milestone() || milestone != escrow_info.milestones_released {
+            msg!("milestone {} is not the next one to release !", milestone);
+            return Err(EscrowError::InvalidMilestones.into());
+        }
+        if *rent_destination_account.key != escrow_info.rent_destination {
+            msg!("rent destination is not the one stored in the escrow !");
+            return Err(EscrowError::InvalidRentDestination.into());
+        }
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+        if *pda_account.key != pda {
+            msg!("pda account is not the escrow authority !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        let mut new_info = EscrowState {
+            milestones_released: escrow_info.milestones_released + 1,
+            lamports: escrow_info.lamports - escrow_info.milestone_tranche(escrow_info.lamports),
+            ..escrow_info
+        };
+        let last = new_info.milestones_released == new_info.milestone_count;
+
+        for (i, (leg, new_leg)) in escrow_info.x_legs().iter().zip(new_info.x_legs.iter_mut()).enumerate() {
+            let taker_token_account = next_account_info(account_info_iter)?;
+            let XLegAccounts { temp_token_account, mint, token_program } =
+                Self::next_x_leg_accounts(account_info_iter, leg, i)?;
+            check_writable(taker_token_account)?;
+            check_writable(temp_token_account)?;
+            Self::create_associated_token_account(
+                approver,
+                taker_token_account,
+                taker_account,
+                mint,
+                token_program,
+                system_program_account,
+                associated_token_program,
+            )?;
+            check_token_account(taker_token_account, &leg.mint, taker_account.key)?;
+            Self::check_temp_authority(temp_token_account, leg, &pda, i)?;
+
+            let tranche = escrow_info.milestone_tranche(leg.amount);
+            new_leg.amount = leg.amount - tranche;
+            msg!("milestone {} releases {} of x leg{}", milestone, tranche, i);
+            if tranche > 0 {
+                Self::transfer_tokens(
+                    token_program,
+                    temp_token_account,
+                    mint,
+                    taker_token_account,
+                    pda_account,
+                    tranche,
+                    false,
+                    &[authority_seeds],
+                )?;
+            }
+
+            if !last {
+                continue;
+            }
+            Self::close_vault(
+                token_program,
+                temp_token_account,
+                mint,
+                rent_destination_account,
+                pda_account,
+                &[authority_seeds],
+            )?;
+        }
+
+        let lamports = escrow_info.lamports - new_info.lamports;
+        if escrow_info.sol_dir == 1 && lamports > 0 {
+            msg!("milestone {} releases {} lamports", milestone, lamports);
+            **escrow_account.try_borrow_mut_lamports()? -= lamports;
+            **taker_account.try_borrow_mut_lamports()? += lamports;
+        }
+
+        if !last {
+            new_info.bump_version()?;
+            return new_info.pack_account(&mut escrow_account.try_borrow_mut_data()?);
+        }
+        msg!("last milestone released");
+        Self::close_escrow_account(rent_destination_account, escrow_account)
+    }
+
+    //==========================================================================
+    fn process_claim_with_secret(
+        accounts: &[AccountInfo],
+        secret: &[u8; 32],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let taker_account = next_account_info(account_info_iter)?;
+        if !taker_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(taker_account)?;
+        msg!("Taker Pubkey : {}", taker_account.key);
+
+        let initializer = next_account_info(account_info_iter)?;
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        check_escrow_owner(escrow_account, program_id)?;
+        check_writable(escrow_account)?;
+        msg!("Escrow account Pubkey : {}", escrow_account.key );
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let rent_destination_account = next_account_info(account_info_iter)?;
+        check_writable(rent_destination_account)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        check_associated_token_program(associated_token_program)?;
+
+        let escrow_info = EscrowState::unpack_account(&escrow_account.try_borrow_data()?)?;
+        Self::check_parties(&escrow_info, initializer, taker_account)?;
+        Self::check_not_disputed(&escrow_info)?;
+        if !escrow_info.is_hashlocked() {
+            msg!("escrow is not hashlocked !");
+            return Err(EscrowError::InvalidHashlock.into());
+        }
+        if hash(secret).to_bytes() != escrow_info.hashlock {
+            msg!("secret does not match the hashlock !");
+            return Err(EscrowError::InvalidSecret.into());
+        }
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            msg!("escrow has expired !");
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        if *rent_destination_account.key != escrow_info.rent_destination {
+            msg!("rent destination is not the one stored in the escrow !");
+            return Err(EscrowError::InvalidRentDestination.into());
+        }
+        let pda = create_escrow_authority(program_id, escrow_account.key, escrow_info.bump_seed)?;
+        if *pda_account.key != pda {
+            msg!("pda account is not the escrow authority !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let authority_seeds: &[&[u8]] = &[AUTHORITY_SEED, escrow_account.key.as_ref(), &[escrow_info.bump_seed]];
+
+        msg!("secret revealed");
+        sol_log_data(&[escrow_account.key.as_ref(), secret]);
+
+        for (i, leg) in escrow_info.x_legs().iter().enumerate() {
+            let taker_token_account = next_account_info(account_info_iter)?;
+            let XLegAccounts { temp_token_account, mint, token_program } =
+                Self::next_x_leg_accounts(account_info_iter, leg, i)?;
+            check_writable(taker_token_account)?;
+            check_writable(temp_token_account)?;
+            Self::create_associated_token_account(
+                taker_account,
+                taker_token_account,
+                taker_account,
+                mint,
+                token_program,
+                system_program_account,
+                associated_token_program,
+            )?;
+            check_token_account(taker_token_account, &leg.mint, taker_account.key)?;
+            Self::check_temp_authority(temp_token_account, leg, &pda, i)?;
+
+            msg!("Calling the token program to transfer tokens to the taker token account...");
+            Self::transfer_tokens(
+                token_program,
+                temp_token_account,
+                mint,
+                taker_token_account,
+                pda_account,
+                leg.amount,
+                false,
+                &[authority_seeds],
+            )?;
+            Self::close_vault(
+                token_program,
+                temp_token_account,
+                mint,
+                rent_destination_account,
+                pda_account,
+                &[authority_seeds],
+            )?;
+        }
+
+        if escrow_info.sol_dir == 1 && escrow_info.lamports > 0 {
+            **escrow_account.try_borrow_mut_lamports()? -= escrow_info.lamports;
+            **taker_account.try_borrow_mut_lamports()? += escrow_info.lamports;
+        }
+
+        Self::close_escrow_account(rent_destination_account, escrow_account)
+    }
+
+    //==========================================================================
+    fn process_fill_signed_order(
+        accounts: &[AccountInfo],
+        order: &Order,
+        authority_bump_seed: u8,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let taker_account = next_account_info(account_info_iter)?;
+        if !taker_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(taker_account)?;
+        msg!("Taker Pubkey : {}", taker_account.key);
+
+        let maker = next_account_info(account_info_iter)?;
+        if *maker.key != order.maker {
+            msg!("maker account is not the maker of the order !");
+            return Err(EscrowError::InvalidMaker.into());
+        }
+        msg!("maker Pubkey : {}", maker.key);
+
+        let order_authority = next_account_info(account_info_iter)?;
+        if *order_authority.key != create_order_authority(program_id, maker.key, authority_bump_seed)? {
+            msg!("order authority is not the one of the maker !");
+            return Err(EscrowError::InvalidAuthority.into());
+        }
+        let nonces_account = next_account_info(account_info_iter)?;
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        check_associated_token_program(associated_token_program)?;
+
+        order.check_signature(instructions_sysvar, program_id)?;
+        msg!("order signature OK -------------->");
+        if !order.is_open() && order.taker != *taker_account.key {
+            msg!("taker pubkey is not the taker of the order !");
+            return Err(EscrowError::InvalidTaker.into());
+        }
+        if order.is_expired(Clock::get()?.unix_timestamp) {
+            msg!("order has expired !");
+            return Err(EscrowError::EscrowExpired.into());
+        }
+        if order.amount_x == 0 {
+            msg!("order sends no X tokens !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        Self::use_order_nonce(
+            taker_account,
+            nonces_account,
+            system_program_account,
+            maker.key,
+            order.nonce,
+            program_id,
+        )?;
+
+        let maker_token_account = next_account_info(account_info_iter)?;
+        check_writable(maker_token_account)?;
+        let taker_token_account = next_account_info(account_info_iter)?;
+        check_writable(taker_token_account)?;
+        let (mint, token_program) = Self::next_order_token_accounts(account_info_iter, &order.mint_x)?;
+        check_token_account(maker_token_account, mint.key, maker.key)?;
+        check_delegation(maker_token_account, order_authority.key, order.amount_x)?;
+        Self::create_associated_token_account(
+            taker_account,
+            taker_token_account,
+            taker_account,
+            mint,
+            token_program,
+            system_program_account,
+            associated_token_program,
+        )?;
+        check_token_account(taker_token_account, mint.key, taker_account.key)?;
+
+        msg!("Calling the token program to transfer the maker's tokens as delegate...");
+        Self::transfer_tokens(
+            token_program,
+            maker_token_account,
+            mint,
+            taker_token_account,
+            order_authority,
+            order.amount_x,
+            false,
+            &[&[ORDER_AUTHORITY_SEED, maker.key.as_ref(), &[authority_bump_seed]]],
+        )?;
+
+        if order.amount_y > 0 {
+            let taker_token_account = next_account_info(account_info_iter)?;
+            check_writable(taker_token_account)?;
+            let maker_token_account = next_account_info(account_info_iter)?;
+            check_writable(maker_token_account)?;
+            let (mint, token_program) = Self::next_order_token_accounts(account_info_iter, &order.mint_y)?;
+            check_token_account(taker_token_account, mint.key, taker_account.key)?;
+            Self::create_associated_token_account(
+                taker_account,
+                maker_token_account,
+                maker,
+                mint,
+                token_program,
+                system_program_account,
+                associated_token_program,
+            )?;
+            check_token_account(maker_token_account, mint.key, maker.key)?;
+
+            msg!("Calling the token program to transfer tokens to the maker token account...");
+            Self::transfer_tokens(
+                token_program,
+                taker_token_account,
+                mint,
+                maker_token_account,
+                taker_account,
+                order.amount_y,
+                true,
+                &[],
+            )?;
+        }
+
+        if order.lamports > 0 {
+            check_writable(maker)?;
+            invoke(
+                &system_instruction::transfer(taker_account.key, maker.key, order.lamports),
+                &[taker_account.clone(), maker.clone(), system_program_account.clone()],
+            )?;
+        }
+
+        msg!("order {} of {} filled", order.nonce, maker.key);
+        Ok(())
+    }
+
+    //==========================================================================
+    fn process_cancel_order(
+        accounts: &[AccountInfo],
+        nonce: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let maker = next_account_info(account_info_iter)?;
+        if !maker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        check_writable(maker)?;
+        msg!("maker Pubkey : {}", maker.key);
+        let nonces_account = next_account_info(account_info_iter)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+
+        Self::use_order_nonce(maker, nonces_account, system_program_account, maker.key, nonce, program_id)?;
+        msg!("order {} cancelled", nonce);
+        Ok(())
+    }
+
+    //==========================================================================
+    fn process_init_config(
+        accounts: &[AccountInfo],
+        fee_bps: u16,
+        treasury: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        check_writable(admin)?;
+        let config_account = next_account_info(account_info_iter)?;
+        check_writable(config_account)?;
+        let program_data_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_account = next_account_info(account_info_iter)?;
+        check_system_program(system_program_account)?;
+
+        check_upgrade_authority(program_id, program_data_account, admin)?;
+        let (config_address, config_bump_seed) = find_config_address(program_id);
+        if *config_account.key != config_address {
+            msg!("config account is not the config address !");
+            return Err(EscrowError::InvalidConfigAccount.into());
+        }
+        if config_account.owner == program_id {
+            msg!("config account already exists !");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        if fee_bps > MAX_FEE_BPS {
+            msg!("fee of {} bps is above the cap !", fee_bps);
+            return Err(EscrowError::FeeTooHigh.into());
+        }
+
+        Self::create_pda_account(
+            admin,
+            config_account,
+            system_program_account,
+            rent.minimum_balance(Config::LEN),
+            Config::LEN,
+            program_id,
+            &[CONFIG_SEED, &[config_bump_seed]],
+        )?;
+        let config = Config {
+            is_initialized: true,
+            bump_seed: config_bump_seed,
+            admin: *admin.key,
+            fee_bps,
+            treasury,
+            ..Config::default()
+        };
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)
+    }
+
+    //==========================================================================
+    fn process_update_config(
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+        fee_bps: u16,
+        treasury: Pubkey,
+        fee_exempt: &[Pubkey],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let config_account = next_account_info(account_info_iter)?;
+        check_writable(config_account)?;
+        if config_account.owner != program_id {
+            msg!("config account is not owned by the program !");
+            return Err(EscrowError::InvalidConfigAccount.into());
+        }
+
+        let mut config = Config::unpack(&config_account.try_borrow_data()?)?;
+        if *config_account.key != create_config_address(program_id, config.bump_seed)? {
+            msg!("config account is not the config of this program !");
+            return Err(EscrowError::InvalidConfigAccount.into());
+        }
+        if config.admin != *admin.key {
+            msg!("{} is not the admin of the config !", admin.key);
+            return Err(EscrowError::InvalidAdmin.into());
+        }
+        if fee_bps > MAX_FEE_BPS {
+            msg!("fee of {} bps is above the cap !", fee_bps);
+            return Err(EscrowError::FeeTooHigh.into());
+        }
+        if fee_exempt.len() > MAX_FEE_EXEMPT {
+            return Err(EscrowError::TooManyFeeExempt.into());
+        }
+
+        config.admin = new_admin;
+        config.fee_bps = fee_bps;
+        config.treasury = treasury;
+        config.fee_exempt_count = fee_exempt.len() as u8;
+        config.fee_exempt = [Pubkey::default(); MAX_FEE_EXEMPT];
+        config.fee_exempt[..fee_exempt.len()].copy_from_slice(fee_exempt);
+        msg!("config updated, fee {} bps", fee_bps);
+        Config::pack(config, &mut config_account.try_borrow_mut_data()?)
+    }
+
+    //==========================================================================
+    /// Checks the terms resent with the instruction against the ones stored at init
+    fn check_terms(escrow_info: &EscrowState, terms: &Terms) -> ProgramResult {
+        let Terms { lamports, sol_dir, amount_x, amount_y, lamports_x, lamports_y } = *terms;
+
+        if escrow_info.amount_x != amount_x {
+            msg!("amount_x is not the same !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        if escrow_info.amount_y != amount_y {
+            msg!("amount_y is not the same !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+        if escrow_info.sol_dir != sol_dir {
+            msg!("sol_dir is not the same !");
+            return Err(EscrowError::InvalidAmount.into());
+        }
+
+        msg!("Lamports --> {}, temp_lamports --> {}", lamports, escrow_info.lamports);
+        if escrow_info.lamports != lamports {
+            msg!("lamports is not the same !");
+            return Err(EscrowError::InvalidAccount.into());
+        }
+        msg!("Lamports OK -------------->");
+
+        for (i, leg) in escrow_info.x_legs().iter().enumerate() {
+            if leg.amount != lamports_x[i] {
+                msg!("lamports_x{} is not the same !", i);
+                return Err(EscrowError::InvalidAccount.into());
+            }
+        }
+        for (j, leg) in escrow_info.y_legs().iter().enumerate() {
+            if leg.amount != lamports_y[j] {
+                msg!("lamports_y{} is not the same !", j);
+                return Err(EscrowError::InvalidAccount.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the metadata and creator accounts of the NFT legs sold in this fill and splits their royalties.
+    /// The NFT legs are the legs going the other way of the SOL leg whose mint `is_nft`, they share its price evenly.
+    fn royalties<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        escrow_info: &EscrowState,
+        remaining_info: &EscrowState,
+        x_mints: &[&AccountInfo<'b>],
+        y_mints: &[&AccountInfo<'b>],
+        lamports: u64,
+        metadata_bump_seeds: &[u8],
+    ) -> Result<Vec<(&'a AccountInfo<'b>, u64)>, ProgramError> {
+        if !escrow_info.enforce_royalties || lamports == 0 {
+            return Ok(Vec::new());
+        }
+        let sold: Vec<(&AccountInfo<'b>, bool)> = match escrow_info.sol_dir {
+            1 => y_mints
+                .iter()
+                .zip(escrow_info.y_legs().iter().zip(remaining_info.y_legs()))
+                .map(|(mint, (leg, remaining_leg))| (*mint, leg.amount > 0 && remaining_leg.amount == 0))
+                .collect(),
+            2 => x_mints
+                .iter()
+                .zip(escrow_info.x_legs().iter().zip(remaining_info.x_legs()))
+                .map(|(mint, (leg, remaining_leg))| (*mint, leg.amount > 0 && remaining_leg.amount == 0))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut nft_mints = Vec::new();
+        for (mint, sold) in sold {
+            if sold && Self::is_nft(mint)? {
+                nft_mints.push(*mint.key);
+            }
+        }
+        if nft_mints.is_empty() {
+            return Ok(Vec::new());
+        }
+        let price = lamports / nft_mints.len() as u64;
+
+        let mut royalties = Vec::new();
+        for (i, mint) in nft_mints.iter().enumerate() {
+            let metadata_bump_seed = *metadata_bump_seeds.get(i).ok_or_else(|| {
+                msg!("no bump seed for the metadata account of {} !", mint);
+                EscrowError::InvalidInstruction
+            })?;
+            let metadata =
+                Metadata::from_account_info(next_account_info(account_info_iter)?, mint, metadata_bump_seed)?;
+            for (creator, royalty) in metadata.royalties(price) {
+                let creator_account = next_account_info(account_info_iter)?;
+                if *creator_account.key != creator {
+                    msg!("creator account {} is not the creator {} of {} !", creator_account.key, creator, mint);
+                    return Err(EscrowError::InvalidCreator.into());
+                }
+                check_writable(creator_account)?;
+                if royalty > 0 {
+                    royalties.push((creator_account, royalty));
+                }
+            }
+        }
+        Ok(royalties)
+    }
+
+    /// Loads the config when it exists and neither party is fee exempt, the trade pays no fee otherwise
+    fn fee_config(
+        config_account: &AccountInfo,
+        initializer: &AccountInfo,
+        taker_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Config>, ProgramError> {
+        if config_account.owner != program_id {
+            // no config yet, only then is its address derived the expensive way
+            if *config_account.key != find_config_address(program_id).0 {
+                msg!("config account is not the config address !");
+                return Err(EscrowError::InvalidConfigAccount.into());
+            }
+            return Ok(None);
+        }
+        let config = Config::unpack(&config_account.try_borrow_data()?)?;
+        if *config_account.key != create_config_address(program_id, config.bump_seed)? {
+            msg!("config account is not the config address !");
+            return Err(EscrowError::InvalidConfigAccount.into());
+        }
+        let exempt = config.fee_exempt();
+        if config.fee_bps == 0 || exempt.contains(initializer.key) || exempt.contains(taker_account.key) {
+            return Ok(None);
+        }
+        Ok(Some(config))
+    }
+
+    /// Moves tokens with `transfer_checked` and checks what `destination` actually received. When the mint charges a
+    /// Token-2022 transfer fee, a sender paying it sends the fee on top so `destination` receives `amount`, otherwise
+    /// the fee is withheld from `amount`.
+    #[allow(clippy::too_many_arguments)]
+    fn transfer_tokens<'a>(
+        token_program: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        amount: u64,
+        sender_pays_fee: bool,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let (decimals, transfer_fee_config) = {
+            let mint_data = mint.try_borrow_data()?;
+            let mint_info = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+            (mint_info.base.decimals, mint_info.get_extension::<TransferFeeConfig>().ok().copied())
+        };
+        let (sent, expected) = match transfer_fee_config {
+            None => (amount, amount),
+            Some(transfer_fee_config) => {
+                let epoch = Clock::get()?.epoch;
+                if sender_pays_fee {
+                    let transfer_fee = transfer_fee_config
+                        .calculate_inverse_epoch_fee(epoch, amount)
+                        .ok_or(EscrowError::AmountOverflow)?;
+                    (amount.checked_add(transfer_fee).ok_or(EscrowError::AmountOverflow)?, amount)
+                } else {
+                    let transfer_fee =
+                        transfer_fee_config.calculate_epoch_fee(epoch, amount).ok_or(EscrowError::AmountOverflow)?;
+                    (amount, amount - transfer_fee)
+                }
+            }
+        };
+
+        let balance_before = Self::token_balance(destination)?;
+        let transfer_ix = spl_token_2022::instruction::transfer_checked(
+            token_program.key,
+            source.key,
+            mint.key,
+            destination.key,
+            authority.key,
+            &[authority.key],
+            sent,
+            decimals,
+        )?;
+        invoke_signed(
+            &transfer_ix,
+            &[
+                source.clone(),
+                mint.clone(),
+                destination.clone(),
+                authority.clone(),
+                token_program.clone(),
+            ],
+            signer_seeds,
+        )?;
+
+        let received = Self::token_balance(destination)?.saturating_sub(balance_before);
+        if received != expected {
+            msg!("{} received {} tokens instead of {} !", destination.key, received, expected);
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+        Ok(())
+    }
+
+    /// Reads the balance of a SPL Token or Token-2022 account
+    fn token_balance(token_account: &AccountInfo) -> Result<u64, ProgramError> {
+        Ok(StateWithExtensions::<TokenAccount>::unpack(&token_account.try_borrow_data()?)?.base.amount)
+    }
+
+    /// Closes an emptied vault, first moving the transfer fees withheld in it to the mint as Token-2022 requires
+    fn close_vault<'a>(
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        rent_destination_account: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let withheld_fees = StateWithExtensions::<TokenAccount>::unpack(&temp_token_account.try_borrow_data()?)?
+            .get_extension::<TransferFeeAmount>()
+            .map_or(0, |transfer_fee_amount| u64::from(transfer_fee_amount.withheld_amount));
+        if withheld_fees > 0 {
+            check_writable(mint)?;
+            let harvest_ix =
+                harvest_withheld_tokens_to_mint(token_program.key, mint.key, &[temp_token_account.key])?;
+            msg!("Calling the token program to harvest the fees withheld in pda's temp account...");
+            invoke(&harvest_ix, &[mint.clone(), temp_token_account.clone(), token_program.clone()])?;
+        }
+
+        let close_escrow_temp_acc_ix = spl_token_2022::instruction::close_account(
+            token_program.key,
+            temp_token_account.key,
+            rent_destination_account.key,
+            pda_account.key,
+            &[pda_account.key],
+        )?;
+        msg!("Calling the token program to close pda's temp account...");
+        invoke_signed(
+            &close_escrow_temp_acc_ix,
+            &[
+                pda_account.clone(),
+                token_program.clone(),
+                temp_token_account.clone(),
+                rent_destination_account.clone(),
+            ],
+            signer_seeds,
+        )
+    }
+
+    /// Returns the escrow state left after settling `fill_numerator / FILL_DENOMINATOR` of its remaining terms.
+    /// Amounts the taker receives are rounded down and amounts the taker pays are rounded up, a partial fill
+    /// must leave something of every leg the taker pays so the rest of the escrow is never given away, and give
+    /// something of every X leg so a leg of a single token or dust fills cannot be paid for nothing.
+    fn fill_terms(escrow_info: &EscrowState, fill_numerator: u64) -> Result<EscrowState, ProgramError> {
+        if fill_numerator == 0 || fill_numerator > FILL_DENOMINATOR {
+            msg!("fill_numerator {} is out of range !", fill_numerator);
+            return Err(EscrowError::InvalidFill.into());
+        }
+        let partial = fill_numerator < FILL_DENOMINATOR;
+        let slice = |amount: u64, round_up: bool| -> Result<u64, ProgramError> {
+            let numerator = amount as u128 * fill_numerator as u128;
+            let denominator = FILL_DENOMINATOR as u128;
+            let slice = if round_up {
+                numerator.div_ceil(denominator)
+            } else {
+                numerator / denominator
+            };
+            let slice = u64::try_from(slice).map_err(|_| EscrowError::AmountOverflow)?;
+            if partial && round_up && amount > 0 && slice == amount {
+                msg!("fill would use up an amount the taker pays !");
+                return Err(EscrowError::InvalidFill.into());
+            }
+            Ok(slice)
+        };
+
+        let mut remaining_info = *escrow_info;
+        for leg in remaining_info.x_legs[..escrow_info.amount_x as usize].iter_mut() {
+            let x_slice = slice(leg.amount, false)?;
+            if partial && leg.amount > 0 && x_slice == 0 {
+                msg!("fill would give nothing of an x leg !");
+                return Err(EscrowError::InvalidFill.into());
+            }
+            leg.amount -= x_slice;
+        }
+        for leg in remaining_info.y_legs[..escrow_info.amount_y as usize].iter_mut() {
+            leg.amount -= slice(leg.amount, true)?;
+        }
+        remaining_info.lamports -= slice(escrow_info.lamports, escrow_info.sol_dir == 2)?;
+        Ok(remaining_info)
+    }
+
+    /// Whether a mint is an NFT, a supply of a single token with no decimals
+    fn is_nft(mint: &AccountInfo) -> Result<bool, ProgramError> {
+        let mint_info = StateWithExtensions::<Mint>::unpack(&mint.try_borrow_data()?)?.base;
+        Ok(mint_info.decimals == 0 && mint_info.supply == 1)
+    }
+
+    /// Checks the initializer and taker accounts against the escrow state, any taker passes for an open escrow
+    fn check_parties(
+        escrow_info: &EscrowState,
+        initializer: &AccountInfo,
+        taker_account: &AccountInfo,
+    ) -> ProgramResult {
+        if escrow_info.initializer_pubkey != *initializer.key {
+            msg!("initializer pubkey is not the same !");
+            return Err(EscrowError::InvalidInitializer.into());
+        }
+        msg!("Initializer Account OK -------------->");
+
+        if !escrow_info.is_open() && escrow_info.taker_pubkey != *taker_account.key {
+            msg!("taker pubkey is not the same !");
+            return Err(EscrowError::InvalidTaker.into());
+        }
+        msg!("Taker Account OK -------------->");
+
+        Ok(())
+    }
+
+    /// Marks `nonce` of `maker` as used in its nonce account, created by `payer` when missing, failing if it already is
+    fn use_order_nonce<'a>(
+        payer: &AccountInfo<'a>,
+        nonces_account: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        maker: &Pubkey,
+        nonce: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        check_writable(nonces_account)?;
+        let page = NonceBitmap::page(nonce);
+        let mut nonces = if nonces_account.owner != program_id {
+            let (nonces_address, nonces_bump_seed) = find_order_nonces_address(program_id, maker, page);
+            if *nonces_account.key != nonces_address {
+                msg!("nonce account is not the one of nonce {} !", nonce);
+                return Err(EscrowError::InvalidNonceAccount.into());
+            }
+            Self::create_pda_account(
+                payer,
+                nonces_account,
+                system_program_account,
+                Rent::get()?.minimum_balance(NonceBitmap::LEN),
+                NonceBitmap::LEN,
+                program_id,
+                &[NONCES_SEED, maker.as_ref(), &page.to_le_bytes(), &[nonces_bump_seed]],
+            )?;
+            NonceBitmap { bump_seed: nonces_bump_seed, ..NonceBitmap::default() }
+        } else {
+            let nonces = NonceBitmap::unpack_from_slice(&nonces_account.try_borrow_data()?)?;
+            if *nonces_account.key != create_order_nonces_address(program_id, maker, page, nonces.bump_seed)? {
+                msg!("nonce account is not the one of nonce {} !", nonce);
+                return Err(EscrowError::InvalidNonceAccount.into());
+            }
+            nonces
+        };
+        if nonces.is_used(nonce) {
+            msg!("nonce {} was already filled or cancelled !", nonce);
+            return Err(EscrowError::OrderNonceUsed.into());
+        }
+        nonces.set_used(nonce);
+        nonces.pack_into_slice(&mut nonces_account.try_borrow_mut_data()?);
+        Ok(())
+    }
+
+    /// Takes the mint and token program accounts of one side of an order, the token program must own the mint
+    fn next_order_token_accounts<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        mint: &Pubkey,
+    ) -> Result<(&'a AccountInfo<'b>, &'a AccountInfo<'b>), ProgramError> {
+        let mint_account = next_account_info(account_info_iter)?;
+        if mint_account.key != mint {
+            msg!("mint {} is not the one of the order !", mint_account.key);
+            return Err(EscrowError::InvalidTokenMint.into());
+        }
+        let token_program = next_account_info(account_info_iter)?;
+        check_leg_token_program(token_program, mint_account)?;
+        check_mint_extensions(mint_account)?;
+        Ok((mint_account, token_program))
+    }
+
+    /// Fails once a party disputed the escrow, only `Resolve` can settle it then
+    fn check_not_disputed(escrow_info: &EscrowState) -> ProgramResult {
+        if escrow_info.disputed {
+            msg!("escrow is disputed, only its arbiter can settle it !");
+            return Err(EscrowError::EscrowDisputed.into());
+        }
+        Ok(())
+    }
+
+    /// Takes the temp token account, mint and token program of an X leg and checks them against the escrow state.
+    /// The account the leg goes to comes before them and is checked by whoever sends to it.
+    fn next_x_leg_accounts<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        leg: &XLeg,
+        i: usize,
+    ) -> Result<XLegAccounts<'a, 'b>, ProgramError> {
+        let temp_token_account = next_account_info(account_info_iter)?;
+        if *temp_token_account.key != leg.temp_token_account {
+            msg!("temp x token account pubkey{} is not the same !", i);
+            return Err(EscrowError::InvalidTempTokenAccount.into());
+        }
+        let (mint, token_program) = Self::next_leg_token_accounts(account_info_iter, &leg.mint, &leg.token_program)?;
+        msg!("x token accounts{} are okay !", i);
+
+        Ok(XLegAccounts { temp_token_account, mint, token_program })
+    }
+
+    /// Takes the initializer and taker token accounts of a Y leg, whoever sends to or from them checks them
+    fn next_y_leg_accounts<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        leg: &YLeg,
+        j: usize,
+    ) -> Result<YLegAccounts<'a, 'b>, ProgramError> {
+        let initializer_token_account = next_account_info(account_info_iter)?;
+        let taker_token_account = next_account_info(account_info_iter)?;
+        let (mint, token_program) = Self::next_leg_token_accounts(account_info_iter, &leg.mint, &leg.token_program)?;
+        msg!("y token accounts{} are okay !", j);
+
+        Ok(YLegAccounts { initializer_token_account, taker_token_account, mint, token_program })
+    }
+
+    /// Takes the mint and token program of a leg and checks them against the ones stored at init
+    fn next_leg_token_accounts<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+        mint: &Pubkey,
+        token_program: &Pubkey,
+    ) -> Result<(&'a AccountInfo<'b>, &'a AccountInfo<'b>), ProgramError> {
+        let mint_account = next_account_info(account_info_iter)?;
+        if mint_account.key != mint {
+            msg!("mint {} is not the leg's mint {} !", mint_account.key, mint);
+            return Err(EscrowError::InvalidTokenMint.into());
+        }
+        let token_program_account = next_account_info(account_info_iter)?;
+        if token_program_account.key != token_program {
+            msg!("token program {} is not the leg's token program {} !", token_program_account.key, token_program);
+            return Err(EscrowError::InvalidTokenProgram.into());
+        }
+        check_leg_token_program(token_program_account, mint_account)?;
+        Ok((mint_account, token_program_account))
+    }
+
+    /// Checks that a temp token account holds the leg's mint and is owned by this escrow's PDA and not another escrow's
+    fn check_temp_authority(temp_token_account: &AccountInfo, leg: &XLeg, pda: &Pubkey, i: usize) -> ProgramResult {
+        let temp_token_info = StateWithExtensions::<TokenAccount>::unpack(&temp_token_account.try_borrow_data()?)?.base;
+        if temp_token_info.owner != *pda {
+            msg!("temp x token account{} is not owned by the escrow authority !", i);
+            return Err(EscrowError::InvalidVaultAuthority.into());
+        }
+        if temp_token_info.mint != leg.mint {
+            msg!("temp x token account{} does not hold the leg's mint !", i);
+            return Err(EscrowError::InvalidTokenMint.into());
+        }
+        Ok(())
+    }
+
+    /// Closes the vault of a settled X leg, a non-custodial escrow has none
+    #[allow(clippy::too_many_arguments)]
+    fn close_leg<'a>(
+        escrow_info: &EscrowState,
+        token_program: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        rent_destination_account: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        if escrow_info.non_custodial {
+            return Ok(());
+        }
+        Self::close_vault(token_program, temp_token_account, mint, rent_destination_account, pda_account, signer_seeds)
+    }
+
+    /// Checks that the program can move `amount` out of an X leg's temp token account, a vault owned by the PDA or, in
+    /// a non-custodial escrow, the initializer's token account still delegating it to the PDA
+    fn check_leg_custody(
+        escrow_info: &EscrowState,
+        temp_token_account: &AccountInfo,
+        leg: &XLeg,
+        pda: &Pubkey,
+        amount: u64,
+        i: usize,
+    ) -> ProgramResult {
+        if !escrow_info.non_custodial {
+            return Self::check_temp_authority(temp_token_account, leg, pda, i);
+        }
+        check_token_account(temp_token_account, &leg.mint, &escrow_info.initializer_pubkey)?;
+        check_delegation(temp_token_account, pda, amount)
+    }
+
+    /// Approves the PDA as delegate of `amount` tokens on the initializer's token account, signed by the initializer
+    fn approve_delegate<'a>(
+        token_program: &AccountInfo<'a>,
+        token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        owner: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        let decimals = StateWithExtensions::<Mint>::unpack(&mint.try_borrow_data()?)?.base.decimals;
+        let approve_ix = spl_token_2022::instruction::approve_checked(
+            token_program.key,
+            token_account.key,
+            mint.key,
+            pda_account.key,
+            owner.key,
+            &[],
+            amount,
+            decimals,
+        )?;
+        invoke(
+            &approve_ix,
+            &[token_account.clone(), mint.clone(), pda_account.clone(), owner.clone(), token_program.clone()],
+        )
+    }
+
+    /// Revokes the delegation of the initializer's token account if the PDA still holds it, as its owner when the
+    /// initializer signs, otherwise as the delegate itself, which only Token-2022 allows. A closed token account has
+    /// nothing left to revoke.
+    ///
+    /// An SPL Token approval left behind is never used again: the escrow authority only moves tokens for a live
+    /// escrow out of the accounts it stores, and an escrow opened again at the same address approves every leg again.
+    fn revoke_delegate<'a>(
+        token_program: &AccountInfo<'a>,
+        token_account: &AccountInfo<'a>,
+        initializer: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        if token_account.owner != token_program.key || token_account.data_is_empty() {
+            return Ok(());
+        }
+        let delegate = StateWithExtensions::<TokenAccount>::unpack(&token_account.try_borrow_data()?)?.base.delegate;
+        if delegate != COption::Some(*pda_account.key) {
+            return Ok(());
+        }
+        if initializer.is_signer {
+            let revoke_ix =
+                spl_token_2022::instruction::revoke(token_program.key, token_account.key, initializer.key, &[])?;
+            msg!("Calling the token program to revoke the escrow authority on {}...", token_account.key);
+            return invoke(&revoke_ix, &[token_account.clone(), initializer.clone(), token_program.clone()]);
+        }
+        if *token_program.key != spl_token_2022::id() {
+            msg!("the initializer has to revoke the escrow authority on {}", token_account.key);
+            return Ok(());
+        }
+        let revoke_ix =
+            spl_token_2022::instruction::revoke(token_program.key, token_account.key, pda_account.key, &[])?;
+        msg!("Calling the token program to revoke the escrow authority on {} as delegate...", token_account.key);
+        invoke_signed(&revoke_ix, &[token_account.clone(), pda_account.clone(), token_program.clone()], signer_seeds)
+    }
+
+    /// Creates the associated token account of `wallet` for a leg's mint when the receiving account does not exist yet,
+    /// `payer` pays its rent. An existing account is left to the caller, who checks it belongs to `wallet`.
+    fn create_associated_token_account<'a>(
+        payer: &AccountInfo<'a>,
+        associated_token_account: &AccountInfo<'a>,
+        wallet: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        associated_token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        if !associated_token_account.data_is_empty() {
+            return Ok(());
+        }
+        if *associated_token_account.key
+            != get_associated_token_address_with_program_id(wallet.key, mint.key, token_program.key)
+        {
+            msg!(
+                "token account {} to create is not the associated token account of {} !",
+                associated_token_account.key,
+                wallet.key
+            );
+            return Err(EscrowError::InvalidAssociatedTokenAccount.into());
+        }
+        let create_ata_ix = create_associated_token_account_idempotent(payer.key, wallet.key, mint.key, token_program.key);
+        msg!("Calling the associated token account program to create {}...", associated_token_account.key);
+        invoke(
+            &create_ata_ix,
+            &[
+                payer.clone(),
+                associated_token_account.clone(),
+                wallet.clone(),
+                mint.clone(),
+                system_program_account.clone(),
+                token_program.clone(),
+                associated_token_program.clone(),
+            ],
+        )
+    }
+
+    /// Creates a vault token account at its PDA and initializes it for `mint`, owned by the escrow authority
+    #[allow(clippy::too_many_arguments)]
+    fn create_vault<'a>(
+        initializer: &AccountInfo<'a>,
+        temp_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        rent_account: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        pda: &Pubkey,
+        vault_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let rent = Rent::from_account_info(rent_account)?;
+        let vault_len = {
+            let mint_data = mint.try_borrow_data()?;
+            let mint_extensions = StateWithExtensions::<Mint>::unpack(&mint_data)?.get_extension_types()?;
+            ExtensionType::try_calculate_account_len::<TokenAccount>(
+                &ExtensionType::get_required_init_account_extensions(&mint_extensions),
+            )?
+        };
+        Self::create_pda_account(
+            initializer,
+            temp_token_account,
+            system_program_account,
+            rent.minimum_balance(vault_len),
+            vault_len,
+            token_program.key,
+            vault_seeds,
+        )?;
+
+        let init_vault_ix = spl_token_2022::instruction::initialize_account2(
+            token_program.key,
+            temp_token_account.key,
+            mint.key,
+            pda,
+        )?;
+        msg!("Calling the token program to initialize the vault owned by the pda...");
+        invoke(
+            &init_vault_ix,
+            &[
+                temp_token_account.clone(),
+                mint.clone(),
+                rent_account.clone(),
+                token_program.clone(),
+            ],
+        )
+    }
+
+    /// Creates a rent exempt account at a PDA of this program, also when someone already sent lamports to the address
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        system_program_account: &AccountInfo<'a>,
+        required_lamports: u64,
+        space: usize,
+        owner: &Pubkey,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let current_lamports = new_account.lamports();
+        if current_lamports == 0 {
+            msg!("Calling the system program to create {}...", new_account.key);
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    new_account.key,
+                    required_lamports,
+                    space as u64,
+                    owner,
+                ),
+                &[payer.clone(), new_account.clone(), system_program_account.clone()],
+                &[signer_seeds],
+            );
+        }
+
+        let missing_lamports = required_lamports.saturating_sub(current_lamports);
+        if missing_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, new_account.key, missing_lamports),
+                &[payer.clone(), new_account.clone(), system_program_account.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(new_account.key, space as u64),
+            &[new_account.clone(), system_program_account.clone()],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(new_account.key, owner),
+            &[new_account.clone(), system_program_account.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    /// Zeroes the escrow account's data, sends its lamports to the rent destination and hands it back to the system
+    /// program, so a later instruction of the same transaction cannot read the closed escrow or revive it
+    fn close_escrow_account(rent_destination_account: &AccountInfo, escrow_account: &AccountInfo) -> ProgramResult {
+        msg!("Closing the escrow account...");
+        escrow_account.try_borrow_mut_data()?.fill(0);
+        **rent_destination_account.try_borrow_mut_lamports()? = rent_destination_account
+            .lamports()
             .checked_add(escrow_account.lamports())
             .ok_or(EscrowError::AmountOverflow)?;
         **escrow_account.try_borrow_mut_lamports()? = 0;
-        *escrow_account.try_borrow_mut_data()? = &mut [];
+        escrow_account.realloc(0, false)?;
+        escrow_account.assign(&system_program::id());
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escrow(x_amounts: &[u64], y_amounts: &[u64], lamports: u64, sol_dir: u8) -> EscrowState {
+        let mut escrow_info = EscrowState {
+            is_initialized: true,
+            amount_x: x_amounts.len() as u8,
+            amount_y: y_amounts.len() as u8,
+            sol_dir,
+            lamports,
+            ..EscrowState::default()
+        };
+        for (leg, &amount) in escrow_info.x_legs.iter_mut().zip(x_amounts) {
+            leg.amount = amount;
+        }
+        for (leg, &amount) in escrow_info.y_legs.iter_mut().zip(y_amounts) {
+            leg.amount = amount;
+        }
+        escrow_info
+    }
+
+    fn x_amounts(escrow_info: &EscrowState) -> Vec<u64> {
+        escrow_info.x_legs().iter().map(|leg| leg.amount).collect()
+    }
+
+    fn y_amounts(escrow_info: &EscrowState) -> Vec<u64> {
+        escrow_info.y_legs().iter().map(|leg| leg.amount).collect()
+    }
+
+    fn mint_data(supply: u64, decimals: u8) -> Vec<u8> {
+        let mut data = vec![0; Mint::LEN];
+        Mint { supply, decimals, is_initialized: true, ..Mint::default() }.pack_into_slice(&mut data);
+        data
+    }
+
+    #[test]
+    fn fill_terms_rounds_in_favour_of_the_initializer() {
+        let escrow_info = escrow(&[1_000, 7], &[10, 3], 1_001, 2);
+
+        let remaining_info = Processor::fill_terms(&escrow_info, FILL_DENOMINATOR / 2).unwrap();
+
+        assert_eq!(x_amounts(&remaining_info), [500, 4]);
+        assert_eq!(y_amounts(&remaining_info), [5, 1]);
+        assert_eq!(remaining_info.lamports, 500);
+    }
+
+    #[test]
+    fn fill_terms_settles_everything_on_a_full_fill() {
+        let escrow_info = escrow(&[1], &[1], 10, 1);
+
+        let remaining_info = Processor::fill_terms(&escrow_info, FILL_DENOMINATOR).unwrap();
+
+        assert!(remaining_info.is_exhausted());
+    }
+
+    #[test]
+    fn fill_terms_rejects_out_of_range_numerators() {
+        let escrow_info = escrow(&[100], &[100], 0, 0);
+
+        for fill_numerator in [0, FILL_DENOMINATOR + 1] {
+            assert_eq!(
+                Processor::fill_terms(&escrow_info, fill_numerator),
+                Err(EscrowError::InvalidFill.into())
+            );
+        }
+    }
+
+    #[test]
+    fn fill_terms_rejects_partial_fills_of_a_single_token() {
+        let single_x = escrow(&[1, 100], &[100], 0, 0);
+        let single_y = escrow(&[100], &[1], 0, 0);
+
+        assert_eq!(Processor::fill_terms(&single_x, FILL_DENOMINATOR / 2), Err(EscrowError::InvalidFill.into()));
+        assert_eq!(Processor::fill_terms(&single_y, FILL_DENOMINATOR / 2), Err(EscrowError::InvalidFill.into()));
+    }
+
+    #[test]
+    fn fill_terms_rejects_dust_fills_giving_nothing_of_an_x_leg() {
+        let escrow_info = escrow(&[1_000, 1_000_000], &[1_000_000], 0, 0);
+
+        assert_eq!(Processor::fill_terms(&escrow_info, 1_000), Err(EscrowError::InvalidFill.into()));
+        assert!(Processor::fill_terms(&escrow_info, 1_000_000).is_ok());
+    }
+
+    #[test]
+    fn fill_terms_rejects_partial_fills_using_up_what_the_taker_pays() {
+        let escrow_info = escrow(&[1_000], &[2], 0, 0);
+
+        assert_eq!(Processor::fill_terms(&escrow_info, FILL_DENOMINATOR - 1), Err(EscrowError::InvalidFill.into()));
+    }
+
+    #[test]
+    fn is_nft_needs_a_single_indivisible_token() {
+        let key = Pubkey::new_unique();
+        let owner = spl_token::id();
+        for (supply, decimals, nft) in [(1, 0, true), (2, 0, false), (1, 6, false), (0, 0, false)] {
+            let mut lamports = 0;
+            let mut data = mint_data(supply, decimals);
+            let mint = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+
+            assert_eq!(Processor::is_nft(&mint), Ok(nft), "supply {} decimals {}", supply, decimals);
+        }
+    }
+}
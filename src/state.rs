@@ -0,0 +1,582 @@
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+    pubkey::{Pubkey, PUBKEY_BYTES},
+};
+
+use crate::error::EscrowError;
+
+/// Maximum number of token legs on either side of an escrow
+pub const MAX_LEGS: usize = 9;
+
+/// Maximum number of milestones an escrow can be split into
+pub const MAX_MILESTONES: usize = 8;
+
+/// Sum of the milestone shares, in basis points
+pub const MILESTONE_DENOMINATOR: u16 = 10_000;
+
+/// A token leg the initializer sends, held in a vault token account until the escrow settles.
+/// The taker receives it in their associated token account for the mint.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct XLeg {
+    /// The vault token account created by the program, holding the tokens while the escrow is open.
+    /// The initializer's token account itself in a non-custodial escrow.
+    pub temp_token_account: Pubkey,
+    /// The mint of the tokens
+    pub mint: Pubkey,
+    /// The token program owning the mint, SPL Token or Token-2022
+    pub token_program: Pubkey,
+    /// The amount of tokens still held in the temp token account
+    pub amount: u64,
+}
+
+impl Sealed for XLeg {}
+
+impl Pack for XLeg {
+    const LEN: usize = 104;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, XLeg::LEN];
+        let (temp_token_account, mint, token_program, amount) =
+            array_refs![src, PUBKEY_BYTES, PUBKEY_BYTES, PUBKEY_BYTES, 8];
+        Ok(XLeg {
+            temp_token_account: Pubkey::new_from_array(*temp_token_account),
+            mint: Pubkey::new_from_array(*mint),
+            token_program: Pubkey::new_from_array(*token_program),
+            amount: u64::from_le_bytes(*amount),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, XLeg::LEN];
+        let (temp_token_account_dst, mint_dst, token_program_dst, amount_dst) =
+            mut_array_refs![dst, PUBKEY_BYTES, PUBKEY_BYTES, PUBKEY_BYTES, 8];
+        temp_token_account_dst.copy_from_slice(self.temp_token_account.as_ref());
+        mint_dst.copy_from_slice(self.mint.as_ref());
+        token_program_dst.copy_from_slice(self.token_program.as_ref());
+        *amount_dst = self.amount.to_le_bytes();
+    }
+}
+
+/// A token leg the taker sends straight to the initializer when the escrow settles, from the token account the
+/// taker gives at exchange. The initializer receives it in their associated token account for the mint.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct YLeg {
+    /// The mint of the tokens
+    pub mint: Pubkey,
+    /// The token program owning the mint, SPL Token or Token-2022
+    pub token_program: Pubkey,
+    /// The amount of tokens the initializer still expects to receive
+    pub amount: u64,
+}
+
+impl Sealed for YLeg {}
+
+impl Pack for YLeg {
+    const LEN: usize = 72;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, YLeg::LEN];
+        let (mint, token_program, amount) = array_refs![src, PUBKEY_BYTES, PUBKEY_BYTES, 8];
+        Ok(YLeg {
+            mint: Pubkey::new_from_array(*mint),
+            token_program: Pubkey::new_from_array(*token_program),
+            amount: u64::from_le_bytes(*amount),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, YLeg::LEN];
+        let (mint_dst, token_program_dst, amount_dst) = mut_array_refs![dst, PUBKEY_BYTES, PUBKEY_BYTES, 8];
+        mint_dst.copy_from_slice(self.mint.as_ref());
+        token_program_dst.copy_from_slice(self.token_program.as_ref());
+        *amount_dst = self.amount.to_le_bytes();
+    }
+}
+
+/// Escrow data, stored in the escrow account
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EscrowState {
+    /// Is `true` if this structure has been initialized
+    pub is_initialized: bool,
+    /// Number of X legs in use
+    pub amount_x: u8,
+    /// Number of Y legs in use
+    pub amount_y: u8,
+    /// Direction of the SOL leg: 1 initializer -> taker, 2 taker -> initializer
+    pub sol_dir: u8,
+    /// Bump seed of the PDA owning the temp token accounts, found once at init
+    pub bump_seed: u8,
+    /// The amount of lamports still to be moved by the SOL leg
+    pub lamports: u64,
+    /// The initializer of the escrow
+    pub initializer_pubkey: Pubkey,
+    /// The only account allowed to take the escrow, `Pubkey::default()` lets anyone take it
+    pub taker_pubkey: Pubkey,
+    /// Receives the rent of the vaults and of the escrow account when the escrow settles
+    pub rent_destination: Pubkey,
+    /// Unix timestamp after which the escrow can no longer be taken, 0 if it never expires
+    pub expires_at: i64,
+    /// Bumped every time the terms a taker sees change, by an amendment, a partial fill, a dispute or a milestone
+    pub version: u64,
+    /// Is `true` if exchanges pay the Metaplex royalties of the NFT legs out of the SOL leg
+    pub enforce_royalties: bool,
+    /// Settles the escrow once a party raises a dispute, `Pubkey::default()` if the escrow has no arbiter
+    pub arbiter: Pubkey,
+    /// Is `true` once a party raised a dispute, the escrow can then only be resolved by the arbiter
+    pub disputed: bool,
+    /// Hash of the evidence given with the last dispute
+    pub evidence_hash: [u8; 32],
+    /// Number of milestones the X legs and the SOL leg are released in, 0 if the escrow settles by exchange
+    pub milestone_count: u8,
+    /// Number of milestones released so far, they are released in order
+    pub milestones_released: u8,
+    /// Weight of each milestone in basis points, only the first `milestone_count` are in use.
+    /// A milestone releases its share of what the milestones left to release still hold.
+    pub milestone_shares: [u16; MAX_MILESTONES],
+    /// SHA-256 hash of the secret the taker reveals to claim the escrow, all zeros if the escrow settles by exchange.
+    /// The expiry is then the timelock after which the initializer can refund.
+    pub hashlock: [u8; 32],
+    /// Is `true` if the X legs stay in the initializer's token accounts, which delegate them to the escrow authority
+    pub non_custodial: bool,
+    /// Token legs sent by the initializer, only the first `amount_x` are in use
+    pub x_legs: [XLeg; MAX_LEGS],
+    /// Token legs sent by the taker, only the first `amount_y` are in use
+    pub y_legs: [YLeg; MAX_LEGS],
+}
+
+impl EscrowState {
+    /// The X legs in use
+    pub fn x_legs(&self) -> &[XLeg] {
+        &self.x_legs[..self.amount_x as usize]
+    }
+
+    /// The Y legs in use
+    pub fn y_legs(&self) -> &[YLeg] {
+        &self.y_legs[..self.amount_y as usize]
+    }
+
+    /// Is `true` if any signer may take the escrow
+    pub fn is_open(&self) -> bool {
+        self.taker_pubkey == Pubkey::default()
+    }
+
+    /// Is `true` if the escrow names an arbiter
+    pub fn has_arbiter(&self) -> bool {
+        self.arbiter != Pubkey::default()
+    }
+
+    /// Is `true` if the escrow releases its X legs and SOL leg by milestone
+    pub fn is_milestone(&self) -> bool {
+        self.milestone_count > 0
+    }
+
+    /// The part of `amount` still held that the next milestone releases, everything on the last one
+    pub fn milestone_tranche(&self, amount: u64) -> u64 {
+        let shares = &self.milestone_shares[self.milestones_released as usize..self.milestone_count as usize];
+        let remaining_shares: u128 = shares.iter().map(|&share| share as u128).sum();
+        match shares.first() {
+            Some(&share) if remaining_shares > 0 => (amount as u128 * share as u128 / remaining_shares) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Is `true` if the escrow is claimed with a secret instead of exchanged
+    pub fn is_hashlocked(&self) -> bool {
+        self.hashlock != [0; 32]
+    }
+
+    /// Moves the escrow to its next version so exchanges built against the current one fail
+    pub fn bump_version(&mut self) -> Result<(), ProgramError> {
+        self.version = self.version.checked_add(1).ok_or(EscrowError::AmountOverflow)?;
+        Ok(())
+    }
+
+    /// Is `true` if the escrow has an expiry and `now` is past it
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && now >= self.expires_at
+    }
+
+    /// Is `true` once every leg and the SOL leg have been fully settled
+    pub fn is_exhausted(&self) -> bool {
+        self.lamports == 0
+            && self.x_legs().iter().all(|leg| leg.amount == 0)
+            && self.y_legs().iter().all(|leg| leg.amount == 0)
+    }
+
+    /// Size of an escrow account holding `amount_x` X legs and `amount_y` Y legs
+    pub fn packed_len(amount_x: u8, amount_y: u8) -> usize {
+        HEADER_LEN + XLeg::LEN * amount_x as usize + YLeg::LEN * amount_y as usize
+    }
+
+    /// Unpacks an escrow account sized by `packed_len`, failing if it is not initialized
+    pub fn unpack_account(src: &[u8]) -> Result<Self, ProgramError> {
+        let escrow_info = Self::unpack_from_slice(src)?;
+        if !escrow_info.is_initialized() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(escrow_info)
+    }
+
+    /// Packs into an escrow account sized by `packed_len`
+    pub fn pack_account(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() < Self::packed_len(self.amount_x, self.amount_y) {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        self.pack_into_slice(dst);
+        Ok(())
+    }
+}
+
+impl Sealed for EscrowState {}
+
+impl IsInitialized for EscrowState {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+const HEADER_LEN: usize = 242;
+
+/// `LEN` is the size of an escrow with every leg in use, the legs actually stored follow the counts in the header
+impl Pack for EscrowState {
+    const LEN: usize = HEADER_LEN + XLeg::LEN * MAX_LEGS + YLeg::LEN * MAX_LEGS;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < HEADER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (header, legs_src) = src.split_at(HEADER_LEN);
+        let header = array_ref![header, 0, HEADER_LEN];
+        let (
+            is_initialized,
+            amount_x,
+            amount_y,
+            sol_dir,
+            bump_seed,
+            lamports,
+            initializer_pubkey,
+            taker_pubkey,
+            rent_destination,
+            expires_at,
+            version,
+            enforce_royalties,
+            arbiter,
+            disputed,
+            evidence_hash,
+            milestone_count,
+            milestones_released,
+            milestone_shares_src,
+            hashlock,
+            non_custodial,
+        ) = array_refs![
+            header, 1, 1, 1, 1, 1, 8, PUBKEY_BYTES, PUBKEY_BYTES, PUBKEY_BYTES, 8, 8, 1, PUBKEY_BYTES, 1, 32, 1, 1,
+            2 * MAX_MILESTONES, 32, 1
+        ];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let enforce_royalties = match enforce_royalties {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let disputed = match disputed {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let non_custodial = match non_custodial {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let (milestone_count, milestones_released) = (milestone_count[0], milestones_released[0]);
+        if milestone_count as usize > MAX_MILESTONES || milestones_released > milestone_count {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut milestone_shares = [0; MAX_MILESTONES];
+        for (share, share_src) in milestone_shares.iter_mut().zip(milestone_shares_src.chunks_exact(2)) {
+            *share = u16::from_le_bytes([share_src[0], share_src[1]]);
+        }
+        let amount_x = amount_x[0];
+        let amount_y = amount_y[0];
+        if amount_x as usize > MAX_LEGS
+            || amount_y as usize > MAX_LEGS
+            || src.len() < Self::packed_len(amount_x, amount_y)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let (x_legs_src, y_legs_src) = legs_src.split_at(XLeg::LEN * amount_x as usize);
+        let mut x_legs = [XLeg::default(); MAX_LEGS];
+        for (leg, leg_src) in x_legs.iter_mut().zip(x_legs_src.chunks_exact(XLeg::LEN)) {
+            *leg = XLeg::unpack_from_slice(leg_src)?;
+        }
+        let mut y_legs = [YLeg::default(); MAX_LEGS];
+        for (leg, leg_src) in y_legs
+            .iter_mut()
+            .zip(y_legs_src.chunks_exact(YLeg::LEN))
+            .take(amount_y as usize)
+        {
+            *leg = YLeg::unpack_from_slice(leg_src)?;
+        }
+
+        Ok(EscrowState {
+            is_initialized,
+            amount_x,
+            amount_y,
+            sol_dir: sol_dir[0],
+            bump_seed: bump_seed[0],
+            lamports: u64::from_le_bytes(*lamports),
+            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
+            taker_pubkey: Pubkey::new_from_array(*taker_pubkey),
+            rent_destination: Pubkey::new_from_array(*rent_destination),
+            expires_at: i64::from_le_bytes(*expires_at),
+            version: u64::from_le_bytes(*version),
+            enforce_royalties,
+            arbiter: Pubkey::new_from_array(*arbiter),
+            disputed,
+            evidence_hash: *evidence_hash,
+            milestone_count,
+            milestones_released,
+            milestone_shares,
+            hashlock: *hashlock,
+            non_custodial,
+            x_legs,
+            y_legs,
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let (header, legs_dst) = dst.split_at_mut(HEADER_LEN);
+        let header = array_mut_ref![header, 0, HEADER_LEN];
+        let (
+            is_initialized_dst,
+            amount_x_dst,
+            amount_y_dst,
+            sol_dir_dst,
+            bump_seed_dst,
+            lamports_dst,
+            initializer_pubkey_dst,
+            taker_pubkey_dst,
+            rent_destination_dst,
+            expires_at_dst,
+            version_dst,
+            enforce_royalties_dst,
+            arbiter_dst,
+            disputed_dst,
+            evidence_hash_dst,
+            milestone_count_dst,
+            milestones_released_dst,
+            milestone_shares_dst,
+            hashlock_dst,
+            non_custodial_dst,
+        ) = mut_array_refs![
+            header, 1, 1, 1, 1, 1, 8, PUBKEY_BYTES, PUBKEY_BYTES, PUBKEY_BYTES, 8, 8, 1, PUBKEY_BYTES, 1, 32, 1, 1,
+            2 * MAX_MILESTONES, 32, 1
+        ];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        amount_x_dst[0] = self.amount_x;
+        amount_y_dst[0] = self.amount_y;
+        sol_dir_dst[0] = self.sol_dir;
+        bump_seed_dst[0] = self.bump_seed;
+        *lamports_dst = self.lamports.to_le_bytes();
+        initializer_pubkey_dst.copy_from_slice(self.initializer_pubkey.as_ref());
+        taker_pubkey_dst.copy_from_slice(self.taker_pubkey.as_ref());
+        rent_destination_dst.copy_from_slice(self.rent_destination.as_ref());
+        *expires_at_dst = self.expires_at.to_le_bytes();
+        *version_dst = self.version.to_le_bytes();
+        enforce_royalties_dst[0] = self.enforce_royalties as u8;
+        arbiter_dst.copy_from_slice(self.arbiter.as_ref());
+        disputed_dst[0] = self.disputed as u8;
+        *evidence_hash_dst = self.evidence_hash;
+        milestone_count_dst[0] = self.milestone_count;
+        milestones_released_dst[0] = self.milestones_released;
+        for (share, share_dst) in self.milestone_shares.iter().zip(milestone_shares_dst.chunks_exact_mut(2)) {
+            share_dst.copy_from_slice(&share.to_le_bytes());
+        }
+        *hashlock_dst = self.hashlock;
+        non_custodial_dst[0] = self.non_custodial as u8;
+
+        let (x_legs_dst, y_legs_dst) = legs_dst.split_at_mut(XLeg::LEN * self.amount_x as usize);
+        for (leg, leg_dst) in self.x_legs().iter().zip(x_legs_dst.chunks_exact_mut(XLeg::LEN)) {
+            leg.pack_into_slice(leg_dst);
+        }
+        for (leg, leg_dst) in self.y_legs().iter().zip(y_legs_dst.chunks_exact_mut(YLeg::LEN)) {
+            leg.pack_into_slice(leg_dst);
+        }
+    }
+}
+
+/// Highest fee the config can charge, in basis points
+pub const MAX_FEE_BPS: u16 = 500;
+
+/// Maximum number of accounts on the fee exempt list
+pub const MAX_FEE_EXEMPT: usize = 16;
+
+/// Protocol fee settings, stored in the config account at `find_config_address`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Config {
+    /// Is `true` if this structure has been initialized
+    pub is_initialized: bool,
+    /// Bump seed of the config address, found once at init
+    pub bump_seed: u8,
+    /// The only account allowed to update the config
+    pub admin: Pubkey,
+    /// Fee taken from what each party receives, in basis points
+    pub fee_bps: u16,
+    /// Receives the fees, in SOL and in its token accounts
+    pub treasury: Pubkey,
+    /// Number of accounts in use on the fee exempt list
+    pub fee_exempt_count: u8,
+    /// Accounts whose trades pay no fee, only the first `fee_exempt_count` are in use
+    pub fee_exempt: [Pubkey; MAX_FEE_EXEMPT],
+}
+
+impl Config {
+    /// The accounts on the fee exempt list
+    pub fn fee_exempt(&self) -> &[Pubkey] {
+        &self.fee_exempt[..self.fee_exempt_count as usize]
+    }
+
+    /// The fee taken from `amount`, rounded down
+    pub fn fee(&self, amount: u64) -> u64 {
+        (amount as u128 * self.fee_bps as u128 / 10_000) as u64
+    }
+}
+
+impl Sealed for Config {}
+
+impl IsInitialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Config {
+    const LEN: usize = 1 + 1 + PUBKEY_BYTES + 2 + PUBKEY_BYTES + 1 + PUBKEY_BYTES * MAX_FEE_EXEMPT;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, Config::LEN];
+        let (is_initialized, bump_seed, admin, fee_bps, treasury, fee_exempt_count, fee_exempt_src) =
+            array_refs![src, 1, 1, PUBKEY_BYTES, 2, PUBKEY_BYTES, 1, PUBKEY_BYTES * MAX_FEE_EXEMPT];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let fee_exempt_count = fee_exempt_count[0];
+        if fee_exempt_count as usize > MAX_FEE_EXEMPT {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut fee_exempt = [Pubkey::default(); MAX_FEE_EXEMPT];
+        for (key, key_src) in fee_exempt.iter_mut().zip(fee_exempt_src.chunks_exact(PUBKEY_BYTES)) {
+            *key = Pubkey::try_from(key_src).map_err(|_| ProgramError::InvalidAccountData)?;
+        }
+        Ok(Config {
+            is_initialized,
+            bump_seed: bump_seed[0],
+            admin: Pubkey::new_from_array(*admin),
+            fee_bps: u16::from_le_bytes(*fee_bps),
+            treasury: Pubkey::new_from_array(*treasury),
+            fee_exempt_count,
+            fee_exempt,
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Config::LEN];
+        let (
+            is_initialized_dst,
+            bump_seed_dst,
+            admin_dst,
+            fee_bps_dst,
+            treasury_dst,
+            fee_exempt_count_dst,
+            fee_exempt_dst,
+        ) = mut_array_refs![dst, 1, 1, PUBKEY_BYTES, 2, PUBKEY_BYTES, 1, PUBKEY_BYTES * MAX_FEE_EXEMPT];
+        is_initialized_dst[0] = self.is_initialized as u8;
+        bump_seed_dst[0] = self.bump_seed;
+        admin_dst.copy_from_slice(self.admin.as_ref());
+        *fee_bps_dst = self.fee_bps.to_le_bytes();
+        treasury_dst.copy_from_slice(self.treasury.as_ref());
+        fee_exempt_count_dst[0] = self.fee_exempt_count;
+        for (key, key_dst) in self.fee_exempt.iter().zip(fee_exempt_dst.chunks_exact_mut(PUBKEY_BYTES)) {
+            key_dst.copy_from_slice(key.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn milestone_escrow(shares: &[u16]) -> EscrowState {
+        let mut escrow_info = EscrowState { milestone_count: shares.len() as u8, ..EscrowState::default() };
+        escrow_info.milestone_shares[..shares.len()].copy_from_slice(shares);
+        escrow_info
+    }
+
+    /// Releases every milestone of `amount` in order and returns the tranches
+    fn release_all(escrow_info: &mut EscrowState, mut amount: u64) -> Vec<u64> {
+        let mut tranches = Vec::new();
+        while escrow_info.milestones_released < escrow_info.milestone_count {
+            let tranche = escrow_info.milestone_tranche(amount);
+            amount -= tranche;
+            tranches.push(tranche);
+            escrow_info.milestones_released += 1;
+        }
+        tranches
+    }
+
+    #[test]
+    fn milestone_tranche_releases_each_share_of_what_is_left() {
+        let mut escrow_info = milestone_escrow(&[2_500, 2_500, 5_000]);
+
+        assert_eq!(release_all(&mut escrow_info, 1_000), [250, 250, 500]);
+    }
+
+    #[test]
+    fn milestone_tranche_releases_the_rounding_left_on_the_last_milestone() {
+        let mut escrow_info = milestone_escrow(&[3_333, 3_333, 3_334]);
+
+        let tranches = release_all(&mut escrow_info, 10);
+
+        assert_eq!(tranches, [3, 3, 4]);
+        assert_eq!(tranches.iter().sum::<u64>(), 10);
+    }
+
+    #[test]
+    fn milestone_tranche_is_zero_once_everything_is_released() {
+        let mut escrow_info = milestone_escrow(&[MILESTONE_DENOMINATOR]);
+        assert_eq!(escrow_info.milestone_tranche(u64::MAX), u64::MAX);
+
+        escrow_info.milestones_released = 1;
+        assert_eq!(escrow_info.milestone_tranche(u64::MAX), 0);
+        assert_eq!(EscrowState::default().milestone_tranche(100), 0);
+    }
+
+    #[test]
+    fn config_pack_round_trips_with_its_bump_seed() {
+        let mut config = Config {
+            is_initialized: true,
+            bump_seed: 254,
+            admin: Pubkey::new_unique(),
+            fee_bps: MAX_FEE_BPS,
+            treasury: Pubkey::new_unique(),
+            fee_exempt_count: 1,
+            ..Config::default()
+        };
+        config.fee_exempt[0] = Pubkey::new_unique();
+        let mut data = vec![0; Config::LEN];
+
+        Config::pack(config, &mut data).unwrap();
+
+        assert_eq!(data[1], 254);
+        assert_eq!(Config::unpack(&data), Ok(config));
+    }
+}
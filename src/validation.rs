@@ -0,0 +1,165 @@
+use solana_program::{
+    account_info::AccountInfo, bpf_loader_upgradeable, entrypoint::ProgramResult, msg,
+    program_error::ProgramError, program_option::COption, pubkey::Pubkey, system_program,
+};
+use spl_token_2022::{
+    extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions},
+    state::{Account as TokenAccount, Mint},
+};
+
+use crate::error::EscrowError;
+
+/// Checks that an escrow account is owned by this program, so its data was written by it
+pub fn check_escrow_owner(escrow_account: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+    if escrow_account.owner != program_id {
+        msg!("escrow account {} is not owned by the program !", escrow_account.key);
+        return Err(EscrowError::InvalidEscrowOwner.into());
+    }
+    Ok(())
+}
+
+/// Checks that a leg's token program is SPL Token or Token-2022 and owns the leg's mint
+pub fn check_leg_token_program(token_program: &AccountInfo, mint: &AccountInfo) -> ProgramResult {
+    if *token_program.key != spl_token::id() && *token_program.key != spl_token_2022::id() {
+        msg!("token program {} is neither SPL Token nor Token-2022 !", token_program.key);
+        return Err(EscrowError::InvalidTokenProgram.into());
+    }
+    if mint.owner != token_program.key {
+        msg!("mint {} is not owned by the token program {} !", mint.key, token_program.key);
+        return Err(EscrowError::InvalidTokenProgram.into());
+    }
+    Ok(())
+}
+
+/// Token-2022 mint extensions a leg can carry, the others let the mint block, redirect or claw back the vault's tokens
+const SUPPORTED_MINT_EXTENSIONS: [ExtensionType; 9] = [
+    ExtensionType::TransferFeeConfig,
+    ExtensionType::MintCloseAuthority,
+    ExtensionType::InterestBearingConfig,
+    ExtensionType::MetadataPointer,
+    ExtensionType::TokenMetadata,
+    ExtensionType::GroupPointer,
+    ExtensionType::GroupMemberPointer,
+    ExtensionType::ConfidentialTransferMint,
+    ExtensionType::ConfidentialTransferFeeConfig,
+];
+
+/// Checks that a Token-2022 mint only has extensions the escrow can settle, such as no transfer hook,
+/// permanent delegate, default frozen state or non-transferable tokens
+pub fn check_mint_extensions(mint: &AccountInfo) -> ProgramResult {
+    if *mint.owner != spl_token_2022::id() {
+        return Ok(());
+    }
+    let mint_data = mint.try_borrow_data()?;
+    let mint_info = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+    for extension in mint_info.get_extension_types()? {
+        if !SUPPORTED_MINT_EXTENSIONS.contains(&extension) {
+            msg!("mint {} has the unsupported extension {:?} !", mint.key, extension);
+            return Err(EscrowError::UnsupportedMintExtension.into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the associated token account program account is really that program
+pub fn check_associated_token_program(associated_token_program: &AccountInfo) -> ProgramResult {
+    if !spl_associated_token_account::check_id(associated_token_program.key) {
+        msg!("program {} is not the associated token account program !", associated_token_program.key);
+        return Err(EscrowError::InvalidAssociatedTokenProgram.into());
+    }
+    Ok(())
+}
+
+/// Checks that the system program account is really the system program
+pub fn check_system_program(system_program_account: &AccountInfo) -> ProgramResult {
+    if !system_program::check_id(system_program_account.key) {
+        msg!("system program {} is not the system program !", system_program_account.key);
+        return Err(EscrowError::InvalidSystemProgram.into());
+    }
+    Ok(())
+}
+
+/// Checks that an account the instruction writes to was passed as writable
+pub fn check_writable(account: &AccountInfo) -> ProgramResult {
+    if !account.is_writable {
+        msg!("account {} is not writable !", account.key);
+        return Err(EscrowError::AccountNotWritable.into());
+    }
+    Ok(())
+}
+
+/// Checks that a token account belongs to SPL Token or Token-2022, holds `mint` and is owned by `owner`
+pub fn check_token_account(token_account: &AccountInfo, mint: &Pubkey, owner: &Pubkey) -> ProgramResult {
+    if *token_account.owner != spl_token::id() && *token_account.owner != spl_token_2022::id() {
+        msg!("token account {} is not owned by the token program !", token_account.key);
+        return Err(EscrowError::InvalidTokenAccountOwner.into());
+    }
+    let token_info = StateWithExtensions::<TokenAccount>::unpack(&token_account.try_borrow_data()?)?.base;
+    if token_info.mint != *mint {
+        msg!("token account {} does not hold mint {} !", token_account.key, mint);
+        return Err(EscrowError::InvalidTokenMint.into());
+    }
+    if token_info.owner != *owner {
+        msg!("token account {} is not owned by {} !", token_account.key, owner);
+        return Err(EscrowError::InvalidTokenOwner.into());
+    }
+    Ok(())
+}
+
+/// Checks that a token account still delegates at least `amount` to `delegate` and holds it
+pub fn check_delegation(token_account: &AccountInfo, delegate: &Pubkey, amount: u64) -> ProgramResult {
+    let token_info = StateWithExtensions::<TokenAccount>::unpack(&token_account.try_borrow_data()?)?.base;
+    if token_info.delegate != COption::Some(*delegate) || token_info.delegated_amount < amount {
+        msg!("token account {} does not delegate {} tokens to {} !", token_account.key, amount, delegate);
+        return Err(EscrowError::InsufficientDelegation.into());
+    }
+    if token_info.amount < amount {
+        msg!("token account {} holds {} tokens, less than {} !", token_account.key, token_info.amount, amount);
+        return Err(EscrowError::InsufficientDelegation.into());
+    }
+    Ok(())
+}
+
+/// Checks that a token account delegates no tokens to anyone but `delegate`, approving `delegate` would replace it
+pub fn check_not_delegated(token_account: &AccountInfo, delegate: &Pubkey) -> ProgramResult {
+    let token_info = StateWithExtensions::<TokenAccount>::unpack(&token_account.try_borrow_data()?)?.base;
+    if let COption::Some(other) = token_info.delegate {
+        if other != *delegate && token_info.delegated_amount > 0 {
+            msg!(
+                "token account {} already delegates {} tokens to {} !",
+                token_account.key,
+                token_info.delegated_amount,
+                other
+            );
+            return Err(EscrowError::TokenAccountDelegated.into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `authority` signed and is the upgrade authority recorded in the program data account of this program
+pub fn check_upgrade_authority(
+    program_id: &Pubkey,
+    program_data_account: &AccountInfo,
+    authority: &AccountInfo,
+) -> ProgramResult {
+    let (program_data_address, _bump_seed) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data_account.key != program_data_address
+        || *program_data_account.owner != bpf_loader_upgradeable::id()
+    {
+        msg!("program data account {} is not the one of this program !", program_data_account.key);
+        return Err(EscrowError::InvalidProgramData.into());
+    }
+
+    // bincode layout of `UpgradeableLoaderState::ProgramData`: u32 variant 3, u64 slot, option tag, authority
+    let data = program_data_account.try_borrow_data()?;
+    if data.len() < 45 || data[..4] != 3u32.to_le_bytes() || data[12] != 1 || data[13..45] != authority.key.to_bytes() {
+        msg!("{} is not the upgrade authority of the program !", authority.key);
+        return Err(EscrowError::InvalidAdmin.into());
+    }
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
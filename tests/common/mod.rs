@@ -0,0 +1,572 @@
+//! In-process ledger running the escrow program together with the programs it invokes: the system program, SPL
+//! Token, Token-2022 and the associated token account program. Cross-program invocations go through the syscall
+//! stubs of `solana_program`, account data is laid out the way the runtime serializes it so `realloc` works, and a
+//! transaction's changes are dropped when one of its instructions fails. Precompiles are not run, an Ed25519
+//! instruction is taken as verified.
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem::size_of,
+    slice,
+    sync::Once,
+};
+
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    ed25519_program,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::Pack,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    program_utils::limited_deserialize,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::{SystemError, SystemInstruction},
+    system_program,
+    sysvar::{
+        self,
+        instructions::{construct_instructions_data, store_current_index, BorrowedAccountMeta, BorrowedInstruction},
+    },
+};
+use spl_token_2022::{
+    extension::{
+        transfer_fee::instruction::initialize_transfer_fee_config, BaseStateWithExtensions, ExtensionType,
+        StateWithExtensions,
+    },
+    state::{Account as TokenAccount, Mint},
+};
+
+use solana_escrow::processor::Processor;
+
+/// Lamports every wallet of a test starts with
+pub const WALLET_LAMPORTS: u64 = 100_000_000_000;
+
+thread_local! {
+    static PROGRAM_ID: RefCell<Pubkey> = RefCell::new(Pubkey::default());
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+    static CALLERS: RefCell<Vec<Pubkey>> = const { RefCell::new(Vec::new()) };
+    static RETURN_DATA: RefCell<Option<(Pubkey, Vec<u8>)>> = const { RefCell::new(None) };
+    static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The key of an account preceded by its original data length, where `AccountInfo::realloc` reads it
+#[repr(C)]
+struct KeySlot {
+    original_data_len: u32,
+    key: Pubkey,
+}
+
+/// An account as the runtime hands it to programs, its data preceded by its length and followed by the room
+/// `realloc` may grow into
+struct StoredAccount {
+    info: AccountInfo<'static>,
+    data: *mut u8,
+}
+
+struct Snapshot {
+    lamports: u64,
+    data: Vec<u8>,
+    owner: Pubkey,
+}
+
+struct Stubs;
+
+impl SyscallStubs for Stubs {
+    fn sol_log(&self, message: &str) {
+        LOGS.with(|logs| logs.borrow_mut().push(message.to_string()));
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        LOGS.with(|logs| logs.borrow_mut().push(format!("Program data: {:?}", fields)));
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let caller = CALLERS.with(|callers| *callers.borrow().last().expect("invoked outside of a program"));
+        let signers = signers_seeds
+            .iter()
+            .map(|seeds| Pubkey::create_program_address(seeds, &caller))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ProgramError::InvalidSeeds)?;
+
+        let mut accounts = Vec::with_capacity(instruction.accounts.len());
+        for meta in &instruction.accounts {
+            let mut account = account_infos
+                .iter()
+                .find(|account| *account.key == meta.pubkey)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?
+                .clone();
+            // like the runtime, an account listed several times gets the privileges of all its entries
+            let (is_signer, is_writable) = privileges(&instruction.accounts, &meta.pubkey);
+            if is_signer && !account.is_signer && !signers.contains(&meta.pubkey) {
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            if is_writable && !account.is_writable {
+                return Err(ProgramError::InvalidArgument);
+            }
+            account.is_signer = is_signer;
+            account.is_writable = is_writable;
+            accounts.push(account);
+        }
+        dispatch(&instruction.program_id, &accounts, &instruction.data)
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Clock) = CLOCK.with(|clock| clock.borrow().clone()) };
+        solana_program::entrypoint::SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        solana_program::entrypoint::SUCCESS
+    }
+
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        RETURN_DATA.with(|return_data| return_data.borrow().clone())
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        let program_id = CALLERS.with(|callers| *callers.borrow().last().expect("return data outside of a program"));
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = Some((program_id, data.to_vec())));
+    }
+
+    fn sol_get_stack_height(&self) -> u64 {
+        CALLERS.with(|callers| callers.borrow().len() as u64)
+    }
+}
+
+/// Whether any entry of `key` in `metas` signs and whether any is writable
+fn privileges(metas: &[AccountMeta], key: &Pubkey) -> (bool, bool) {
+    metas
+        .iter()
+        .filter(|meta| meta.pubkey == *key)
+        .fold((false, false), |(is_signer, is_writable), meta| {
+            (is_signer || meta.is_signer, is_writable || meta.is_writable)
+        })
+}
+
+/// Runs an instruction of one of the programs the ledger knows
+fn dispatch(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    CALLERS.with(|callers| callers.borrow_mut().push(*program_id));
+    let result = if *program_id == system_program::id() {
+        process_system_instruction(accounts, data)
+    } else if *program_id == spl_token::id() {
+        spl_token::processor::Processor::process(program_id, accounts, data)
+    } else if *program_id == spl_token_2022::id() {
+        spl_token_2022::processor::Processor::process(program_id, accounts, data)
+    } else if *program_id == spl_associated_token_account::id() {
+        spl_associated_token_account::processor::process_instruction(program_id, accounts, data)
+    } else if *program_id == PROGRAM_ID.with(|id| *id.borrow()) {
+        Processor::process(program_id, accounts, data)
+    } else {
+        Err(ProgramError::IncorrectProgramId)
+    };
+    CALLERS.with(|callers| callers.borrow_mut().pop());
+    result
+}
+
+/// The system program instructions the programs under test use
+fn process_system_instruction(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let instruction: SystemInstruction =
+        limited_deserialize(data, 1_232).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let signed = |account: &AccountInfo| {
+        if account.is_signer {
+            Ok(())
+        } else {
+            Err(ProgramError::MissingRequiredSignature)
+        }
+    };
+    let unused = |account: &AccountInfo| {
+        if *account.owner != system_program::id() || !account.data_is_empty() {
+            return Err(ProgramError::Custom(SystemError::AccountAlreadyInUse as u32));
+        }
+        Ok(())
+    };
+    let transfer = |from: &AccountInfo, to: &AccountInfo, lamports: u64| {
+        signed(from)?;
+        if *from.owner != system_program::id() || !from.data_is_empty() {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if from.lamports() < lamports {
+            return Err(ProgramError::Custom(SystemError::ResultWithNegativeLamports as u32));
+        }
+        **from.try_borrow_mut_lamports()? -= lamports;
+        **to.try_borrow_mut_lamports()? += lamports;
+        Ok(())
+    };
+
+    match instruction {
+        SystemInstruction::CreateAccount { lamports, space, owner } => {
+            let (from, to) = (&accounts[0], &accounts[1]);
+            signed(to)?;
+            if to.lamports() > 0 {
+                return Err(ProgramError::Custom(SystemError::AccountAlreadyInUse as u32));
+            }
+            unused(to)?;
+            transfer(from, to, lamports)?;
+            to.realloc(space as usize, true)?;
+            to.assign(&owner);
+            Ok(())
+        }
+        SystemInstruction::Transfer { lamports } => transfer(&accounts[0], &accounts[1], lamports),
+        SystemInstruction::Allocate { space } => {
+            signed(&accounts[0])?;
+            unused(&accounts[0])?;
+            accounts[0].realloc(space as usize, true)
+        }
+        SystemInstruction::Assign { owner } => {
+            signed(&accounts[0])?;
+            if *accounts[0].owner != system_program::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            accounts[0].assign(&owner);
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+/// Accounts of a test and the escrow program deployed among them
+pub struct Ledger {
+    pub program_id: Pubkey,
+    accounts: HashMap<Pubkey, StoredAccount>,
+    payer: Pubkey,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            set_syscall_stubs(Box::new(Stubs));
+        });
+
+        let program_id = Pubkey::new_unique();
+        PROGRAM_ID.with(|id| *id.borrow_mut() = program_id);
+        CLOCK.with(|clock| *clock.borrow_mut() = Clock { epoch: 1, unix_timestamp: 1_700_000_000, ..Clock::default() });
+
+        let mut ledger = Self { program_id, accounts: HashMap::new(), payer: Pubkey::default() };
+        for program in [
+            program_id,
+            system_program::id(),
+            spl_token::id(),
+            spl_token_2022::id(),
+            spl_associated_token_account::id(),
+            ed25519_program::id(),
+        ] {
+            ledger.insert(program, 1, &[], &solana_program::bpf_loader::id(), true);
+        }
+        let rent = Rent::default();
+        let mut rent_data = Vec::with_capacity(size_of::<Rent>());
+        rent_data.extend_from_slice(&rent.lamports_per_byte_year.to_le_bytes());
+        rent_data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+        rent_data.push(rent.burn_percent);
+        ledger.insert(sysvar::rent::id(), 1, &rent_data, &sysvar::id(), false);
+        ledger.payer = ledger.wallet();
+        ledger
+    }
+
+    /// Sets the unix timestamp of the clock
+    pub fn set_time(&mut self, unix_timestamp: i64) {
+        CLOCK.with(|clock| clock.borrow_mut().unix_timestamp = unix_timestamp);
+    }
+
+    pub fn now(&self) -> i64 {
+        CLOCK.with(|clock| clock.borrow().unix_timestamp)
+    }
+
+    /// Creates a system account holding `WALLET_LAMPORTS`
+    pub fn wallet(&mut self) -> Pubkey {
+        let key = Pubkey::new_unique();
+        self.insert(key, WALLET_LAMPORTS, &[], &system_program::id(), false);
+        key
+    }
+
+    /// Stores an account, replacing the one at `key`
+    pub fn set_account(&mut self, key: Pubkey, lamports: u64, data: &[u8], owner: &Pubkey) {
+        self.insert(key, lamports, data, owner, false);
+    }
+
+    fn insert(&mut self, key: Pubkey, lamports: u64, data: &[u8], owner: &Pubkey, executable: bool) {
+        let slot = Box::leak(Box::new(KeySlot { original_data_len: data.len() as u32, key }));
+        let words = (size_of::<u64>() + data.len() + MAX_PERMITTED_DATA_INCREASE).div_ceil(size_of::<u64>());
+        let buffer = Box::leak(vec![0u64; words].into_boxed_slice()).as_mut_ptr() as *mut u8;
+        let (info_data, data_ptr) = unsafe {
+            let data_ptr = buffer.add(size_of::<u64>());
+            *(buffer as *mut u64) = data.len() as u64;
+            let info_data = slice::from_raw_parts_mut(data_ptr, data.len());
+            info_data.copy_from_slice(data);
+            (info_data, data_ptr)
+        };
+        let info = AccountInfo::new(
+            &slot.key,
+            false,
+            false,
+            Box::leak(Box::new(lamports)),
+            info_data,
+            Box::leak(Box::new(*owner)),
+            executable,
+            0,
+        );
+        self.accounts.insert(key, StoredAccount { info, data: data_ptr });
+    }
+
+    fn account(&mut self, key: &Pubkey) -> &StoredAccount {
+        if !self.accounts.contains_key(key) {
+            self.insert(*key, 0, &[], &system_program::id(), false);
+        }
+        &self.accounts[key]
+    }
+
+    /// Runs one instruction in its own transaction
+    pub fn process(&mut self, instruction: &Instruction) -> ProgramResult {
+        self.process_transaction(std::slice::from_ref(instruction))
+    }
+
+    /// Runs the instructions in order, none of their changes are kept when one fails. Accounts left without lamports
+    /// are deleted once the transaction succeeds.
+    pub fn process_transaction(&mut self, instructions: &[Instruction]) -> ProgramResult {
+        let snapshot: HashMap<Pubkey, Snapshot> = self
+            .accounts
+            .iter()
+            .map(|(key, account)| {
+                let snapshot = Snapshot {
+                    lamports: account.info.lamports(),
+                    data: account.info.data.borrow().to_vec(),
+                    owner: *account.info.owner,
+                };
+                (*key, snapshot)
+            })
+            .collect();
+        let result = self.run(instructions);
+        if result.is_err() {
+            for (key, account) in &self.accounts {
+                let empty = Snapshot { lamports: 0, data: Vec::new(), owner: system_program::id() };
+                let snapshot = snapshot.get(key).unwrap_or(&empty);
+                **account.info.lamports.borrow_mut() = snapshot.lamports;
+                account.info.assign(&snapshot.owner);
+                Self::set_data(account, &snapshot.data);
+            }
+            return result;
+        }
+
+        for account in self.accounts.values() {
+            if account.info.lamports() == 0 && !account.info.executable {
+                account.info.assign(&system_program::id());
+                Self::set_data(account, &[]);
+            }
+        }
+        result
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) -> ProgramResult {
+        let borrowed: Vec<BorrowedInstruction> = instructions
+            .iter()
+            .map(|instruction| BorrowedInstruction {
+                program_id: &instruction.program_id,
+                accounts: instruction
+                    .accounts
+                    .iter()
+                    .map(|meta| BorrowedAccountMeta {
+                        pubkey: &meta.pubkey,
+                        is_signer: meta.is_signer,
+                        is_writable: meta.is_writable,
+                    })
+                    .collect(),
+                data: &instruction.data,
+            })
+            .collect();
+        let mut instructions_data = construct_instructions_data(&borrowed);
+
+        for (index, instruction) in instructions.iter().enumerate() {
+            if instruction.program_id == ed25519_program::id() {
+                continue;
+            }
+            store_current_index(&mut instructions_data, index as u16);
+            self.insert(sysvar::instructions::id(), 1, &instructions_data, &sysvar::id(), false);
+
+            let mut accounts = Vec::with_capacity(instruction.accounts.len());
+            for meta in &instruction.accounts {
+                let mut account = self.account(&meta.pubkey).info.clone();
+                (account.is_signer, account.is_writable) = privileges(&instruction.accounts, &meta.pubkey);
+                accounts.push(account);
+            }
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = None);
+            dispatch(&instruction.program_id, &accounts, &instruction.data)?;
+
+            // the runtime reads back the data length the program left in the serialized account
+            for account in self.accounts.values() {
+                let len = unsafe { *(account.data.sub(size_of::<u64>()) as *const u64) } as usize;
+                *account.info.data.borrow_mut() = unsafe { slice::from_raw_parts_mut(account.data, len) };
+            }
+        }
+        Ok(())
+    }
+
+    fn set_data(account: &StoredAccount, data: &[u8]) {
+        unsafe {
+            *(account.data.sub(size_of::<u64>()) as *mut u64) = data.len() as u64;
+            let info_data = slice::from_raw_parts_mut(account.data, data.len());
+            info_data.copy_from_slice(data);
+            *account.info.data.borrow_mut() = info_data;
+        }
+    }
+
+    pub fn exists(&self, key: &Pubkey) -> bool {
+        self.accounts.get(key).is_some_and(|account| account.info.lamports() > 0)
+    }
+
+    pub fn lamports(&self, key: &Pubkey) -> u64 {
+        self.accounts.get(key).map_or(0, |account| account.info.lamports())
+    }
+
+    pub fn data(&self, key: &Pubkey) -> Vec<u8> {
+        self.accounts.get(key).map_or(Vec::new(), |account| account.info.data.borrow().to_vec())
+    }
+
+    pub fn owner(&self, key: &Pubkey) -> Pubkey {
+        self.accounts.get(key).map_or(system_program::id(), |account| *account.info.owner)
+    }
+
+    /// The logs of the instructions run on this thread so far
+    pub fn logs(&self) -> Vec<String> {
+        LOGS.with(|logs| logs.borrow().clone())
+    }
+
+    /// Creates a mint of `token_program` with `decimals`, minting `supply` to nobody yet
+    pub fn mint(&mut self, token_program: &Pubkey, decimals: u8) -> Pubkey {
+        self.mint_with_extensions(token_program, decimals, &[], |_| Vec::new())
+    }
+
+    /// Creates a Token-2022 mint charging a transfer fee of `fee_bps`, capped at `maximum_fee`
+    pub fn mint_with_transfer_fee(&mut self, decimals: u8, fee_bps: u16, maximum_fee: u64) -> Pubkey {
+        let mint = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let init = initialize_transfer_fee_config(
+            &spl_token_2022::id(),
+            &mint,
+            Some(&authority),
+            Some(&authority),
+            fee_bps,
+            maximum_fee,
+        )
+        .unwrap();
+        self.create_mint(mint, &spl_token_2022::id(), decimals, &[ExtensionType::TransferFeeConfig], &[init]);
+        mint
+    }
+
+    /// Creates a mint with `extensions`, initialized by the instructions `init` returns for the mint address
+    /// before the mint itself
+    pub fn mint_with_extensions(
+        &mut self,
+        token_program: &Pubkey,
+        decimals: u8,
+        extensions: &[ExtensionType],
+        init: impl FnOnce(&Pubkey) -> Vec<Instruction>,
+    ) -> Pubkey {
+        let mint = Pubkey::new_unique();
+        self.create_mint(mint, token_program, decimals, extensions, &init(&mint));
+        mint
+    }
+
+    fn create_mint(
+        &mut self,
+        mint: Pubkey,
+        token_program: &Pubkey,
+        decimals: u8,
+        extensions: &[ExtensionType],
+        init: &[Instruction],
+    ) {
+        let len = if *token_program == spl_token::id() {
+            spl_token::state::Mint::LEN
+        } else {
+            ExtensionType::try_calculate_account_len::<Mint>(extensions).unwrap()
+        };
+        self.set_account(mint, Rent::default().minimum_balance(len), &vec![0; len], token_program);
+        let mut instructions = init.to_vec();
+        instructions.push(
+            spl_token_2022::instruction::initialize_mint2(token_program, &mint, &mint_authority(), None, decimals)
+                .unwrap(),
+        );
+        self.process_transaction(&instructions).unwrap();
+    }
+
+    /// Creates the associated token account of `owner` for `mint` and mints `amount` to it
+    pub fn token_account(&mut self, owner: &Pubkey, mint: &Pubkey, amount: u64) -> Pubkey {
+        let token_program = self.owner(mint);
+        let address =
+            spl_associated_token_account::get_associated_token_address_with_program_id(owner, mint, &token_program);
+        let create = spl_associated_token_account::instruction::create_associated_token_account(
+            &self.payer,
+            owner,
+            mint,
+            &token_program,
+        );
+        self.process(&create).unwrap();
+        self.mint_to(&address, amount);
+        address
+    }
+
+    /// Creates a token account of `owner` for `mint` that is not their associated one and mints `amount` to it
+    pub fn auxiliary_token_account(&mut self, owner: &Pubkey, mint: &Pubkey, amount: u64) -> Pubkey {
+        let token_program = self.owner(mint);
+        let len = if token_program == spl_token::id() {
+            spl_token::state::Account::LEN
+        } else {
+            let mint_extensions = self.mint_extensions(mint);
+            let extensions = ExtensionType::get_required_init_account_extensions(&mint_extensions);
+            ExtensionType::try_calculate_account_len::<TokenAccount>(&extensions).unwrap()
+        };
+        let address = Pubkey::new_unique();
+        self.set_account(address, Rent::default().minimum_balance(len), &vec![0; len], &token_program);
+        let initialize = spl_token_2022::instruction::initialize_account3(&token_program, &address, mint, owner);
+        self.process(&initialize.unwrap()).unwrap();
+        self.mint_to(&address, amount);
+        address
+    }
+
+    /// Mints `amount` more tokens to a token account
+    pub fn mint_to(&mut self, token_account: &Pubkey, amount: u64) {
+        if amount == 0 {
+            return;
+        }
+        let mint = self.token(token_account).mint;
+        let token_program = self.owner(&mint);
+        let mint_to =
+            spl_token_2022::instruction::mint_to(&token_program, &mint, token_account, &mint_authority(), &[], amount)
+                .unwrap();
+        self.process(&mint_to).unwrap();
+    }
+
+    pub fn token(&self, token_account: &Pubkey) -> TokenAccount {
+        StateWithExtensions::<TokenAccount>::unpack(&self.data(token_account)).unwrap().base
+    }
+
+    /// The balance of a token account, 0 when it does not exist
+    pub fn balance(&self, token_account: &Pubkey) -> u64 {
+        if self.data(token_account).is_empty() {
+            return 0;
+        }
+        self.token(token_account).amount
+    }
+
+    pub fn supply(&self, mint: &Pubkey) -> u64 {
+        StateWithExtensions::<Mint>::unpack(&self.data(mint)).unwrap().base.supply
+    }
+
+    pub fn mint_extensions(&self, mint: &Pubkey) -> Vec<ExtensionType> {
+        StateWithExtensions::<Mint>::unpack(&self.data(mint)).unwrap().get_extension_types().unwrap()
+    }
+}
+
+/// Mint authority of every mint the ledger creates, it signs without a key
+pub fn mint_authority() -> Pubkey {
+    Pubkey::new_from_array([1; 32])
+}
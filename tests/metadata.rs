@@ -0,0 +1,111 @@
+use solana_escrow::metadata::{Creator, Metadata};
+use solana_program::pubkey::Pubkey;
+
+/// Local copy of the Metaplex `MetadataV1` layout, serialized the way borsh does
+struct MetadataV1<'a> {
+    update_authority: Pubkey,
+    mint: Pubkey,
+    name: &'a str,
+    symbol: &'a str,
+    uri: &'a str,
+    seller_fee_basis_points: u16,
+    creators: Option<Vec<Creator>>,
+}
+
+impl MetadataV1<'_> {
+    fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![4];
+        buf.extend_from_slice(self.update_authority.as_ref());
+        buf.extend_from_slice(self.mint.as_ref());
+        for string in [self.name, self.symbol, self.uri] {
+            buf.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            buf.extend_from_slice(string.as_bytes());
+        }
+        buf.extend_from_slice(&self.seller_fee_basis_points.to_le_bytes());
+        match &self.creators {
+            None => buf.push(0),
+            Some(creators) => {
+                buf.push(1);
+                buf.extend_from_slice(&(creators.len() as u32).to_le_bytes());
+                for creator in creators {
+                    buf.extend_from_slice(creator.address.as_ref());
+                    buf.push(creator.verified as u8);
+                    buf.push(creator.share);
+                }
+            }
+        }
+        // primary_sale_happened, is_mutable and the optional fields that follow are not read
+        buf.extend_from_slice(&[0, 1, 0, 0, 0]);
+        buf
+    }
+}
+
+fn creator(verified: bool, share: u8) -> Creator {
+    Creator { address: Pubkey::new_unique(), verified, share }
+}
+
+fn metadata_v1(seller_fee_basis_points: u16, creators: Option<Vec<Creator>>) -> MetadataV1<'static> {
+    MetadataV1 {
+        update_authority: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        name: "Escrow NFT #1",
+        symbol: "ESC",
+        uri: "https://example.com/1.json",
+        seller_fee_basis_points,
+        creators,
+    }
+}
+
+#[test]
+fn unpack_reads_mint_fee_and_creators() {
+    let creators = vec![creator(true, 70), creator(false, 30)];
+    let account = metadata_v1(500, Some(creators.clone()));
+
+    let metadata = Metadata::unpack(&account.serialize()).unwrap();
+
+    assert_eq!(metadata.mint, account.mint);
+    assert_eq!(metadata.seller_fee_basis_points, 500);
+    assert_eq!(metadata.creators, creators);
+}
+
+#[test]
+fn royalties_split_by_share_among_verified_creators() {
+    let creators = vec![creator(true, 60), creator(false, 10), creator(true, 30), creator(true, 0)];
+    let metadata = Metadata::unpack(&metadata_v1(1_000, Some(creators.clone())).serialize()).unwrap();
+
+    // 10% of 2 SOL, 60% and 30% of it to the two verified creators with a share
+    let royalties: Vec<(Pubkey, u64)> = metadata.royalties(2_000_000_000).collect();
+
+    assert_eq!(royalties, vec![(creators[0].address, 120_000_000), (creators[2].address, 60_000_000)]);
+}
+
+#[test]
+fn royalties_round_down() {
+    let creators = vec![creator(true, 50), creator(true, 50)];
+    let metadata = Metadata::unpack(&metadata_v1(333, Some(creators)).serialize()).unwrap();
+
+    let royalties: Vec<u64> = metadata.royalties(1_001).map(|(_, royalty)| royalty).collect();
+
+    assert_eq!(royalties, vec![16, 16]);
+}
+
+#[test]
+fn no_creators_pay_no_royalties() {
+    let metadata = Metadata::unpack(&metadata_v1(500, None).serialize()).unwrap();
+
+    assert!(metadata.creators.is_empty());
+    assert_eq!(metadata.royalties(1_000_000).count(), 0);
+}
+
+#[test]
+fn unpack_rejects_other_accounts() {
+    let mut data = metadata_v1(500, Some(vec![creator(true, 100)])).serialize();
+
+    let mut edition = data.clone();
+    edition[0] = 6;
+    assert!(Metadata::unpack(&edition).is_err());
+
+    // cut inside the creators
+    data.truncate(data.len() - 20);
+    assert!(Metadata::unpack(&data).is_err());
+}
@@ -0,0 +1,784 @@
+mod common;
+
+use common::Ledger;
+use solana_escrow::{
+    error::EscrowError,
+    find_config_address, find_escrow_authority, find_order_nonces_address, find_vault_address,
+    instruction::{
+        cancel_escrow, cancel_order, claim_with_secret, dispute, exchange, exchange_partial, init_escrow,
+        reclaim_expired, resolve, update_escrow, InitEscrowArgs, FILL_DENOMINATOR,
+    },
+    order::NonceBitmap,
+    state::{Config, EscrowState, XLeg, YLeg, MAX_FEE_EXEMPT},
+};
+use solana_program::{
+    hash::hash, instruction::Instruction, program_error::ProgramError, program_option::COption, program_pack::Pack,
+    pubkey::Pubkey, rent::Rent, system_instruction, system_program,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token_2022::{
+    extension::ExtensionType,
+    instruction::{
+        approve, initialize_mint_close_authority, initialize_non_transferable_mint, initialize_permanent_delegate,
+        transfer_checked,
+    },
+};
+
+/// An escrow trading one X leg for one Y leg, with the accounts of both parties
+struct Trade {
+    initializer: Pubkey,
+    taker: Pubkey,
+    escrow: Pubkey,
+    mint_x: Pubkey,
+    mint_y: Pubkey,
+    token_program: Pubkey,
+    initializer_token_account: Pubkey,
+    taker_token_account: Pubkey,
+}
+
+impl Trade {
+    /// The taker's associated token account receiving the X leg
+    fn taker_receiving_account(&self) -> Pubkey {
+        get_associated_token_address_with_program_id(&self.taker, &self.mint_x, &self.token_program)
+    }
+
+    /// The initializer's associated token account receiving the Y leg
+    fn initializer_receiving_account(&self) -> Pubkey {
+        get_associated_token_address_with_program_id(&self.initializer, &self.mint_y, &self.token_program)
+    }
+}
+
+/// Funds both parties of a trade of `amount_x` X tokens for `amount_y` Y tokens and returns its `InitEscrow` instruction
+fn new_trade(
+    ledger: &mut Ledger,
+    token_program: &Pubkey,
+    amount_x: u64,
+    amount_y: u64,
+    args: &InitEscrowArgs,
+) -> (Trade, Instruction) {
+    let initializer = ledger.wallet();
+    let taker = ledger.wallet();
+    let mint_x = ledger.mint(token_program, 0);
+    let mint_y = ledger.mint(token_program, 0);
+    let initializer_token_account = ledger.token_account(&initializer, &mint_x, amount_x);
+    let taker_token_account = ledger.token_account(&taker, &mint_y, amount_y);
+
+    let legs_x = [XLeg { mint: mint_x, token_program: *token_program, amount: amount_x, ..XLeg::default() }];
+    let legs_y = [YLeg { mint: mint_y, token_program: *token_program, amount: amount_y }];
+    let init = init_escrow(&ledger.program_id, &initializer, &taker, &legs_x, &legs_y, 0, 0, args).unwrap();
+    let trade = Trade {
+        initializer,
+        taker,
+        escrow: init.accounts[2].pubkey,
+        mint_x,
+        mint_y,
+        token_program: *token_program,
+        initializer_token_account,
+        taker_token_account,
+    };
+    (trade, init)
+}
+
+/// Opens an escrow of `amount_x` X tokens for `amount_y` Y tokens, both parties holding exactly what they trade
+fn open_trade(ledger: &mut Ledger, token_program: &Pubkey, amount_x: u64, amount_y: u64, args: &InitEscrowArgs) -> Trade {
+    let (trade, init) = new_trade(ledger, token_program, amount_x, amount_y, args);
+    ledger.process(&init).unwrap();
+    trade
+}
+
+fn escrow_state(ledger: &Ledger, escrow: &Pubkey) -> EscrowState {
+    EscrowState::unpack_account(&ledger.data(escrow)).unwrap()
+}
+
+/// An `ExchangeStored` instruction taking the whole trade at its current version
+fn exchange_trade(ledger: &Ledger, trade: &Trade) -> Instruction {
+    let escrow_info = escrow_state(ledger, &trade.escrow);
+    exchange(
+        &ledger.program_id,
+        &trade.initializer,
+        &trade.taker,
+        &trade.escrow,
+        &escrow_info.rent_destination,
+        &Pubkey::new_unique(),
+        &[],
+        escrow_info.x_legs(),
+        escrow_info.y_legs(),
+        &[],
+        escrow_info.version,
+    )
+    .unwrap()
+}
+
+#[test]
+fn exchange_settles_through_the_authority_of_the_escrow() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 5, 7, &InitEscrowArgs::default());
+    let vault = escrow_state(&ledger, &trade.escrow).x_legs()[0].temp_token_account;
+
+    assert_eq!(ledger.token(&vault).owner, find_escrow_authority(&ledger.program_id, &trade.escrow).0);
+    assert_eq!(ledger.balance(&vault), 5);
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 0);
+
+    ledger.process(&exchange_trade(&ledger, &trade)).unwrap();
+
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 5);
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 7);
+    assert_eq!(ledger.balance(&trade.taker_token_account), 0);
+    assert!(!ledger.exists(&vault));
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn each_escrow_has_its_own_authority() {
+    let mut ledger = Ledger::new();
+    let first = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let second = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let first_vault = escrow_state(&ledger, &first.escrow).x_legs()[0].temp_token_account;
+    let second_vault = escrow_state(&ledger, &second.escrow).x_legs()[0].temp_token_account;
+
+    assert_ne!(ledger.token(&first_vault).owner, ledger.token(&second_vault).owner);
+}
+
+#[test]
+fn exchange_rejects_the_authority_or_vault_of_another_escrow() {
+    let mut ledger = Ledger::new();
+    let first = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let second = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let second_exchange = exchange_trade(&ledger, &second);
+
+    let mut other_authority = exchange_trade(&ledger, &first);
+    other_authority.accounts[4] = second_exchange.accounts[4].clone();
+    assert_eq!(ledger.process(&other_authority), Err(EscrowError::InvalidAuthority.into()));
+
+    // the vault is the second account of the first X leg, after the ten fixed accounts
+    let mut other_vault = exchange_trade(&ledger, &first);
+    other_vault.accounts[11] = second_exchange.accounts[11].clone();
+    assert_eq!(ledger.process(&other_vault), Err(EscrowError::InvalidTempTokenAccount.into()));
+
+    ledger.process(&exchange_trade(&ledger, &first)).unwrap();
+    assert_eq!(ledger.balance(&first.taker_receiving_account()), 1);
+}
+
+#[test]
+fn init_creates_the_vaults_at_their_program_address() {
+    for token_program in [spl_token::id(), spl_token_2022::id()] {
+        let mut ledger = Ledger::new();
+        let trade = open_trade(&mut ledger, &token_program, 3, 1, &InitEscrowArgs::default());
+        let (vault, _vault_bump_seed) = find_vault_address(&ledger.program_id, &trade.escrow, 0);
+
+        assert_eq!(escrow_state(&ledger, &trade.escrow).x_legs()[0].temp_token_account, vault);
+        assert_eq!(ledger.owner(&vault), token_program);
+        let vault_info = ledger.token(&vault);
+        assert_eq!(vault_info.owner, find_escrow_authority(&ledger.program_id, &trade.escrow).0);
+        assert_eq!(vault_info.mint, trade.mint_x);
+        assert_eq!(vault_info.amount, 3);
+    }
+}
+
+#[test]
+fn init_rejects_a_vault_the_client_created() {
+    let mut ledger = Ledger::new();
+    let (trade, mut init) = new_trade(&mut ledger, &spl_token::id(), 3, 1, &InitEscrowArgs::default());
+    let client_vault = ledger.token_account(&Pubkey::new_unique(), &trade.mint_x, 0);
+
+    // the vault follows the initializer's token account of the first X leg, after the five fixed accounts
+    init.accounts[6].pubkey = client_vault;
+    assert_eq!(ledger.process(&init), Err(EscrowError::InvalidVaultAddress.into()));
+    assert!(!ledger.exists(&trade.escrow));
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 3);
+}
+
+#[test]
+fn nonce_account_keeps_the_bump_seed_found_at_creation() {
+    let mut ledger = Ledger::new();
+    let maker = ledger.wallet();
+    let (nonces, nonces_bump_seed) = find_order_nonces_address(&ledger.program_id, &maker, 0);
+
+    ledger.process(&cancel_order(&ledger.program_id, &maker, 3).unwrap()).unwrap();
+    let bitmap = NonceBitmap::unpack_from_slice(&ledger.data(&nonces)).unwrap();
+    assert_eq!(bitmap.bump_seed, nonces_bump_seed);
+    assert!(bitmap.is_used(3));
+
+    ledger.process(&cancel_order(&ledger.program_id, &maker, 4).unwrap()).unwrap();
+    assert_eq!(
+        ledger.process(&cancel_order(&ledger.program_id, &maker, 4).unwrap()),
+        Err(EscrowError::OrderNonceUsed.into())
+    );
+}
+
+#[test]
+fn closed_escrow_cannot_be_revived_in_the_same_transaction() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let escrow_lamports = ledger.lamports(&trade.escrow);
+    let refill = system_instruction::transfer(&trade.taker, &trade.escrow, escrow_lamports);
+
+    ledger.process_transaction(&[exchange_trade(&ledger, &trade), refill]).unwrap();
+
+    assert_eq!(ledger.lamports(&trade.escrow), escrow_lamports);
+    assert!(ledger.data(&trade.escrow).is_empty());
+    assert_eq!(ledger.owner(&trade.escrow), system_program::id());
+}
+
+#[test]
+fn closed_escrow_cannot_be_exchanged_again_in_the_same_transaction() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let exchange = exchange_trade(&ledger, &trade);
+
+    assert!(ledger.process_transaction(&[exchange.clone(), exchange]).is_err());
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 0);
+    assert!(Rent::default().is_exempt(ledger.lamports(&trade.escrow), ledger.data(&trade.escrow).len()));
+}
+
+/// A `CancelStored` instruction of the initializer returning every X leg to its stored token account
+fn cancel_trade(ledger: &Ledger, trade: &Trade) -> Instruction {
+    let escrow_info = escrow_state(ledger, &trade.escrow);
+    cancel_escrow(
+        &ledger.program_id,
+        &trade.initializer,
+        &trade.taker,
+        &trade.escrow,
+        &escrow_info.rent_destination,
+        escrow_info.x_legs(),
+        escrow_info.non_custodial,
+        None,
+    )
+    .unwrap()
+}
+
+#[test]
+fn cancel_refunds_a_token_account_of_the_initializer() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 4, 1, &InitEscrowArgs::default());
+    let other_account = ledger.auxiliary_token_account(&trade.initializer, &trade.mint_x, 0);
+    let taker_account = ledger.token_account(&trade.taker, &trade.mint_x, 0);
+
+    // the first X leg starts with the initializer's token account, after the six fixed accounts
+    let mut to_the_taker = cancel_trade(&ledger, &trade);
+    to_the_taker.accounts[6].pubkey = taker_account;
+    assert_eq!(ledger.process(&to_the_taker), Err(EscrowError::InvalidTokenOwner.into()));
+
+    let mut to_another_account = cancel_trade(&ledger, &trade);
+    to_another_account.accounts[6].pubkey = other_account;
+    ledger.process(&to_another_account).unwrap();
+    assert_eq!(ledger.balance(&other_account), 4);
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 0);
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn exchange_returns_the_rent_to_the_rent_destination() {
+    let mut ledger = Ledger::new();
+    let rent_destination = ledger.wallet();
+    let args = InitEscrowArgs { rent_destination: Some(rent_destination), ..InitEscrowArgs::default() };
+    let trade = open_trade(&mut ledger, &spl_token::id(), 2, 3, &args);
+    let vault = escrow_state(&ledger, &trade.escrow).x_legs()[0].temp_token_account;
+    let rent = ledger.lamports(&vault) + ledger.lamports(&trade.escrow);
+    let destination_lamports = ledger.lamports(&rent_destination);
+    let token_account_lamports = ledger.lamports(&trade.initializer_token_account);
+
+    let mut other_destination = exchange_trade(&ledger, &trade);
+    other_destination.accounts[5].pubkey = trade.initializer;
+    assert_eq!(ledger.process(&other_destination), Err(EscrowError::InvalidRentDestination.into()));
+
+    ledger.process(&exchange_trade(&ledger, &trade)).unwrap();
+    assert_eq!(ledger.lamports(&rent_destination), destination_lamports + rent);
+    assert_eq!(ledger.lamports(&trade.initializer_token_account), token_account_lamports);
+}
+
+#[test]
+fn cancel_returns_the_rent_to_the_initializer_by_default() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 2, 3, &InitEscrowArgs::default());
+    let vault = escrow_state(&ledger, &trade.escrow).x_legs()[0].temp_token_account;
+    assert_eq!(escrow_state(&ledger, &trade.escrow).rent_destination, trade.initializer);
+    let rent = ledger.lamports(&vault) + ledger.lamports(&trade.escrow);
+    let initializer_lamports = ledger.lamports(&trade.initializer);
+    let token_account_lamports = ledger.lamports(&trade.initializer_token_account);
+
+    ledger.process(&cancel_trade(&ledger, &trade)).unwrap();
+    assert_eq!(ledger.lamports(&trade.initializer), initializer_lamports + rent);
+    assert_eq!(ledger.lamports(&trade.initializer_token_account), token_account_lamports);
+}
+
+/// A `ReclaimExpired` instruction nobody has to sign
+fn reclaim_trade(ledger: &Ledger, trade: &Trade) -> Instruction {
+    let escrow_info = escrow_state(ledger, &trade.escrow);
+    reclaim_expired(
+        &ledger.program_id,
+        &trade.initializer,
+        &trade.taker,
+        &trade.escrow,
+        &escrow_info.rent_destination,
+        escrow_info.x_legs(),
+        escrow_info.non_custodial,
+    )
+    .unwrap()
+}
+
+#[test]
+fn expired_escrow_can_only_be_reclaimed() {
+    let mut ledger = Ledger::new();
+    let expires_at = ledger.now() + 100;
+    let args = InitEscrowArgs { expires_at: Some(expires_at), ..InitEscrowArgs::default() };
+    let trade = open_trade(&mut ledger, &spl_token::id(), 6, 2, &args);
+    assert!(reclaim_trade(&ledger, &trade).accounts.iter().all(|account| !account.is_signer));
+
+    assert_eq!(ledger.process(&reclaim_trade(&ledger, &trade)), Err(EscrowError::EscrowNotExpired.into()));
+
+    ledger.set_time(expires_at);
+    assert_eq!(ledger.process(&exchange_trade(&ledger, &trade)), Err(EscrowError::EscrowExpired.into()));
+
+    ledger.process(&reclaim_trade(&ledger, &trade)).unwrap();
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 6);
+    assert_eq!(ledger.balance(&trade.taker_token_account), 2);
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn open_escrow_can_be_taken_by_any_taker() {
+    let mut ledger = Ledger::new();
+    let (mut trade, _init) = new_trade(&mut ledger, &spl_token::id(), 3, 4, &InitEscrowArgs::default());
+    let legs_x = [XLeg { mint: trade.mint_x, token_program: trade.token_program, amount: 3, ..XLeg::default() }];
+    let legs_y = [YLeg { mint: trade.mint_y, token_program: trade.token_program, amount: 4 }];
+    let args = InitEscrowArgs::default();
+    let init = init_escrow(&ledger.program_id, &trade.initializer, &Pubkey::default(), &legs_x, &legs_y, 0, 0, &args);
+    ledger.process(&init.unwrap()).unwrap();
+    let escrow_info = escrow_state(&ledger, &trade.escrow);
+    assert!(escrow_info.is_open());
+
+    trade.taker = ledger.wallet();
+    trade.taker_token_account = ledger.token_account(&trade.taker, &trade.mint_y, 4);
+    ledger.process(&exchange_trade(&ledger, &trade)).unwrap();
+
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 3);
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 4);
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+/// An `ExchangePartial` instruction taking `fill_numerator / FILL_DENOMINATOR` of what is left of the trade
+fn exchange_part_of_trade(ledger: &Ledger, trade: &Trade, fill_numerator: u64) -> Instruction {
+    let escrow_info = escrow_state(ledger, &trade.escrow);
+    exchange_partial(
+        &ledger.program_id,
+        &trade.initializer,
+        &trade.taker,
+        &trade.escrow,
+        &escrow_info.rent_destination,
+        &Pubkey::new_unique(),
+        &[],
+        escrow_info.x_legs(),
+        escrow_info.y_legs(),
+        &[],
+        fill_numerator,
+        escrow_info.version,
+    )
+    .unwrap()
+}
+
+#[test]
+fn partial_fill_settles_its_share_of_every_leg() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 100, 40, &InitEscrowArgs::default());
+    let vault = escrow_state(&ledger, &trade.escrow).x_legs()[0].temp_token_account;
+
+    ledger.process(&exchange_part_of_trade(&ledger, &trade, FILL_DENOMINATOR / 4)).unwrap();
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 25);
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 10);
+    assert_eq!(ledger.balance(&vault), 75);
+    let escrow_info = escrow_state(&ledger, &trade.escrow);
+    assert_eq!(escrow_info.x_legs()[0].amount, 75);
+    assert_eq!(escrow_info.y_legs()[0].amount, 30);
+
+    ledger.process(&exchange_part_of_trade(&ledger, &trade, FILL_DENOMINATOR / 5)).unwrap();
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 40);
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 16);
+    assert_eq!(ledger.balance(&vault), 60);
+
+    ledger.process(&exchange_part_of_trade(&ledger, &trade, FILL_DENOMINATOR)).unwrap();
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 100);
+    assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 40);
+    assert!(!ledger.exists(&vault));
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn update_keeps_each_non_custodial_leg_on_its_own_token_account() {
+    let mut ledger = Ledger::new();
+    let args = InitEscrowArgs { non_custodial: true, ..InitEscrowArgs::default() };
+    let trade = open_trade(&mut ledger, &spl_token::id(), 5, 2, &args);
+    let other_account = ledger.auxiliary_token_account(&trade.initializer, &trade.mint_x, 9);
+    let escrow_info = escrow_state(&ledger, &trade.escrow);
+    let leg = XLeg { amount: 1, ..escrow_info.x_legs()[0] };
+    let program_id = ledger.program_id;
+    let update = |legs_x: &[XLeg]| {
+        update_escrow(&program_id, &trade.initializer, &trade.escrow, legs_x, &[3], 0, None, true).unwrap()
+    };
+
+    // the existing leg starts with the initializer's token account and its temp one, after the four fixed accounts
+    let mut other_source = update(&[leg]);
+    other_source.accounts[4].pubkey = other_account;
+    assert_eq!(ledger.process(&other_source), Err(EscrowError::InvalidTempTokenAccount.into()));
+
+    let duplicate = update(&[leg, XLeg { amount: 1, ..leg }]);
+    assert_eq!(ledger.process(&duplicate), Err(EscrowError::DuplicateTokenAccount.into()));
+
+    let stale_exchange = exchange_trade(&ledger, &trade);
+    ledger.mint_to(&trade.initializer_token_account, 1);
+    ledger.process(&update(&[leg])).unwrap();
+    let updated_info = escrow_state(&ledger, &trade.escrow);
+    assert_eq!(updated_info.version, escrow_info.version + 1);
+    assert_eq!(updated_info.x_legs()[0].amount, 6);
+    assert_eq!(updated_info.y_legs()[0].amount, 3);
+    assert_eq!(ledger.token(&trade.initializer_token_account).delegated_amount, 6);
+    assert_eq!(ledger.balance(&other_account), 9);
+
+    assert_eq!(ledger.process(&stale_exchange), Err(EscrowError::StaleEscrowVersion.into()));
+}
+
+#[test]
+fn partial_fill_moves_the_escrow_to_its_next_version() {
+    let mut ledger = Ledger::new();
+    let trade = open_trade(&mut ledger, &spl_token::id(), 10, 10, &InitEscrowArgs::default());
+    let version = escrow_state(&ledger, &trade.escrow).version;
+    let stale_exchange = exchange_trade(&ledger, &trade);
+
+    ledger.process(&exchange_part_of_trade(&ledger, &trade, FILL_DENOMINATOR / 2)).unwrap();
+    assert_eq!(escrow_state(&ledger, &trade.escrow).version, version + 1);
+    assert_eq!(ledger.process(&stale_exchange), Err(EscrowError::StaleEscrowVersion.into()));
+
+    ledger.process(&exchange_trade(&ledger, &trade)).unwrap();
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 10);
+}
+
+/// Stores a config charging `fee_bps` to `treasury` on every trade without a party on `fee_exempt`
+fn set_config(ledger: &mut Ledger, fee_bps: u16, treasury: &Pubkey, fee_exempt: &[Pubkey]) {
+    let (config_address, bump_seed) = find_config_address(&ledger.program_id);
+    let mut config = Config {
+        is_initialized: true,
+        bump_seed,
+        fee_bps,
+        treasury: *treasury,
+        fee_exempt_count: fee_exempt.len() as u8,
+        fee_exempt: [Pubkey::default(); MAX_FEE_EXEMPT],
+        ..Config::default()
+    };
+    config.fee_exempt[..fee_exempt.len()].copy_from_slice(fee_exempt);
+    let mut data = vec![0; Config::LEN];
+    Config::pack(config, &mut data).unwrap();
+    let program_id = ledger.program_id;
+    ledger.set_account(config_address, Rent::default().minimum_balance(Config::LEN), &data, &program_id);
+}
+
+#[test]
+fn fee_exempt_taker_trades_without_fee() {
+    let mut ledger = Ledger::new();
+    let treasury = ledger.wallet();
+    let charged = open_trade(&mut ledger, &spl_token::id(), 1_000, 2_000, &InitEscrowArgs::default());
+    let exempt = open_trade(&mut ledger, &spl_token::id(), 1_000, 2_000, &InitEscrowArgs::default());
+    set_config(&mut ledger, 100, &treasury, &[exempt.taker]);
+    let treasury_token_accounts = [
+        ledger.token_account(&treasury, &charged.mint_x, 0),
+        ledger.token_account(&treasury, &charged.mint_y, 0),
+    ];
+
+    let escrow_info = escrow_state(&ledger, &charged.escrow);
+    let charged_exchange = exchange(
+        &ledger.program_id,
+        &charged.initializer,
+        &charged.taker,
+        &charged.escrow,
+        &escrow_info.rent_destination,
+        &treasury,
+        &treasury_token_accounts,
+        escrow_info.x_legs(),
+        escrow_info.y_legs(),
+        &[],
+        escrow_info.version,
+    );
+    ledger.process(&charged_exchange.unwrap()).unwrap();
+    assert_eq!(ledger.balance(&charged.taker_receiving_account()), 990);
+    assert_eq!(ledger.balance(&charged.initializer_receiving_account()), 1_980);
+    assert_eq!(ledger.balance(&treasury_token_accounts[0]), 10);
+    assert_eq!(ledger.balance(&treasury_token_accounts[1]), 20);
+
+    // an exempt trade takes no treasury token account at all
+    ledger.process(&exchange_trade(&ledger, &exempt)).unwrap();
+    assert_eq!(ledger.balance(&exempt.taker_receiving_account()), 1_000);
+    assert_eq!(ledger.balance(&exempt.initializer_receiving_account()), 2_000);
+}
+
+#[test]
+fn init_rejects_mints_with_an_unsupported_extension() {
+    let mut ledger = Ledger::new();
+    let token_program = spl_token_2022::id();
+    let (trade, _init) = new_trade(&mut ledger, &token_program, 1, 1, &InitEscrowArgs::default());
+    let non_transferable = ledger.mint_with_extensions(&token_program, 0, &[ExtensionType::NonTransferable], |mint| {
+        vec![initialize_non_transferable_mint(&token_program, mint).unwrap()]
+    });
+    let delegate = Pubkey::new_unique();
+    let delegated = ledger.mint_with_extensions(&token_program, 0, &[ExtensionType::PermanentDelegate], |mint| {
+        vec![initialize_permanent_delegate(&token_program, mint, &delegate).unwrap()]
+    });
+    let closable = ledger.mint_with_extensions(&token_program, 0, &[ExtensionType::MintCloseAuthority], |mint| {
+        vec![initialize_mint_close_authority(&token_program, mint, Some(&Pubkey::new_unique())).unwrap()]
+    });
+    let leg_x = |mint: Pubkey| XLeg { mint, token_program, amount: 1, ..XLeg::default() };
+    let leg_y = |mint: Pubkey| YLeg { mint, token_program, amount: 1 };
+    let program_id = ledger.program_id;
+    let init = |legs_x: &[XLeg], legs_y: &[YLeg]| {
+        init_escrow(&program_id, &trade.initializer, &trade.taker, legs_x, legs_y, 0, 0, &InitEscrowArgs::default())
+            .unwrap()
+    };
+
+    let non_transferable_x = init(&[leg_x(non_transferable)], &[leg_y(trade.mint_y)]);
+    assert_eq!(ledger.process(&non_transferable_x), Err(EscrowError::UnsupportedMintExtension.into()));
+    let permanent_delegate_y = init(&[leg_x(trade.mint_x)], &[leg_y(delegated)]);
+    assert_eq!(ledger.process(&permanent_delegate_y), Err(EscrowError::UnsupportedMintExtension.into()));
+
+    ledger.token_account(&trade.initializer, &closable, 1);
+    ledger.process(&init(&[leg_x(closable)], &[leg_y(trade.mint_y)])).unwrap();
+    assert_eq!(escrow_state(&ledger, &trade.escrow).x_legs()[0].mint, closable);
+}
+
+#[test]
+fn only_a_party_can_dispute_an_escrow_with_an_arbiter() {
+    let mut ledger = Ledger::new();
+    let without_arbiter = open_trade(&mut ledger, &spl_token::id(), 1, 1, &InitEscrowArgs::default());
+    let no_arbiter =
+        dispute(&ledger.program_id, &without_arbiter.initializer, &without_arbiter.escrow, [1; 32]).unwrap();
+    assert_eq!(ledger.process(&no_arbiter), Err(EscrowError::InvalidArbiter.into()));
+
+    let args = InitEscrowArgs { arbiter: Some(ledger.wallet()), ..InitEscrowArgs::default() };
+    let trade = open_trade(&mut ledger, &spl_token::id(), 1, 1, &args);
+    let stranger = ledger.wallet();
+    let by_stranger = dispute(&ledger.program_id, &stranger, &trade.escrow, [1; 32]).unwrap();
+    assert_eq!(ledger.process(&by_stranger), Err(EscrowError::InvalidParty.into()));
+
+    ledger.process(&dispute(&ledger.program_id, &trade.taker, &trade.escrow, [7; 32]).unwrap()).unwrap();
+    let escrow_info = escrow_state(&ledger, &trade.escrow);
+    assert!(escrow_info.disputed);
+    assert_eq!(escrow_info.evidence_hash, [7; 32]);
+    assert_eq!(escrow_info.version, 1);
+
+    // a disputed escrow is frozen until its arbiter resolves it
+    assert_eq!(ledger.process(&exchange_trade(&ledger, &trade)), Err(EscrowError::EscrowDisputed.into()));
+    assert_eq!(ledger.process(&cancel_trade(&ledger, &trade)), Err(EscrowError::EscrowDisputed.into()));
+}
+
+#[test]
+fn arbiter_resolves_a_dispute_with_a_split() {
+    let mut ledger = Ledger::new();
+    let token_program = spl_token::id();
+    let arbiter = ledger.wallet();
+    let args = InitEscrowArgs { arbiter: Some(arbiter), ..InitEscrowArgs::default() };
+    let (trade, _init) = new_trade(&mut ledger, &token_program, 5, 1, &args);
+    let second_mint = ledger.mint(&token_program, 0);
+    let second_account = ledger.token_account(&trade.initializer, &second_mint, 3);
+    let legs_x = [
+        XLeg { mint: trade.mint_x, token_program, amount: 5, ..XLeg::default() },
+        XLeg { mint: second_mint, token_program, amount: 3, ..XLeg::default() },
+    ];
+    let legs_y = [YLeg { mint: trade.mint_y, token_program, amount: 1 }];
+    let init = init_escrow(&ledger.program_id, &trade.initializer, &trade.taker, &legs_x, &legs_y, 1, 1_000, &args);
+    ledger.process(&init.unwrap()).unwrap();
+    let escrow_info = escrow_state(&ledger, &trade.escrow);
+    let program_id = ledger.program_id;
+    let resolve_trade = |signer: &Pubkey, taker_lamports: u64| {
+        resolve(
+            &program_id,
+            signer,
+            &trade.initializer,
+            &trade.taker,
+            &trade.escrow,
+            &escrow_info.rent_destination,
+            escrow_info.x_legs(),
+            &[true, false],
+            taker_lamports,
+        )
+        .unwrap()
+    };
+
+    assert_eq!(ledger.process(&resolve_trade(&arbiter, 600)), Err(EscrowError::EscrowNotDisputed.into()));
+    ledger.process(&dispute(&program_id, &trade.initializer, &trade.escrow, [7; 32]).unwrap()).unwrap();
+    assert_eq!(ledger.process(&resolve_trade(&trade.initializer, 600)), Err(EscrowError::InvalidArbiter.into()));
+    assert_eq!(ledger.process(&resolve_trade(&arbiter, 1_001)), Err(EscrowError::InvalidAmount.into()));
+
+    let vaults = [0, 1].map(|index| find_vault_address(&program_id, &trade.escrow, index).0);
+    let initializer_lamports = ledger.lamports(&trade.initializer);
+    let taker_lamports = ledger.lamports(&trade.taker);
+    let escrow_lamports = ledger.lamports(&trade.escrow);
+    let vault_lamports = ledger.lamports(&vaults[0]) + ledger.lamports(&vaults[1]);
+    ledger.process(&resolve_trade(&arbiter, 600)).unwrap();
+
+    // the first X leg goes to the taker, the second one back to the initializer, the SOL leg is split
+    assert_eq!(ledger.balance(&trade.taker_receiving_account()), 5);
+    assert_eq!(ledger.balance(&second_account), 3);
+    assert_eq!(ledger.balance(&trade.taker_token_account), 1);
+    assert_eq!(ledger.lamports(&trade.taker), taker_lamports + 600);
+    assert_eq!(ledger.lamports(&trade.initializer), initializer_lamports + escrow_lamports - 600 + vault_lamports);
+    assert!(!ledger.exists(&vaults[0]) && !ledger.exists(&vaults[1]));
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+/// Opens an escrow of 4 X tokens and 500 lamports for the taker, locked by the hash of `secret` until `expires_at`
+fn hashlocked_trade(ledger: &mut Ledger, secret: &[u8; 32], expires_at: i64) -> Trade {
+    let hashlock = Some(hash(secret).to_bytes());
+    let args = InitEscrowArgs { expires_at: Some(expires_at), hashlock, ..InitEscrowArgs::default() };
+    let (trade, _init) = new_trade(ledger, &spl_token::id(), 4, 0, &args);
+    let legs_x = [XLeg { mint: trade.mint_x, token_program: trade.token_program, amount: 4, ..XLeg::default() }];
+    let init = init_escrow(&ledger.program_id, &trade.initializer, &trade.taker, &legs_x, &[], 1, 500, &args);
+    ledger.process(&init.unwrap()).unwrap();
+    trade
+}
+
+/// A `ClaimWithSecret` instruction of the taker revealing `secret`
+fn claim_trade(ledger: &Ledger, trade: &Trade, secret: [u8; 32]) -> Instruction {
+    let escrow_info = escrow_state(ledger, &trade.escrow);
+    claim_with_secret(
+        &ledger.program_id,
+        &trade.taker,
+        &trade.initializer,
+        &trade.escrow,
+        &escrow_info.rent_destination,
+        escrow_info.x_legs(),
+        secret,
+    )
+    .unwrap()
+}
+
+#[test]
+fn taker_claims_a_hashlocked_escrow_with_its_secret() {
+    let mut ledger = Ledger::new();
+    let secret = [9; 32];
+    let expires_at = ledger.now() + 100;
+    let trade = hashlocked_trade(&mut ledger, &secret, expires_at);
+
+    assert_eq!(ledger.process(&claim_trade(&ledger, &trade, [8; 32])), Err(EscrowError::InvalidSecret.into()));
+    assert_eq!(ledger.process(&exchange_trade(&ledger, &trade)), Err(EscrowError::InvalidHashlock.into()));
+    assert_eq!(ledger.process(&cancel_trade(&ledger, &trade)), Err(EscrowError::EscrowNotExpired.into()));
+
+    let taker_lamports = ledger.lamports(&trade.taker);
+    ledger.process(&claim_trade(&ledger, &trade, secret)).unwrap();
+    let receiving_account = trade.taker_receiving_account();
+    assert_eq!(ledger.balance(&receiving_account), 4);
+    assert_eq!(ledger.lamports(&trade.taker), taker_lamports + 500 - ledger.lamports(&receiving_account));
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn hashlocked_escrow_is_refunded_once_expired() {
+    let mut ledger = Ledger::new();
+    let secret = [9; 32];
+    let expires_at = ledger.now() + 100;
+    let trade = hashlocked_trade(&mut ledger, &secret, expires_at);
+
+    ledger.set_time(expires_at);
+    assert_eq!(ledger.process(&claim_trade(&ledger, &trade, secret)), Err(EscrowError::EscrowExpired.into()));
+
+    let initializer_lamports = ledger.lamports(&trade.initializer);
+    let escrow_lamports = ledger.lamports(&trade.escrow);
+    let vault_lamports = ledger.lamports(&find_vault_address(&ledger.program_id, &trade.escrow, 0).0);
+    ledger.process(&cancel_trade(&ledger, &trade)).unwrap();
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 4);
+    assert_eq!(ledger.lamports(&trade.initializer), initializer_lamports + escrow_lamports + vault_lamports);
+    assert!(!ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn non_custodial_escrow_settles_as_delegate_of_the_initializer() {
+    for token_program in [spl_token::id(), spl_token_2022::id()] {
+        let mut ledger = Ledger::new();
+        let args = InitEscrowArgs { non_custodial: true, ..InitEscrowArgs::default() };
+        let trade = open_trade(&mut ledger, &token_program, 5, 2, &args);
+        let (pda, _bump_seed) = find_escrow_authority(&ledger.program_id, &trade.escrow);
+
+        // the tokens stay with the initializer, approved to the escrow authority
+        let token_info = ledger.token(&trade.initializer_token_account);
+        assert_eq!((token_info.amount, token_info.delegate, token_info.delegated_amount), (5, COption::Some(pda), 5));
+        assert!(!ledger.exists(&find_vault_address(&ledger.program_id, &trade.escrow, 0).0));
+
+        ledger.process(&exchange_trade(&ledger, &trade)).unwrap();
+        assert_eq!(ledger.balance(&trade.taker_receiving_account()), 5);
+        assert_eq!(ledger.balance(&trade.initializer_receiving_account()), 2);
+        let token_info = ledger.token(&trade.initializer_token_account);
+        assert_eq!((token_info.amount, token_info.delegate), (0, COption::None));
+        assert!(!ledger.exists(&trade.escrow));
+    }
+}
+
+#[test]
+fn closing_a_non_custodial_escrow_revokes_its_approval() {
+    for token_program in [spl_token::id(), spl_token_2022::id()] {
+        let mut ledger = Ledger::new();
+        let expires_at = ledger.now() + 100;
+        let args = InitEscrowArgs { expires_at: Some(expires_at), non_custodial: true, ..InitEscrowArgs::default() };
+        let cancelled = open_trade(&mut ledger, &token_program, 5, 2, &args);
+        let reclaimed = open_trade(&mut ledger, &token_program, 5, 2, &args);
+
+        ledger.process(&cancel_trade(&ledger, &cancelled)).unwrap();
+        let token_info = ledger.token(&cancelled.initializer_token_account);
+        assert_eq!((token_info.amount, token_info.delegate), (5, COption::None));
+        assert!(!ledger.exists(&cancelled.escrow));
+
+        // only the initializer can reclaim the escrow, and revoke the approval with it
+        ledger.set_time(expires_at);
+        let mut unsigned = reclaim_trade(&ledger, &reclaimed);
+        assert!(unsigned.accounts[0].is_signer);
+        unsigned.accounts[0].is_signer = false;
+        assert_eq!(ledger.process(&unsigned), Err(ProgramError::MissingRequiredSignature));
+
+        ledger.process(&reclaim_trade(&ledger, &reclaimed)).unwrap();
+        let token_info = ledger.token(&reclaimed.initializer_token_account);
+        assert_eq!((token_info.amount, token_info.delegate), (5, COption::None));
+        assert!(!ledger.exists(&reclaimed.escrow));
+    }
+}
+
+#[test]
+fn non_custodial_escrow_fails_once_the_tokens_moved() {
+    let mut ledger = Ledger::new();
+    let token_program = spl_token::id();
+    let args = InitEscrowArgs { non_custodial: true, ..InitEscrowArgs::default() };
+    let trade = open_trade(&mut ledger, &token_program, 5, 2, &args);
+    let other_account = ledger.auxiliary_token_account(&trade.initializer, &trade.mint_x, 0);
+
+    let move_out = transfer_checked(
+        &token_program,
+        &trade.initializer_token_account,
+        &trade.mint_x,
+        &other_account,
+        &trade.initializer,
+        &[],
+        1,
+        0,
+    )
+    .unwrap();
+    ledger.process(&move_out).unwrap();
+    assert_eq!(ledger.process(&exchange_trade(&ledger, &trade)), Err(EscrowError::InsufficientDelegation.into()));
+    assert_eq!(ledger.balance(&trade.initializer_token_account), 4);
+    assert!(ledger.exists(&trade.escrow));
+}
+
+#[test]
+fn non_custodial_escrow_rejects_a_token_account_delegated_elsewhere() {
+    let mut ledger = Ledger::new();
+    let token_program = spl_token::id();
+    let args = InitEscrowArgs { non_custodial: true, ..InitEscrowArgs::default() };
+    let (trade, init) = new_trade(&mut ledger, &token_program, 5, 2, &args);
+
+    let with_arbiter = InitEscrowArgs { arbiter: Some(ledger.wallet()), ..args.clone() };
+    let legs_x = [XLeg { mint: trade.mint_x, token_program, amount: 5, ..XLeg::default() }];
+    let legs_y = [YLeg { mint: trade.mint_y, token_program, amount: 2 }];
+    let arbitrated =
+        init_escrow(&ledger.program_id, &trade.initializer, &trade.taker, &legs_x, &legs_y, 0, 0, &with_arbiter);
+    assert_eq!(ledger.process(&arbitrated.unwrap()), Err(EscrowError::InvalidArbiter.into()));
+
+    let other_delegate = Pubkey::new_unique();
+    let approve_other =
+        approve(&token_program, &trade.initializer_token_account, &other_delegate, &trade.initializer, &[], 1).unwrap();
+    ledger.process(&approve_other).unwrap();
+    assert_eq!(ledger.process(&init), Err(EscrowError::TokenAccountDelegated.into()));
+    assert_eq!(ledger.token(&trade.initializer_token_account).delegate, COption::Some(other_delegate));
+}
@@ -1,4 +1,4 @@
-import { AccountLayout, TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { AccountLayout, ASSOCIATED_TOKEN_PROGRAM_ID, Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import {
   Connection,
   Keypair,
@@ -12,7 +12,9 @@ import {
 import BN = require("bn.js");
 import {
   EscrowLayout,
-  ESCROW_ACCOUNT_DATA_LAYOUT,
+  ESCROW_HEADER_LAYOUT,
+  ESCROW_SEED,
+  getEscrowAddress,
   getKeypair,
   getProgramId,
   getPublicKey,
@@ -27,7 +29,6 @@ const cancel = async () => {
     
   const aliceKeypair = getKeypair("alice");
   const bobKeypair = getKeypair("bob");
-  const escrowKeypair = getKeypair("escrow");
   const clientKeypair = getKeypair("id");
 
   // const connection = new Connection("https://api.testnet.solana.com", "confirmed");
@@ -40,6 +41,11 @@ const cancel = async () => {
   // await connection.requestAirdrop(bobKeypair.publicKey, LAMPORTS_PER_SOL * 100);
 
   const escrowProgramId = getProgramId();
+  const escrowPubkey = await getEscrowAddress(
+    aliceKeypair.publicKey,
+    ESCROW_SEED,
+    escrowProgramId
+  );
 
   const aliceXAmount = 3;
   const bobYAmount = 2;
@@ -62,18 +68,28 @@ const cancel = async () => {
     getPublicKey("bob_token1"),
     getPublicKey("bob_token2")
   ];
-  const tempTokenAccountPubkey = [
-    getPublicKey("escrow_x1"),
-    getPublicKey("escrow_x2"),
-    getPublicKey("escrow_x3"),
-    getPublicKey("escrow_y1"),
-    getPublicKey("escrow_y2"),
-    getPublicKey("escrow_token1"),
-    getPublicKey("escrow_token2")
+  const mintXPubkey = [
+    getPublicKey("mint_x1"),
+    getPublicKey("mint_x2"),
+    getPublicKey("mint_x3"),
+    getPublicKey("mint_token1")
   ];
+  const mintYPubkey = [
+    getPublicKey("mint_y1"),
+    getPublicKey("mint_y2"),
+    getPublicKey("mint_token2")
+  ];
+  const vaultPubkey = await Promise.all(
+    [0, 1, 2, 3].map(async (i) =>
+      (await PublicKey.findProgramAddress(
+        [Buffer.from("vault"), escrowPubkey.toBuffer(), Buffer.from([i])],
+        escrowProgramId
+      ))[0]
+    )
+  );
 
   const PDA = await PublicKey.findProgramAddress(
-    [Buffer.from("escrow")],
+    [Buffer.from("escrow"), escrowPubkey.toBuffer()],
     escrowProgramId
   );
 
@@ -82,52 +98,33 @@ const cancel = async () => {
     keys: [
         { pubkey: aliceKeypair.publicKey, isSigner: true, isWritable: true },
         { pubkey: bobKeypair.publicKey, isSigner: false, isWritable: true },
-        { pubkey: escrowKeypair.publicKey, isSigner: false, isWritable: true },
+        { pubkey: escrowPubkey, isSigner: false, isWritable: true },
         { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
-        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: PDA[0], isSigner: false, isWritable: false },
+        // rent of the vaults and the escrow goes back to alice, the default rent destination
+        { pubkey: aliceKeypair.publicKey, isSigner: false, isWritable: true },
+        // each X leg is refunded from its vault to a token account of alice
         //--- a -> b --- NFT
         { pubkey: aliceTokenAccountPubkey[0], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[0], isSigner: false, isWritable: true },
-        { pubkey: tempTokenAccountPubkey[0], isSigner: false, isWritable: true },
+        { pubkey: vaultPubkey[0], isSigner: false, isWritable: true },
+        { pubkey: mintXPubkey[0], isSigner: false, isWritable: true },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: aliceTokenAccountPubkey[1], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[1], isSigner: false, isWritable: true },
-        { pubkey: tempTokenAccountPubkey[1], isSigner: false, isWritable: true },
+        { pubkey: vaultPubkey[1], isSigner: false, isWritable: true },
+        { pubkey: mintXPubkey[1], isSigner: false, isWritable: true },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: aliceTokenAccountPubkey[2], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[2], isSigner: false, isWritable: true },
-        { pubkey: tempTokenAccountPubkey[2], isSigner: false, isWritable: true },
+        { pubkey: vaultPubkey[2], isSigner: false, isWritable: true },
+        { pubkey: mintXPubkey[2], isSigner: false, isWritable: true },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         //--- a -> b --- FT
         { pubkey: aliceTokenAccountPubkey[5], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[5], isSigner: false, isWritable: true },
-        { pubkey: tempTokenAccountPubkey[5], isSigner: false, isWritable: true },
-
-        //--- b -> a --- NFT
-        { pubkey: aliceTokenAccountPubkey[3], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[3], isSigner: false, isWritable: true },
-        { pubkey: aliceTokenAccountPubkey[4], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[4], isSigner: false, isWritable: true },
-        //--- b -> a --- FT
-        { pubkey: aliceTokenAccountPubkey[6], isSigner: false, isWritable: true },
-        { pubkey: bobTokenAccountPubkey[6], isSigner: false, isWritable: true },      
+        { pubkey: vaultPubkey[3], isSigner: false, isWritable: true },
+        { pubkey: mintXPubkey[3], isSigner: false, isWritable: true },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ],
       data: Buffer.from(
-        Uint8Array.of(
-          2,  // escrow cancel
-
-          1,  // sol_dir : alice -> bob
-          ...new BN(2000000000).toArray("le", 8),     //sol_lamports
-
-          ...new BN(4).toArray("le", 1),   //alice token amount
-          ...new BN(1).toArray("le", 8),
-          ...new BN(1).toArray("le", 8),
-          ...new BN(1).toArray("le", 8),
-          ...new BN(2000000000).toArray("le", 8),
-
-          ...new BN(3).toArray("le", 1),     //bob token amount
-          ...new BN(1).toArray("le", 8),
-          ...new BN(1).toArray("le", 8),
-          ...new BN(3000000000).toArray("le", 8),
-        )
+        Uint8Array.of(4)  // escrow cancel on stored terms
     ),
   });
 
@@ -139,7 +136,7 @@ const cancel = async () => {
   console.log("Canceling Alice's transaction...");
   await connection.sendTransaction(
     tx,
-    [aliceKeypair, escrowKeypair],
+    [aliceKeypair],
     { skipPreflight: false, preflightCommitment: "confirmed" }
   );
 
@@ -1,4 +1,4 @@
-import { AccountLayout, TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { AccountLayout, ASSOCIATED_TOKEN_PROGRAM_ID, Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 import {
   Connection,
   Keypair,
@@ -11,8 +11,14 @@ import {
 } from "@solana/web3.js";
 import BN = require("bn.js");
 import {
+  CONFIG_LAYOUT,
+  ConfigLayout,
+  decodeEscrow,
   EscrowLayout,
-  ESCROW_ACCOUNT_DATA_LAYOUT,
+  ESCROW_HEADER_LAYOUT,
+  ESCROW_SEED,
+  getConfigAddress,
+  getEscrowAddress,
   getKeypair,
   getProgramId,
   getPublicKey,
@@ -27,7 +33,6 @@ const alice = async () => {
     
   const aliceKeypair = getKeypair("alice");
   const bobKeypair = getKeypair("bob");
-  const escrowKeypair = getKeypair("escrow");
   const clientKeypair = getKeypair("id");
 
   // const connection = new Connection("https://api.testnet.solana.com", "confirmed");
@@ -35,6 +40,11 @@ const alice = async () => {
   const connection = new Connection("https://api.devnet.solana.com", "confirmed");
   
   const escrowProgramId = getProgramId();
+  const escrowPubkey = await getEscrowAddress(
+    aliceKeypair.publicKey,
+    ESCROW_SEED,
+    escrowProgramId
+  );
 
   const aliceXAmount = 3;
   const bobYAmount = 2;
@@ -57,18 +67,62 @@ const alice = async () => {
     getPublicKey("bob_token1"),
     getPublicKey("bob_token2")
   ];
-  const tempTokenAccountPubkey = [
-    getPublicKey("escrow_x1"),
-    getPublicKey("escrow_x2"),
-    getPublicKey("escrow_x3"),
-    getPublicKey("escrow_y1"),
-    getPublicKey("escrow_y2"),
-    getPublicKey("escrow_token1"),
-    getPublicKey("escrow_token2")
+  const mintXPubkey = [
+    getPublicKey("mint_x1"),
+    getPublicKey("mint_x2"),
+    getPublicKey("mint_x3"),
+    getPublicKey("mint_token1")
+  ];
+  const mintYPubkey = [
+    getPublicKey("mint_y1"),
+    getPublicKey("mint_y2"),
+    getPublicKey("mint_token2")
   ];
+  // the legs are received in associated token accounts, the program creates the missing ones at exchange
+  const bobXReceiverPubkey = await Promise.all(
+    mintXPubkey.map((mint) =>
+      Token.getAssociatedTokenAddress(ASSOCIATED_TOKEN_PROGRAM_ID, TOKEN_PROGRAM_ID, mint, bobKeypair.publicKey)
+    )
+  );
+  const aliceYReceiverPubkey = await Promise.all(
+    mintYPubkey.map((mint) =>
+      Token.getAssociatedTokenAddress(ASSOCIATED_TOKEN_PROGRAM_ID, TOKEN_PROGRAM_ID, mint, aliceKeypair.publicKey)
+    )
+  );
+  const vaultPubkey = await Promise.all(
+    [0, 1, 2, 3].map(async (i) =>
+      (await PublicKey.findProgramAddress(
+        [Buffer.from("vault"), escrowPubkey.toBuffer(), Buffer.from([i])],
+        escrowProgramId
+      ))[0]
+    )
+  );
   
+  // the trade only goes through on the version of the terms bob looked at
+  const escrowAccount = await connection.getAccountInfo(escrowPubkey);
+  if (escrowAccount === null) {
+    logError("Could not find escrow at given address!");
+    process.exit(1);
+  }
+  const expectedVersion = decodeEscrow(escrowAccount.data).version;
+
+  // once a fee is configured the treasury takes its share of the SOL leg and of the fungible legs
+  const configPubkey = await getConfigAddress(escrowProgramId);
+  const configAccount = await connection.getAccountInfo(configPubkey);
+  const config: ConfigLayout | null =
+    configAccount === null ? null : CONFIG_LAYOUT.decode(configAccount.data);
+  const treasuryPubkey =
+    config === null ? aliceKeypair.publicKey : new PublicKey(config.treasury);
+  const treasuryTokenAccountKeys =
+    config === null || config.feeBps === 0
+      ? []
+      : [
+          { pubkey: getPublicKey("treasury_token1"), isSigner: false, isWritable: true },
+          { pubkey: getPublicKey("treasury_token2"), isSigner: false, isWritable: true },
+        ];
+
   const PDA = await PublicKey.findProgramAddress(
-    [Buffer.from("escrow")],
+    [Buffer.from("escrow"), escrowPubkey.toBuffer()],
     escrowProgramId
   );
 
@@ -77,55 +131,57 @@ const alice = async () => {
     keys: [
       { pubkey: aliceKeypair.publicKey, isSigner: false, isWritable: true },
       { pubkey: bobKeypair.publicKey, isSigner: true, isWritable: true },
-      { pubkey: escrowKeypair.publicKey, isSigner: false, isWritable: true },
+      { pubkey: escrowPubkey, isSigner: false, isWritable: true },
       { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
-      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       { pubkey: PDA[0], isSigner: false, isWritable: false },
+      // rent of the vaults and the escrow goes back to alice, the default rent destination
+      { pubkey: aliceKeypair.publicKey, isSigner: false, isWritable: true },
+      { pubkey: configPubkey, isSigner: false, isWritable: false },
+      { pubkey: treasuryPubkey, isSigner: false, isWritable: true },
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      { pubkey: ASSOCIATED_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ...treasuryTokenAccountKeys,
       //--- a -> b --- NFT
-      { pubkey: aliceTokenAccountPubkey[0], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[0], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[0], isSigner: false, isWritable: true },
-      { pubkey: aliceTokenAccountPubkey[1], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[1], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[1], isSigner: false, isWritable: true },
-      { pubkey: aliceTokenAccountPubkey[2], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[2], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: bobXReceiverPubkey[0], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[0], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[0], isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: bobXReceiverPubkey[1], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[1], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[1], isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: bobXReceiverPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       //--- a -> b --- FT
-      { pubkey: aliceTokenAccountPubkey[5], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[5], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[5], isSigner: false, isWritable: true },
+      { pubkey: bobXReceiverPubkey[3], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[3], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[3], isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
 
       //--- b -> a --- NFT
-      { pubkey: aliceTokenAccountPubkey[3], isSigner: false, isWritable: true },
+      { pubkey: aliceYReceiverPubkey[0], isSigner: false, isWritable: true },
       { pubkey: bobTokenAccountPubkey[3], isSigner: false, isWritable: true },
-      { pubkey: aliceTokenAccountPubkey[4], isSigner: false, isWritable: true },
+      { pubkey: mintYPubkey[0], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: aliceYReceiverPubkey[1], isSigner: false, isWritable: true },
       { pubkey: bobTokenAccountPubkey[4], isSigner: false, isWritable: true },
+      { pubkey: mintYPubkey[1], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       //--- b -> a --- FT
-      { pubkey: aliceTokenAccountPubkey[6], isSigner: false, isWritable: true },
+      { pubkey: aliceYReceiverPubkey[2], isSigner: false, isWritable: true },
       { pubkey: bobTokenAccountPubkey[6], isSigner: false, isWritable: true },
-
-      { pubkey: SystemProgram.programId, isSigner:false, isWritable:false }
+      { pubkey: mintYPubkey[2], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
     ],
     data: Buffer.from(
       Uint8Array.of(
-        1,  // escrow exchange
-
-        1,  // sol_dir : alice -> bob
-        ...new BN(2000000000).toArray("le", 8),     //sol_lamports
-
-        ...new BN(4).toArray("le", 1),   //alice token amount
-        ...new BN(1).toArray("le", 8),
-        ...new BN(1).toArray("le", 8),
-        ...new BN(1).toArray("le", 8),
-        ...new BN(2000000000).toArray("le", 8),
-
-        ...new BN(3).toArray("le", 1),     //bob token amount
-        ...new BN(1).toArray("le", 8),
-        ...new BN(1).toArray("le", 8),
-        ...new BN(3000000000).toArray("le", 8),
+        3,  // escrow exchange on stored terms
+        ...expectedVersion,  // the version bob read the terms at
+        0,  // no metadata bump seeds, the escrow does not enforce royalties
       )
-),
+    ),
 });
 
   const tx = new Transaction().add(
@@ -139,7 +195,7 @@ const alice = async () => {
   console.log("Sending Exchange's transaction...");
   await connection.sendTransaction(
     tx,
-    [bobKeypair, escrowKeypair ],
+    [bobKeypair],
     { skipPreflight: false, preflightCommitment: "confirmed" }
   );
   console.log("Exchange completed!");
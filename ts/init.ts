@@ -12,7 +12,9 @@ import {
 import BN = require("bn.js");
 import {
   EscrowLayout,
-  ESCROW_ACCOUNT_DATA_LAYOUT,
+  ESCROW_HEADER_LAYOUT,
+  ESCROW_SEED,
+  getEscrowAddress,
   getKeypair,
   getProgramId,
   getPublicKey,
@@ -27,7 +29,6 @@ const alice = async () => {
     
   const aliceKeypair = getKeypair("alice");
   const bobKeypair = getKeypair("bob");
-  const escrowKeypair = getKeypair("escrow");
   const clientKeypair = getKeypair("id");
 
   // const connection = new Connection("https://api.testnet.solana.com", "confirmed");
@@ -40,6 +41,11 @@ const alice = async () => {
   // await connection.requestAirdrop(bobKeypair.publicKey, LAMPORTS_PER_SOL * 100);
 
   const escrowProgramId = getProgramId();
+  const escrowPubkey = await getEscrowAddress(
+    aliceKeypair.publicKey,
+    ESCROW_SEED,
+    escrowProgramId
+  );
 
   const aliceTokenAccountPubkey = [
     getPublicKey("alice_x1"),
@@ -59,59 +65,62 @@ const alice = async () => {
     getPublicKey("bob_token1"),
     getPublicKey("bob_token2")
   ];
-  const tempTokenAccountPubkey = [
-    getPublicKey("escrow_x1"),
-    getPublicKey("escrow_x2"),
-    getPublicKey("escrow_x3"),
-    getPublicKey("escrow_y1"),
-    getPublicKey("escrow_y2"),
-    getPublicKey("escrow_token1"),
-    getPublicKey("escrow_token2")
+  const mintXPubkey = [
+    getPublicKey("mint_x1"),
+    getPublicKey("mint_x2"),
+    getPublicKey("mint_x3"),
+    getPublicKey("mint_token1")
+  ];
+  const mintYPubkey = [
+    getPublicKey("mint_y1"),
+    getPublicKey("mint_y2"),
+    getPublicKey("mint_token2")
   ];
+  // the program creates one vault per X leg, derived from the escrow and the leg index
+  const vaultPubkey = await Promise.all(
+    mintXPubkey.map(async (_, i) =>
+      (await PublicKey.findProgramAddress(
+        [Buffer.from("vault"), escrowPubkey.toBuffer(), Buffer.from([i])],
+        escrowProgramId
+      ))[0]
+    )
+  );
   
-  const createEscrowAccountIx = SystemProgram.createAccount({
-    space: ESCROW_ACCOUNT_DATA_LAYOUT.span,
-    lamports: await connection.getMinimumBalanceForRentExemption(
-      ESCROW_ACCOUNT_DATA_LAYOUT.span
-    ),
-    fromPubkey: aliceKeypair.publicKey,
-    newAccountPubkey: escrowKeypair.publicKey,
-    programId: escrowProgramId,
-  });
-
   const initEscrowIx = new TransactionInstruction({
     programId: escrowProgramId,
     keys: [
-      { pubkey: aliceKeypair.publicKey, isSigner: true, isWritable: false },
+      { pubkey: aliceKeypair.publicKey, isSigner: true, isWritable: true },
       { pubkey: bobKeypair.publicKey, isSigner: false, isWritable: false },
-      { pubkey: escrowKeypair.publicKey, isSigner: false, isWritable: true },
+      { pubkey: escrowPubkey, isSigner: false, isWritable: true },
       { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
-      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: SystemProgram.programId, isSigner:false, isWritable:false },
       //--- a -> b --- NFT
       { pubkey: aliceTokenAccountPubkey[0], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[0], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[0], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[0], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[0], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       { pubkey: aliceTokenAccountPubkey[1], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[1], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[1], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[1], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[1], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       { pubkey: aliceTokenAccountPubkey[2], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[2], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[2], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[2], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       //--- a -> b --- FT
       { pubkey: aliceTokenAccountPubkey[5], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[5], isSigner: false, isWritable: true },
-      { pubkey: tempTokenAccountPubkey[5], isSigner: false, isWritable: true },
+      { pubkey: vaultPubkey[3], isSigner: false, isWritable: true },
+      { pubkey: mintXPubkey[3], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
 
       //--- b -> a --- NFT
-      { pubkey: aliceTokenAccountPubkey[3], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[3], isSigner: false, isWritable: true },
-      { pubkey: aliceTokenAccountPubkey[4], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[4], isSigner: false, isWritable: true },
+      { pubkey: mintYPubkey[0], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: mintYPubkey[1], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       //--- b -> a --- FT
-      { pubkey: aliceTokenAccountPubkey[6], isSigner: false, isWritable: true },
-      { pubkey: bobTokenAccountPubkey[6], isSigner: false, isWritable: true },
-
-      { pubkey: SystemProgram.programId, isSigner:false, isWritable:false }
+      { pubkey: mintYPubkey[2], isSigner: false, isWritable: false },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
     ],
     data: Buffer.from(
         Uint8Array.of(
@@ -130,12 +139,20 @@ const alice = async () => {
           ...new BN(1).toArray("le", 8),
           ...new BN(1).toArray("le", 8),
           ...new BN(3000000000).toArray("le", 8),
+
+          ...new BN(ESCROW_SEED).toArray("le", 8),   //escrow seed
+          0,  // no rent destination, the initializer gets the rent back
+          0,  // no expiry
+          0,  // no royalties enforced
+          0,  // no arbiter
+          0,  // no milestones
+          0,  // no hashlock
+          0,  // custodial, the X legs go into vaults
         )
     ),
   });
 
   const tx = new Transaction().add(
-    createEscrowAccountIx,
     initEscrowIx
   );
 
@@ -146,7 +163,7 @@ const alice = async () => {
   console.log("Sending Alice's transaction...");
   await connection.sendTransaction(
     tx,
-    [aliceKeypair, escrowKeypair],
+    [aliceKeypair],
     { skipPreflight: false, preflightCommitment: "confirmed" }
   );
   
@@ -35,9 +35,8 @@ import {
     connection: Connection,
     alicePublicKey: PublicKey,
     bobPublicKey: PublicKey,
-    escrowPublicKey: PublicKey,
     clientKeypair: Signer
-  ): Promise<[Token, PublicKey, PublicKey, PublicKey]> => {
+  ): Promise<[Token, PublicKey, PublicKey]> => {
     console.log(`Creating Mint ${name}...`);
     const mint = await createMint(connection, clientKeypair);
     writePublicKey(mint.publicKey, `mint_${name.toLowerCase()}`);
@@ -50,17 +49,12 @@ import {
     const bobTokenAccount = await mint.createAccount(bobPublicKey);
     writePublicKey(bobTokenAccount, `bob_${name.toLowerCase()}`);
   
-    console.log(`Creating Escrow TokenAccount for ${name}...`);
-    const escrowTokenAccount = await mint.createAccount(escrowPublicKey);
-    writePublicKey(escrowTokenAccount, `escrow_${name.toLowerCase()}`);
-
-    return [mint, aliceTokenAccount, bobTokenAccount, escrowTokenAccount];
+    return [mint, aliceTokenAccount, bobTokenAccount];
   };
   
   const setup = async () => {
     const alicePublicKey = getPublicKey("alice");
     const bobPublicKey = getPublicKey("bob");
-    const escrowPublicKey = getPublicKey("escrow");
     const clientKeypair = getKeypair("id");
   
     // const connection = new Connection("http://localhost:8899", "confirmed");
@@ -79,57 +73,52 @@ import {
     //   LAMPORTS_PER_SOL * 100
     // );
   //=============== alice mint X ================================
-    const [mintX1, aliceTokenAccountForX1, bobTokenAccountForX1, escrowTokenAccountForX1] = await setupMint(
+    const [mintX1, aliceTokenAccountForX1, bobTokenAccountForX1] = await setupMint(
       "X1",
       connection,
       alicePublicKey,
       bobPublicKey,
-      escrowPublicKey,
       clientKeypair
     );
     console.log("Sending X1 to Alice's X1 TokenAccount... 1");
     await mintX1.mintTo(aliceTokenAccountForX1, clientKeypair.publicKey, [], 1);
 
-    const [mintX2, aliceTokenAccountForX2, bobTokenAccountForX2, escrowTokenAccountForX2] = await setupMint(
+    const [mintX2, aliceTokenAccountForX2, bobTokenAccountForX2] = await setupMint(
       "X2",
       connection,
       alicePublicKey,
       bobPublicKey,
-      escrowPublicKey,
       clientKeypair
     );
     console.log("Sending X2 to Alice's X2 TokenAccount... 1");
     await mintX2.mintTo(aliceTokenAccountForX2, clientKeypair.publicKey, [], 1);
 
-    const [mintX3, aliceTokenAccountForX3, bobTokenAccountForX3, escrowTokenAccountForX3] = await setupMint(
+    const [mintX3, aliceTokenAccountForX3, bobTokenAccountForX3] = await setupMint(
       "X3",
       connection,
       alicePublicKey,
       bobPublicKey,
-      escrowPublicKey,
       clientKeypair
     );
     console.log("Sending X3 to Alice's X3 TokenAccount... 1");
     await mintX3.mintTo(aliceTokenAccountForX3, clientKeypair.publicKey, [], 1);
 
   //============= Bob mint Y =====================================
-    const [mintY1, aliceTokenAccountForY1, bobTokenAccountForY1, escrowTokenAccountForY1] = await setupMint(
+    const [mintY1, aliceTokenAccountForY1, bobTokenAccountForY1] = await setupMint(
       "Y1",
       connection,
       alicePublicKey,
       bobPublicKey,
-      escrowPublicKey,
       clientKeypair
     );
     console.log("Sending Y1 to Bob's Y1 TokenAccount...");
     await mintY1.mintTo(bobTokenAccountForY1, clientKeypair.publicKey, [], 1);
 
-    const [mintY2, aliceTokenAccountForY2, bobTokenAccountForY2, escrowTokenAccountForY2] = await setupMint(
+    const [mintY2, aliceTokenAccountForY2, bobTokenAccountForY2] = await setupMint(
       "Y2",
       connection,
       alicePublicKey,
       bobPublicKey,
-      escrowPublicKey,
       clientKeypair
     );
     console.log("Sending Y2 to Bob's Y2 TokenAccount...");
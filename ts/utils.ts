@@ -1,7 +1,8 @@
-import { Connection, Keypair, PublicKey } from "@solana/web3.js";
+import { Connection, Ed25519Program, Keypair, PublicKey } from "@solana/web3.js";
 //@ts-expect-error missing types
 import * as BufferLayout from "buffer-layout";
 
+import BN = require("bn.js");
 import * as fs from "fs";
 
 export const logError = (msg: string) => {
@@ -40,6 +41,85 @@ export const getProgramId = () => {
   }
 };
 
+/**
+ * Seed the scripts open their escrow with, any u64 works
+ */
+export const ESCROW_SEED = 0;
+
+/**
+ * Address of the escrow account `initializer` opens with `seed`, matches `find_escrow_address`
+ */
+export const getEscrowAddress = async (
+  initializer: PublicKey,
+  seed: number,
+  programId: PublicKey
+) =>
+  (
+    await PublicKey.findProgramAddress(
+      [
+        Buffer.from("escrow_state"),
+        initializer.toBuffer(),
+        Buffer.from(new BN(seed).toArray("le", 8)),
+      ],
+      programId
+    )
+  )[0];
+
+/**
+ * Address of the global config account, matches `find_config_address`
+ */
+export const getConfigAddress = async (programId: PublicKey) =>
+  (await PublicKey.findProgramAddress([Buffer.from("config")], programId))[0];
+
+/**
+ * Prefix of every signed order message, matches `ORDER_MESSAGE_PREFIX`
+ */
+export const ORDER_MESSAGE_PREFIX = Buffer.from("solana-escrow order");
+
+/**
+ * An order signed off chain by its maker, mirrors `Order`
+ */
+export interface Order {
+  maker: PublicKey;
+  taker: PublicKey;
+  nonce: BN;
+  expiresAt: BN;
+  mintX: PublicKey;
+  amountX: BN;
+  mintY: PublicKey;
+  amountY: BN;
+  lamports: BN;
+}
+
+/**
+ * The message the maker signs, matches `Order::message`: the prefix, the id of the program filling the order, then
+ * the packed order
+ */
+export const getOrderMessage = (order: Order, programId: PublicKey) =>
+  Buffer.concat([
+    ORDER_MESSAGE_PREFIX,
+    programId.toBuffer(),
+    order.maker.toBuffer(),
+    order.taker.toBuffer(),
+    Buffer.from(order.nonce.toArray("le", 8)),
+    Buffer.from(order.expiresAt.toTwos(64).toArray("le", 8)),
+    order.mintX.toBuffer(),
+    Buffer.from(order.amountX.toArray("le", 8)),
+    order.mintY.toBuffer(),
+    Buffer.from(order.amountY.toArray("le", 8)),
+    Buffer.from(order.lamports.toArray("le", 8)),
+  ]);
+
+/**
+ * Signs `order` for `programId` and returns the Ed25519 program instruction that must come right before
+ * `FillSignedOrder`, matches `verify_order_signature`
+ */
+export const signOrder = (maker: Keypair, order: Order, programId: PublicKey) =>
+  Ed25519Program.createInstructionWithPrivateKey({
+    privateKey: maker.secretKey,
+    message: getOrderMessage(order, programId),
+  });
+
 export const getTerms = (): {
   aliceExpectedAmount: number;
   bobExpectedAmount: number;
@@ -70,83 +150,125 @@ const uint64 = (property = "uint64") => {
   return BufferLayout.blob(8, property);
 };
 
-export const ESCROW_ACCOUNT_DATA_LAYOUT = BufferLayout.struct([
+/**
+ * Maximum number of token legs on either side of an escrow, matches `state::MAX_LEGS`
+ */
+export const MAX_LEGS = 9;
+
+export const X_LEG_LAYOUT = BufferLayout.struct([
+  publicKey("tempTokenAccount"),
+  publicKey("mint"),
+  publicKey("tokenProgram"),
+  uint64("amount"),
+]);
+
+export const Y_LEG_LAYOUT = BufferLayout.struct([
+  publicKey("mint"),
+  publicKey("tokenProgram"),
+  uint64("amount"),
+]);
+
+/**
+ * Mirrors the header written by `EscrowState::pack_into_slice`, u64 values are little-endian.
+ * It is followed by `amountX` X legs and `amountY` Y legs, back to back.
+ */
+export const ESCROW_HEADER_LAYOUT = BufferLayout.struct([
   BufferLayout.u8("isInitialized"),
-  BufferLayout.u8("sol_dir"),
-  uint64("sol_amount"),
-  BufferLayout.u8("amount_x"),
-  BufferLayout.u8("amount_y"),
-  publicKey("initPubkey"),
+  BufferLayout.u8("amountX"),
+  BufferLayout.u8("amountY"),
+  BufferLayout.u8("solDir"),
+  BufferLayout.u8("bumpSeed"),
+  uint64("lamports"),
+  publicKey("initializerPubkey"),
   publicKey("takerPubkey"),
-  publicKey("initializerPubkey1"),
-  publicKey("initializerPubkey2"),
-  publicKey("initializerPubkey3"),
-  publicKey("initializerPubkey4"),
-  publicKey("initializerPubkey5"),
-  publicKey("initializerPubkey6"),
-  publicKey("initializerPubkey7"),
-  publicKey("initializerPubkey8"),
-  publicKey("initializerPubkey9"),
-  publicKey("initializerPubkey10"),
-  publicKey("initializerPubkey11"),
-  publicKey("initializerPubkey12"),
-  publicKey("initializerPubkey13"),
-  publicKey("initializerPubkey14"),
-  publicKey("initializerPubkey15"),
-  publicKey("initializerPubkey16"),
-  publicKey("initializerPubkey17"),
-  publicKey("initializerPubkey18"),
-  publicKey("takerPubkey1"),
-  publicKey("takerPubkey2"),
-  publicKey("takerPubkey3"),
-  publicKey("takerPubkey4"),
-  publicKey("takerPubkey5"),
-  publicKey("takerPubkey6"),
-  publicKey("takerPubkey7"),
-  publicKey("takerPubkey8"),
-  publicKey("takerPubkey9"),
-  publicKey("takerPubkey10"),
-  publicKey("takerPubkey11"),
-  publicKey("takerPubkey12"),
-  publicKey("takerPubkey13"),
-  publicKey("takerPubkey14"),
-  publicKey("takerPubkey15"),
-  publicKey("takerPubkey16"),
-  publicKey("takerPubkey17"),
-  publicKey("takerPubkey18"),
-  publicKey("tempPubkey1"),
-  publicKey("tempPubkey2"),
-  publicKey("tempPubkey3"),
-  publicKey("tempPubkey4"),
-  publicKey("tempPubkey5"),
-  publicKey("tempPubkey6"),
-  publicKey("tempPubkey7"),
-  publicKey("tempPubkey8"),
-  publicKey("tempPubkey9"),
-  uint64("lamports_1"),
-  uint64("lamports_2"),
-  uint64("lamports_3"),
-  uint64("lamports_4"),
-  uint64("lamports_5"),
-  uint64("lamports_6"),
-  uint64("lamports_7"),
-  uint64("lamports_8"),
-  uint64("lamports_9"),
-  uint64("lamports_10"),
-  uint64("lamports_11"),
-  uint64("lamports_12"),
-  uint64("lamports_13"),
-  uint64("lamports_14"),
-  uint64("lamports_15"),
-  uint64("lamports_16"),
-  uint64("lamports_17"),
-  uint64("lamports_18")
+  publicKey("rentDestination"),
+  uint64("expiresAt"),
+  uint64("version"),
+  BufferLayout.u8("enforceRoyalties"),
+  publicKey("arbiter"),
+  BufferLayout.u8("disputed"),
+  BufferLayout.blob(32, "evidenceHash"),
+  BufferLayout.u8("milestoneCount"),
+  BufferLayout.u8("milestonesReleased"),
+  BufferLayout.seq(BufferLayout.u16("share"), 8, "milestoneShares"),
+  BufferLayout.blob(32, "hashlock"),
+  BufferLayout.u8("nonCustodial"),
 ]);
 
+/**
+ * Decodes an escrow account, mirrors `EscrowState::unpack_from_slice`
+ */
+export const decodeEscrow = (data: Buffer): EscrowLayout => {
+  const header = ESCROW_HEADER_LAYOUT.decode(data);
+  let offset = ESCROW_HEADER_LAYOUT.span;
+  const xLegs: XLegLayout[] = [];
+  for (let i = 0; i < header.amountX; i++) {
+    xLegs.push(X_LEG_LAYOUT.decode(data, offset));
+    offset += X_LEG_LAYOUT.span;
+  }
+  const yLegs: YLegLayout[] = [];
+  for (let j = 0; j < header.amountY; j++) {
+    yLegs.push(Y_LEG_LAYOUT.decode(data, offset));
+    offset += Y_LEG_LAYOUT.span;
+  }
+  return { ...header, xLegs, yLegs };
+};
+
+export interface XLegLayout {
+  tempTokenAccount: Uint8Array;
+  mint: Uint8Array;
+  tokenProgram: Uint8Array;
+  amount: Uint8Array;
+}
+
+export interface YLegLayout {
+  mint: Uint8Array;
+  tokenProgram: Uint8Array;
+  amount: Uint8Array;
+}
+
 export interface EscrowLayout {
   isInitialized: number;
+  amountX: number;
+  amountY: number;
+  solDir: number;
+  bumpSeed: number;
+  lamports: Uint8Array;
   initializerPubkey: Uint8Array;
-  initializerReceivingTokenAccountPubkey: Uint8Array;
-  initializerTempTokenAccountPubkey: Uint8Array;
-  expectedAmount: Uint8Array;
-}
\ No newline at end of file
+  takerPubkey: Uint8Array;
+  rentDestination: Uint8Array;
+  expiresAt: Uint8Array;
+  version: Uint8Array;
+  enforceRoyalties: number;
+  arbiter: Uint8Array;
+  disputed: number;
+  evidenceHash: Uint8Array;
+  milestoneCount: number;
+  milestonesReleased: number;
+  milestoneShares: number[];
+  hashlock: Uint8Array;
+  nonCustodial: number;
+  xLegs: XLegLayout[];
+  yLegs: YLegLayout[];
+}
+
+/**
+ * Mirrors `Config::pack_into_slice`, the fee exempt list follows the count
+ */
+export const CONFIG_LAYOUT = BufferLayout.struct([
+  BufferLayout.u8("isInitialized"),
+  BufferLayout.u8("bumpSeed"),
+  publicKey("admin"),
+  BufferLayout.u16("feeBps"),
+  publicKey("treasury"),
+  BufferLayout.u8("feeExemptCount"),
+]);
+
+export interface ConfigLayout {
+  isInitialized: number;
+  bumpSeed: number;
+  admin: Uint8Array;
+  feeBps: number;
+  treasury: Uint8Array;
+  feeExemptCount: number;
+}